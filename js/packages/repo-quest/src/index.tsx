@@ -11,14 +11,21 @@ import * as uuid from "uuid";
 
 import guideMd from "../../../../GUIDE.md?raw";
 import {
+  type DeviceCode,
   events,
+  type GitProtocol,
+  type GithubCredentials,
+  type HookDecision,
+  type HookSet,
   type QuestConfig,
   type QuestLocation,
   type QuestState,
   type Result,
   type Stage,
+  type StagePart,
   type StageState,
   type StateDescriptor,
+  type TokenStatus,
   commands
 } from "./bindings/backend";
 
@@ -162,33 +169,173 @@ async function tryAwait<T>(
   }
 }
 
-let GithubLoader = () => (
-  <Await promise={commands.getGithubToken()}>
-    {token =>
-      token.type === "Found" ? (
-        <Await promise={commands.initOctocrab(token.value)}>
+let GithubInit: React.FC<{ credentials: GithubCredentials }> = ({
+  credentials
+}) => (
+  <Await promise={commands.initOctocrab(credentials)}>
+    {result =>
+      result.status === "ok" ? (
+        <LoaderEntry />
+      ) : (
+        <ErrorView action="Loading Github API" message={result.error} />
+      )
+    }
+  </Await>
+);
+
+let DeviceLoginView: React.FC<{ code: DeviceCode }> = ({ code }) => (
+  <>
+    <div>
+      Open <Link href={code.verification_uri}>{code.verification_uri}</Link>{" "}
+      and enter the code <code>{code.user_code}</code>
+    </div>
+    <Await promise={commands.finishGithubLogin()}>
+      {credentials =>
+        credentials.status === "ok" ? (
+          <GithubInit credentials={credentials.data} />
+        ) : (
+          <ErrorView action="Logging into Github" message={credentials.error} />
+        )
+      }
+    </Await>
+  </>
+);
+
+let GithubLogin = () => {
+  let [started, setStarted] = useState(false);
+  if (!started)
+    return (
+      <button type="button" onClick={() => setStarted(true)}>
+        Log in with Github
+      </button>
+    );
+  return (
+    <Await promise={commands.startGithubLogin()}>
+      {code =>
+        code.status === "ok" ? (
+          <DeviceLoginView code={code.data} />
+        ) : (
+          <ErrorView action="Starting Github login" message={code.error} />
+        )
+      }
+    </Await>
+  );
+};
+
+let tokenGuidance = (status: TokenStatus): string => {
+  switch (status.type) {
+    case "Valid":
+      return "Your Github token is valid.";
+    case "MissingScopes":
+      return `Your Github token is missing the scopes: ${status.value.join(", ")}. Generate a new token with these scopes selected, or log in below.`;
+    case "Expired":
+      return "Your Github token has expired. Log in again or generate a new token.";
+    case "Revoked":
+      return "Github does not accept your token. It may have been revoked or expired. Log in again or generate a new token.";
+    case "MissingPermissions":
+      return `Your Github token cannot access your repositories.${
+        status.value.length > 0
+          ? ` It needs the permissions: ${status.value.join(", ")}.`
+          : ""
+      } Grant it read and write access to all repositories, or use a classic token with the repo scope.`;
+  }
+};
+
+let GithubLoader = () => {
+  let [offline, setOffline] = useState(false);
+  if (offline) return <LocalLoader />;
+  return (
+    <Await promise={commands.getGithubToken()}>
+      {token =>
+        token.type === "Found" ? (
+          <GithubInit credentials={token.value} />
+        ) : token.type === "NotFound" || token.type === "Invalid" ? (
+          <>
+            <div>
+              {token.type === "Invalid"
+                ? tokenGuidance(token.value)
+                : "Before running RepoQuest, you need to provide it access to Github."}{" "}
+              Log in through your browser:
+            </div>
+            <div>
+              <GithubLogin />
+            </div>
+            <div>
+              Or follow the instructions at the link below and restart
+              RepoQuest.
+            </div>
+            <div>
+              <Link href="https://github.com/cognitive-engineering-lab/repo-quest/blob/main/README.md#github-token">
+                https://github.com/cognitive-engineering-lab/repo-quest/blob/main/README.md#github-token
+              </Link>
+            </div>
+            <div>
+              Alternatively, you can play quest packages offline without a Github
+              account.{" "}
+              <button type="button" onClick={() => setOffline(true)}>
+                Play offline
+              </button>
+            </div>
+          </>
+        ) : (
+          <ErrorView action="Loading Github token" message={token.value} />
+        )
+      }
+    </Await>
+  );
+};
+
+let LocalLoader = () => (
+  <Await promise={commands.initLocal()}>
+    {result =>
+      result.status === "ok" ? (
+        <LoaderEntry />
+      ) : (
+        <ErrorView action="Setting up local mode" message={result.error} />
+      )
+    }
+  </Await>
+);
+
+let ForgeLoader = () => (
+  <Await promise={commands.getGiteaConfig()}>
+    {config =>
+      config.status === "error" ? (
+        <ErrorView action="Loading Gitea config" message={config.error} />
+      ) : config.data !== null ? (
+        <Await promise={commands.initGitea(config.data)}>
           {result =>
             result.status === "ok" ? (
               <LoaderEntry />
             ) : (
-              <ErrorView action="Loading Github API" message={result.error} />
+              <ErrorView action="Loading Gitea API" message={result.error} />
+            )
+          }
+        </Await>
+      ) : (
+        <GitlabLoader />
+      )
+    }
+  </Await>
+);
+
+let GitlabLoader = () => (
+  <Await promise={commands.getGitlabConfig()}>
+    {config =>
+      config.status === "error" ? (
+        <ErrorView action="Loading GitLab config" message={config.error} />
+      ) : config.data !== null ? (
+        <Await promise={commands.initGitlab(config.data)}>
+          {result =>
+            result.status === "ok" ? (
+              <LoaderEntry />
+            ) : (
+              <ErrorView action="Loading GitLab API" message={result.error} />
             )
           }
         </Await>
-      ) : token.type === "NotFound" ? (
-        <>
-          <div>
-            Before running RepoQuest, you need to provide it access to Github.
-            Follow the instructions at the link below and restart RepoQuest.
-          </div>
-          <div>
-            <Link href="https://github.com/cognitive-engineering-lab/repo-quest/blob/main/README.md#github-token">
-              https://github.com/cognitive-engineering-lab/repo-quest/blob/main/README.md#github-token
-            </Link>
-          </div>
-        </>
       ) : (
-        <ErrorView action="Loading Github token" message={token.value} />
+        <GithubLoader />
       )
     }
   </Await>
@@ -257,6 +404,9 @@ const QUESTS = ["cognitive-engineering-lab/rqst-async"];
 let NewQuest = () => {
   let [dir, setDir] = useState<string | undefined>(undefined);
   let [quest, setQuest] = useState<QuestLocation | undefined>(undefined);
+  let [protocol, setProtocol] = useState<GitProtocol | null>(null);
+  let [allowUntrusted, setAllowUntrusted] = useState(false);
+  let [revision, setRevision] = useState<string | undefined>(undefined);
   let [submit, setSubmit] = useState(false);
   return !submit ? (
     <div className="new-quest">
@@ -309,12 +459,40 @@ let NewQuest = () => {
                 type="button"
                 onClick={async () => {
                   let file = await dialog.open();
-                  if (file !== null) setQuest({ type: "Local", value: file });
+                  if (file === null) return;
+                  let info = await commands.checkPackage(file);
+                  if (info.status === "error") {
+                    await dialog.message(info.error, { kind: "error" });
+                    return;
+                  }
+                  let trust = info.data.trust;
+                  if (trust.type !== "Trusted") {
+                    let reason =
+                      trust.type === "Unsigned"
+                        ? "This quest package is not signed"
+                        : `This quest package is signed by a key you haven't trusted (${trust.key})`;
+                    let confirmed = await dialog.confirm(
+                      `${reason}, so RepoQuest can't tell who made it. Packages can run code on your computer. Only continue if you trust where it came from.`
+                    );
+                    if (!confirmed) return;
+                  }
+                  setAllowUntrusted(trust.type !== "Trusted");
+                  setRevision(info.data.content_hash);
+                  setQuest({ type: "Local", value: file });
                 }}
               >
                 Choose a local package file
               </button>
-              {quest && quest.type === "Local" && <code>{quest.value}</code>}
+              {quest && quest.type === "Local" && (
+                <>
+                  <code>{quest.value}</code>
+                  {revision && (
+                    <div>
+                      Revision <code>{revision.slice(0, 12)}</code>
+                    </div>
+                  )}
+                </>
+              )}
             </td>
           </tr>
           <tr>
@@ -333,6 +511,25 @@ let NewQuest = () => {
               {dir && <code>{dir}</code>}
             </td>
           </tr>
+          <tr>
+            <td>Connect via:</td>
+            <td>
+              <select
+                onChange={e =>
+                  setProtocol(
+                    e.target.value === ""
+                      ? null
+                      : (e.target.value as GitProtocol)
+                  )
+                }
+                defaultValue={""}
+              >
+                <option value="">Detect automatically</option>
+                <option value="Ssh">SSH</option>
+                <option value="Https">HTTPS</option>
+              </select>
+            </td>
+          </tr>
         </tbody>
       </table>
       <div>
@@ -346,7 +543,7 @@ let NewQuest = () => {
       </div>
     </div>
   ) : (
-    <Await promise={commands.newQuest(dir!, quest!)}>
+    <Await promise={commands.newQuest(dir!, quest!, protocol, allowUntrusted)}>
       {quest_res =>
         quest_res.status === "ok" ? (
           <QuestView
@@ -386,6 +583,37 @@ let QuizPage: React.FC<{ quest: QuestConfig; goBack: () => void }> = ({
   );
 };
 
+let HooksPrompt: React.FC<{ hooks: HookSet }> = ({ hooks }) => {
+  let loader = useContext(Loader.context)!;
+  let decide = (decision: HookDecision) =>
+    loader.loadAwait(commands.decideHooks(hooks.sha256, decision));
+  return (
+    <div className="hooks-prompt">
+      <p>
+        This quest comes with git hooks, which run on your computer whenever
+        you use git in the quest directory. Only allow them if you trust the
+        quest's author.
+      </p>
+      {hooks.hooks.map(hook => (
+        <details key={hook.name}>
+          <summary>
+            <code>{hook.name}</code> (SHA-256 <code>{hook.sha256}</code>)
+          </summary>
+          <pre>{hook.contents}</pre>
+        </details>
+      ))}
+      <div>
+        <button type="button" onClick={() => decide("Allow")}>
+          Allow hooks
+        </button>
+        <button type="button" onClick={() => decide("Deny")}>
+          Play without hooks
+        </button>
+      </div>
+    </div>
+  );
+};
+
 let QuestView: React.FC<{
   quest: QuestConfig;
   initialState: StateDescriptor;
@@ -434,6 +662,31 @@ let QuestView: React.FC<{
                 <code>git pull</code>!
               </div>
             )}
+            {state.rate_limit?.paused_until &&
+              new Date(state.rate_limit.paused_until) > new Date() && (
+                <div className="rate-limit-warning">
+                  Github is rate limiting RepoQuest, so updates are paused until{" "}
+                  {new Date(state.rate_limit.paused_until).toLocaleTimeString(
+                    [],
+                    { hour: "2-digit", minute: "2-digit" }
+                  )}
+                  .
+                </div>
+              )}
+            {state.connectivity.type === "Offline" && (
+              <div className="offline-warning">
+                RepoQuest can't reach Github, so this is the quest as of your
+                last session. Changes are disabled until the connection
+                returns.
+              </div>
+            )}
+            {state.connectivity.type === "Retrying" && (
+              <div className="offline-warning">
+                RepoQuest failed to update the quest and is retrying (
+                {state.connectivity.error}).
+              </div>
+            )}
+            {state.pending_hooks && <HooksPrompt hooks={state.pending_hooks} />}
             <ol className="stages" start={0}>
               {_.range(cur_stage + 1).map(i => (
                 <StageView
@@ -441,6 +694,8 @@ let QuestView: React.FC<{
                   index={i}
                   stage={state.stages[i]}
                   state={state.state}
+                  local={state.local}
+                  offline={state.connectivity.type === "Offline"}
                 />
               ))}
               {state.state.type === "Completed" && quest.final && (
@@ -475,7 +730,7 @@ let QuestView: React.FC<{
             </button>
           </div>
 
-          {initialState.can_skip && (
+          {initialState.can_skip && state?.connectivity.type !== "Offline" && (
             <div>
               <select
                 defaultValue={""}
@@ -510,11 +765,51 @@ let QuestView: React.FC<{
   );
 };
 
+let LocalActions: React.FC<{
+  index: number;
+  state: QuestState;
+}> = ({ index, state }) => {
+  let loader = useContext(Loader.context)!;
+  let setMessage = useContext(ErrorContext)!;
+  if (state.type !== "Ongoing" || state.stage !== index) return null;
+
+  let merge = (part: StagePart) =>
+    loader.loadAwait(
+      tryAwait(commands.mergeStagePr(index, part), "Merging PR", setMessage)
+    );
+  let close = () =>
+    loader.loadAwait(
+      tryAwait(commands.closeStageIssue(index), "Closing issue", setMessage)
+    );
+
+  return (
+    <span className="local-actions">
+      {state.part === "Starter" && state.status === "Ongoing" && (
+        <button type="button" onClick={() => merge("Starter")}>
+          Merge starter PR
+        </button>
+      )}
+      {state.part === "Solution" && state.status === "Ongoing" && (
+        <button type="button" onClick={() => merge("Solution")}>
+          Merge solution PR
+        </button>
+      )}
+      {state.part === "Solution" && (
+        <button type="button" onClick={close}>
+          Close issue
+        </button>
+      )}
+    </span>
+  );
+};
+
 let StageView: React.FC<{
   index: number;
   stage: StageState;
   state: QuestState;
-}> = ({ index, stage, state }) => {
+  local: boolean;
+  offline: boolean;
+}> = ({ index, stage, state, local, offline }) => {
   let loader = useContext(Loader.context)!;
   let setMessage = useContext(ErrorContext)!;
   return (
@@ -527,6 +822,7 @@ let StageView: React.FC<{
             state.status === "Start" ? (
               <button
                 type="button"
+                disabled={offline}
                 onClick={() =>
                   loader.loadAwait(
                     tryAwait(
@@ -564,6 +860,7 @@ let StageView: React.FC<{
                   <div>
                     <button
                       type="button"
+                      disabled={offline}
                       onClick={() =>
                         loader.loadAwait(
                           tryAwait(
@@ -592,6 +889,7 @@ let StageView: React.FC<{
         ) : (
           <span className="status">Completed</span>
         )}
+        {local && <LocalActions index={index} state={state} />}
       </div>
       <div className="gh-links">
         {stage.issue_url && <Link href={stage.issue_url}>Issue</Link>}
@@ -674,7 +972,7 @@ let App = () => {
                 <pre>{errorMessage.message}</pre>
               </div>
             ) : (
-              <GithubLoader />
+              <ForgeLoader />
             )}
           </div>
           <div id="version-watermark">v{VERSION}</div>
@@ -1,12 +1,26 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use std::{collections::HashMap, env, path::PathBuf, sync::Arc};
+use std::{
+  collections::HashMap,
+  env,
+  path::PathBuf,
+  sync::{Arc, RwLock},
+};
 
 use rq_core::{
-  github::{self, GithubToken},
-  package::QuestPackage,
+  forge::{local::LocalHost, ForgeHost, GitProtocol},
+  gitea::{self, GiteaConfig, GiteaHost},
+  github::{
+    self,
+    oauth::{DeviceCode, DeviceLogin},
+    GithubCredentials, GithubHost, GithubToken,
+  },
+  gitlab::{self, GitlabConfig, GitlabHost},
+  hooks::HookDecision,
+  package::{signature::PackageTrust, QuestPackage},
   quest::{CreateSource, Quest, QuestConfig, StateDescriptor, StateEmitter},
+  stage::StagePart,
 };
 use serde::{Deserialize, Serialize};
 use specta::Type;
@@ -25,6 +39,17 @@ impl StateEmitter for TauriEmitter {
   }
 }
 
+/// The forge that quests are created on and loaded from. Defaults to Github.
+#[derive(Default)]
+pub struct ForgeState(RwLock<Option<Arc<dyn ForgeHost>>>);
+
+impl ForgeState {
+  fn host(&self) -> Arc<dyn ForgeHost> {
+    let host = self.0.read().unwrap();
+    host.clone().unwrap_or_else(|| Arc::new(GithubHost::new()))
+  }
+}
+
 #[inline]
 fn fmt_err<T>(r: anyhow::Result<T>) -> Result<T, String> {
   r.map_err(|e| format!("{e:?}"))
@@ -32,14 +57,71 @@ fn fmt_err<T>(r: anyhow::Result<T>) -> Result<T, String> {
 
 #[tauri::command]
 #[specta::specta]
-fn get_github_token() -> GithubToken {
-  github::get_github_token()
+async fn get_github_token() -> GithubToken {
+  github::get_github_token().await
+}
+
+/// The device login in progress, if any.
+#[derive(Default)]
+pub struct LoginState(tokio::sync::Mutex<Option<DeviceLogin>>);
+
+#[tauri::command]
+#[specta::specta]
+async fn start_github_login(login: State<'_, LoginState>) -> Result<DeviceCode, String> {
+  let device_login = fmt_err(github::start_device_login().await)?;
+  let code = device_login.code();
+  *login.0.lock().await = Some(device_login);
+  Ok(code)
+}
+
+#[tauri::command]
+#[specta::specta]
+async fn finish_github_login(login: State<'_, LoginState>) -> Result<GithubCredentials, String> {
+  let device_login = login.0.lock().await.take();
+  let device_login = device_login.ok_or("Github login was not started")?;
+  fmt_err(github::finish_device_login(&device_login).await)
+}
+
+#[tauri::command]
+#[specta::specta]
+fn init_octocrab(credentials: GithubCredentials) -> Result<(), String> {
+  fmt_err(github::init_octocrab(&credentials))
+}
+
+#[tauri::command]
+#[specta::specta]
+fn get_gitea_config() -> Result<Option<GiteaConfig>, String> {
+  fmt_err(gitea::get_gitea_config())
 }
 
 #[tauri::command]
 #[specta::specta]
-fn init_octocrab(token: String) -> Result<(), String> {
-  fmt_err(github::init_octocrab(&token))
+fn init_gitea(config: GiteaConfig, forge: State<'_, ForgeState>) -> Result<(), String> {
+  let host = fmt_err(GiteaHost::new(&config))?;
+  *forge.0.write().unwrap() = Some(Arc::new(host));
+  Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+fn get_gitlab_config() -> Result<Option<GitlabConfig>, String> {
+  fmt_err(gitlab::get_gitlab_config())
+}
+
+#[tauri::command]
+#[specta::specta]
+fn init_gitlab(config: GitlabConfig, forge: State<'_, ForgeState>) -> Result<(), String> {
+  let host = fmt_err(GitlabHost::new(&config))?;
+  *forge.0.write().unwrap() = Some(Arc::new(host));
+  Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+fn init_local(forge: State<'_, ForgeState>) -> Result<(), String> {
+  let host = fmt_err(LocalHost::open_default())?;
+  *forge.0.write().unwrap() = Some(Arc::new(host));
+  Ok(())
 }
 
 #[tauri::command]
@@ -64,9 +146,15 @@ fn manage_quest(quest: Quest, app: &AppHandle) -> Arc<Quest> {
 #[specta::specta]
 async fn load_quest(
   dir: PathBuf,
+  forge: State<'_, ForgeState>,
   app: AppHandle,
 ) -> Result<(QuestConfig, StateDescriptor), String> {
-  let quest = fmt_err(Quest::load(dir, Box::new(TauriEmitter(app.clone()))).await)?;
+  // Quests played in local mode can be opened no matter which forge is configured.
+  let host: Arc<dyn ForgeHost> = match LocalHost::detect(&dir) {
+    Some(local) => Arc::new(local),
+    None => forge.host(),
+  };
+  let quest = fmt_err(Quest::load(dir, &*host, Box::new(TauriEmitter(app.clone()))).await)?;
   let quest = manage_quest(quest, &app);
   let state = fmt_err(quest.state_descriptor().await)?;
   Ok((quest.config.clone(), state))
@@ -79,11 +167,34 @@ pub enum QuestLocation {
   Local(PathBuf),
 }
 
+/// Checks who signed the package at `path`, so the learner can decide whether to play it.
+#[derive(Serialize, Deserialize, Type)]
+struct PackageInfo {
+  trust: PackageTrust,
+  /// Identifies the quest's revision.
+  content_hash: String,
+}
+
+#[tauri::command]
+#[specta::specta]
+fn check_package(path: PathBuf) -> Result<PackageInfo, String> {
+  let package = fmt_err(QuestPackage::load_trusted(&path, true))?;
+  Ok(PackageInfo {
+    trust: package.trust().clone(),
+    content_hash: package.content_hash,
+  })
+}
+
+/// Packages that aren't signed by a trusted author are refused unless `allow_untrusted`
+/// is set, which the app does once the learner has seen the result of `check_package`.
 #[tauri::command]
 #[specta::specta]
 async fn new_quest(
   dir: PathBuf,
   quest_loc: QuestLocation,
+  protocol: Option<GitProtocol>,
+  allow_untrusted: bool,
+  forge: State<'_, ForgeState>,
   app: AppHandle,
 ) -> Result<(QuestConfig, StateDescriptor), String> {
   let source = match quest_loc {
@@ -97,11 +208,21 @@ async fn new_quest(
       }
     }
     QuestLocation::Local(local) => {
-      let package = fmt_err(QuestPackage::load_from_file(&local))?;
-      CreateSource::Package(package)
+      let package = fmt_err(QuestPackage::load_trusted(&local, allow_untrusted))?;
+      CreateSource::Package(Box::new(package))
     }
   };
-  let quest = fmt_err(Quest::create(dir, source, Box::new(TauriEmitter(app.clone()))).await)?;
+  let host = forge.host();
+  let quest = fmt_err(
+    Quest::create(
+      dir,
+      source,
+      &*host,
+      protocol,
+      Box::new(TauriEmitter(app.clone())),
+    )
+    .await,
+  )?;
   let quest = manage_quest(quest, &app);
   let state = fmt_err(quest.state_descriptor().await)?;
   Ok((quest.config.clone(), state))
@@ -137,6 +258,36 @@ async fn skip_to_stage(quest: State<'_, Arc<Quest>>, stage: u32) -> Result<(), S
   Ok(())
 }
 
+#[tauri::command]
+#[specta::specta]
+async fn close_stage_issue(quest: State<'_, Arc<Quest>>, stage: u32) -> Result<(), String> {
+  let stage = usize::try_from(stage).unwrap();
+  fmt_err(quest.close_stage_issue(stage).await)
+}
+
+#[tauri::command]
+#[specta::specta]
+async fn merge_stage_pr(
+  quest: State<'_, Arc<Quest>>,
+  stage: u32,
+  part: StagePart,
+) -> Result<(), String> {
+  let stage = usize::try_from(stage).unwrap();
+  fmt_err(quest.merge_stage_pr(stage, part).await)
+}
+
+/// `sha256` is the hash of the hooks the learner was shown, so that they only decide about
+/// hooks they have seen.
+#[tauri::command]
+#[specta::specta]
+async fn decide_hooks(
+  quest: State<'_, Arc<Quest>>,
+  sha256: String,
+  decision: HookDecision,
+) -> Result<(), String> {
+  fmt_err(quest.decide_hooks(&sha256, decision).await)
+}
+
 #[derive(Serialize, Deserialize, Type)]
 struct DevDump {
   env: HashMap<String, String>,
@@ -145,9 +296,9 @@ struct DevDump {
 
 #[tauri::command]
 #[specta::specta]
-fn dev_dump() -> DevDump {
+async fn dev_dump() -> DevDump {
   let env = env::vars().collect::<HashMap<_, _>>();
-  let token = github::get_github_token();
+  let token = github::get_github_token().await;
   DevDump { env, token }
 }
 
@@ -155,14 +306,25 @@ pub fn specta_builder() -> tauri_specta::Builder {
   tauri_specta::Builder::<tauri::Wry>::new()
     .commands(tauri_specta::collect_commands![
       get_github_token,
+      start_github_login,
+      finish_github_login,
       init_octocrab,
+      get_gitea_config,
+      init_gitea,
+      get_gitlab_config,
+      init_gitlab,
+      init_local,
       load_quest,
       current_dir,
+      check_package,
       new_quest,
       file_feature_and_issue,
       file_solution,
       refresh_state,
       skip_to_stage,
+      close_stage_issue,
+      merge_stage_pr,
+      decide_hooks,
       dev_dump
     ])
     .events(collect_events![StateEvent])
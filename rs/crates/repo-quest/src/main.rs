@@ -5,6 +5,17 @@ use tracing_subscriber::{fmt, layer::SubscriberExt, prelude::*, EnvFilter};
 
 #[tokio::main]
 async fn main() {
+  // Git runs us as a credential helper for quests cloned over HTTPS.
+  let args = std::env::args().collect::<Vec<_>>();
+  if args.get(1).map(String::as_str) == Some(rq_core::credential::HELPER_ARG) {
+    let operation = args.get(2).map(String::as_str).unwrap_or_default();
+    if let Err(e) = rq_core::credential::run(operation).await {
+      eprintln!("{e:?}");
+      std::process::exit(1);
+    }
+    return;
+  }
+
   tracing_subscriber::registry()
     .with(fmt::layer())
     .with(EnvFilter::from_default_env())
@@ -16,6 +27,8 @@ async fn main() {
   tauri::Builder::default()
     .plugin(tauri_plugin_dialog::init())
     .plugin(tauri_plugin_shell::init())
+    .manage(repo_quest::ForgeState::default())
+    .manage(repo_quest::LoginState::default())
     .invoke_handler(specta_builder.invoke_handler())
     .setup(move |app| {
       #[cfg(debug_assertions)]
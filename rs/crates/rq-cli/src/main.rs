@@ -1,10 +1,14 @@
 use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use clap::{Parser, Subcommand};
 use rq_core::{
-  github::{self, GithubToken},
-  package::QuestPackage,
+  credential,
+  github::{self, GithubHost, GithubToken},
+  package::{
+    signature::{signature_path, SigningKey, TrustStore},
+    source, QuestPackage,
+  },
 };
 
 #[derive(Parser)]
@@ -16,24 +20,121 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Command {
-  Pack { path: PathBuf },
+  /// Packs the quest at `path`, which is either a quest repo on Github or a directory of
+  /// quest source files, which are packed without network access.
+  Pack {
+    path: PathBuf,
+    /// Also write a signature, made with the key in `~/.rqst-signing-key.toml`.
+    #[arg(long)]
+    sign: bool,
+    /// Instead of writing a package, fail unless the package at `check` has the same
+    /// contents as the one that would be written, e.g. to check a committed package in CI.
+    #[arg(long, conflicts_with = "sign")]
+    check: Option<PathBuf>,
+    /// Check the package at `check` even if it isn't signed by a trusted author.
+    #[arg(long, requires = "check")]
+    allow_untrusted: bool,
+  },
+  /// Upgrades a quest package to the package format of this version of RepoQuest.
+  Migrate {
+    path: PathBuf,
+    /// Where to write the upgraded package, instead of overwriting the original.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Upgrade the package even if it isn't signed by a trusted author.
+    #[arg(long)]
+    allow_untrusted: bool,
+  },
+  /// Signs a quest package with the key in `~/.rqst-signing-key.toml`.
+  Sign { path: PathBuf },
+  /// Trusts quest packages signed with `key`, the public key printed by `pack --sign`.
+  Trust { author: String, key: String },
+  /// Git credential helper for quests cloned over HTTPS, run by git rather than by hand.
+  #[command(name = credential::HELPER_ARG, hide = true)]
+  GitCredential { operation: String },
+}
+
+fn sign_package(path: &Path) -> Result<()> {
+  let key = SigningKey::load_or_generate()?;
+  let sig_path = key.sign(path)?;
+  println!("Signed the package in: {}", sig_path.display());
+  println!(
+    "Learners can trust your key with: rq-cli trust <your name> {}",
+    key.public_key()
+  );
+  Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
   let args = Cli::parse();
   match args.command {
-    Command::Pack { path } => {
-      let token = github::get_github_token();
-      match token {
-        GithubToken::Found(token) => github::init_octocrab(&token).unwrap(),
-        other => panic!("Failed to get github token: {other:?}"),
+    Command::Pack {
+      path,
+      sign,
+      check,
+      allow_untrusted,
+    } => {
+      let package = if source::is_source(&path) {
+        QuestPackage::build_from_source(&path)?
+      } else {
+        let token = github::get_github_token().await;
+        match token {
+          GithubToken::Found(credentials) => github::init_octocrab(&credentials).unwrap(),
+          GithubToken::Invalid(status) => bail!("{status}"),
+          other => panic!("Failed to get github token: {other:?}"),
+        }
+        QuestPackage::build(&path, &GithubHost::new()).await?
+      };
+      if let Some(check) = check {
+        let existing = QuestPackage::load_trusted(&check, allow_untrusted)?;
+        if existing.content_hash != package.content_hash {
+          bail!(
+            "Quest package is out of date: {}\n  package: {}\n  source:  {}",
+            check.display(),
+            existing.content_hash,
+            package.content_hash
+          );
+        }
+        println!("Quest package is up to date: {}", check.display());
+        return Ok(());
       }
-      let package = QuestPackage::build(&path).await?;
       let dst = format!("{}.json.gz", package.config.repo);
       package.save(Path::new(&dst))?;
       println!("Successfully generated quest package: {dst}");
+      println!("Content hash: {}", package.content_hash);
+      if sign {
+        sign_package(Path::new(&dst))?;
+      }
+    }
+    Command::Migrate {
+      path,
+      output,
+      allow_untrusted,
+    } => {
+      let package = QuestPackage::load_trusted(&path, allow_untrusted)?;
+      let migrations = package.migrations();
+      if migrations.is_empty() {
+        println!("Quest package is already up to date: {}", path.display());
+        return Ok(());
+      }
+      println!("Applied migrations:");
+      for migration in migrations {
+        println!("  {migration}");
+      }
+      let dst = output.unwrap_or(path);
+      package.save(&dst)?;
+      println!("Successfully upgraded quest package: {}", dst.display());
+      if signature_path(&dst).exists() {
+        println!("Note: the upgraded package no longer matches its signature, sign it again with `rq-cli sign`");
+      }
+    }
+    Command::Sign { path } => sign_package(&path)?,
+    Command::Trust { author, key } => {
+      TrustStore::load()?.trust(&author, &key)?;
+      println!("Trusting quest packages from {author}");
     }
+    Command::GitCredential { operation } => credential::run(&operation).await?,
   }
 
   Ok(())
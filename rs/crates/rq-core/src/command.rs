@@ -31,9 +31,20 @@ static ENV: LazyLock<HashMap<String, String>> = LazyLock::new(|| {
   }
 });
 
+/// Quests make commits, so tests give git an identity on machines without one, like CI.
+#[cfg(test)]
+const TEST_GIT_IDENTITY: [(&str, &str); 4] = [
+  ("GIT_AUTHOR_NAME", "rqst"),
+  ("GIT_AUTHOR_EMAIL", "rqst@example.com"),
+  ("GIT_COMMITTER_NAME", "rqst"),
+  ("GIT_COMMITTER_EMAIL", "rqst@example.com"),
+];
+
 pub fn command(args: &str, dir: &Path) -> Command {
   let mut arg_vec = shlex::split(args).expect("Invalid command");
   let mut cmd = Command::new(arg_vec.remove(0));
   cmd.current_dir(dir).envs(ENV.deref()).args(arg_vec);
+  #[cfg(test)]
+  cmd.envs(TEST_GIT_IDENTITY);
   cmd
 }
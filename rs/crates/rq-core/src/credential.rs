@@ -0,0 +1,212 @@
+//! A git credential helper that authenticates HTTPS remotes with the token RepoQuest already has,
+//! so learners can clone and push without setting up SSH keys.
+//!
+//! Repos cloned over HTTPS are configured to run the current executable with [`HELPER_ARG`],
+//! which answers git's requests via [`run`]. See `gitcredentials(7)` for the protocol.
+
+use std::{
+  collections::HashMap,
+  env,
+  future::Future,
+  io::{BufRead, Write},
+};
+
+use anyhow::{Context, Result};
+use url::Url;
+
+use crate::{gitea, github, gitlab};
+
+/// Subcommand that RepoQuest binaries dispatch to [`run`].
+pub const HELPER_ARG: &str = "git-credential";
+
+/// A username and password that a git host accepts for HTTPS.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Credentials {
+  pub username: String,
+  pub password: String,
+}
+
+fn host_of(url: &str) -> Option<String> {
+  Url::parse(url).ok()?.host_str().map(ToString::to_string)
+}
+
+/// Finds a token for `host` among the configured forges.
+pub async fn lookup(host: String) -> Result<Option<Credentials>> {
+  if let Some(config) = gitea::get_gitea_config()? {
+    if host_of(&config.url).as_ref() == Some(&host) {
+      return Ok(Some(Credentials {
+        username: "x-access-token".into(),
+        password: config.token,
+      }));
+    }
+  }
+
+  if let Some(config) = gitlab::get_gitlab_config()? {
+    if host_of(&config.url).as_ref() == Some(&host) {
+      return Ok(Some(Credentials {
+        username: "oauth2".into(),
+        password: config.token,
+      }));
+    }
+  }
+
+  // Git asks on every fetch and push, so use the stored token rather than checking it with
+  // Github each time.
+  if let Some(token) = github::stored_github_token(&host)? {
+    return Ok(Some(Credentials {
+      username: "x-access-token".into(),
+      password: token,
+    }));
+  }
+
+  Ok(None)
+}
+
+/// Returns the git config entry that makes git ask RepoQuest for credentials to `remote`,
+/// or `None` if the remote is not an HTTPS URL.
+pub fn helper_config(remote: &str) -> Result<Option<(String, String)>> {
+  let url = Url::parse(remote).ok();
+  let Some(host) = url
+    .filter(|url| url.scheme() == "https")
+    .and_then(|url| url.host_str().map(ToString::to_string))
+  else {
+    return Ok(None);
+  };
+  let exe = env::current_exe().context("Failed to find the RepoQuest executable")?;
+  // Helpers starting with `!` are run by the shell. Forward slashes keep Windows paths intact.
+  let exe = exe.display().to_string().replace('\\', "/");
+  let exe = shlex::try_quote(&exe).context("Invalid executable path")?;
+  Ok(Some((
+    format!("credential.https://{host}.helper"),
+    format!("!{exe} {HELPER_ARG}"),
+  )))
+}
+
+/// Answers a single request from git, where `operation` is `get`, `store` or `erase`.
+///
+/// Only `get` does anything: the tokens are owned by RepoQuest's config, not git.
+pub async fn respond<F>(
+  operation: &str,
+  input: impl BufRead,
+  mut output: impl Write,
+  lookup: impl FnOnce(String) -> F,
+) -> Result<()>
+where
+  F: Future<Output = Result<Option<Credentials>>>,
+{
+  if operation != "get" {
+    return Ok(());
+  }
+
+  let mut attrs = HashMap::new();
+  for line in input.lines() {
+    let line = line.context("Failed to read credential request")?;
+    if line.is_empty() {
+      break;
+    }
+    if let Some((key, value)) = line.split_once('=') {
+      attrs.insert(key.to_string(), value.to_string());
+    }
+  }
+
+  let (Some(protocol), Some(host)) = (attrs.remove("protocol"), attrs.remove("host")) else {
+    return Ok(());
+  };
+  if protocol != "https" {
+    return Ok(());
+  }
+
+  // Git falls back to its other helpers (or a prompt) if we print nothing.
+  if let Some(Credentials { username, password }) = lookup(host).await? {
+    writeln!(output, "username={username}")?;
+    writeln!(output, "password={password}")?;
+  }
+
+  Ok(())
+}
+
+/// Runs the credential helper over stdin and stdout.
+pub async fn run(operation: &str) -> Result<()> {
+  respond(
+    operation,
+    std::io::stdin().lock(),
+    std::io::stdout().lock(),
+    lookup,
+  )
+  .await
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{command::command, git::GitRepo};
+
+  async fn github(host: String) -> Result<Option<Credentials>> {
+    Ok((host == "github.com").then(|| Credentials {
+      username: "x-access-token".into(),
+      password: "secret".into(),
+    }))
+  }
+
+  async fn respond_to(operation: &str, input: &str) -> String {
+    let mut output = Vec::new();
+    respond(operation, input.as_bytes(), &mut output, github)
+      .await
+      .unwrap();
+    String::from_utf8(output).unwrap()
+  }
+
+  #[tokio::test]
+  async fn get() {
+    assert_eq!(
+      respond_to("get", "protocol=https\nhost=github.com\npath=a/b.git\n\n").await,
+      "username=x-access-token\npassword=secret\n"
+    );
+    assert_eq!(
+      respond_to("get", "protocol=https\nhost=gitlab.com\n\n").await,
+      ""
+    );
+    assert_eq!(
+      respond_to("get", "protocol=http\nhost=github.com\n\n").await,
+      ""
+    );
+    assert_eq!(
+      respond_to("store", "protocol=https\nhost=github.com\n\n").await,
+      ""
+    );
+  }
+
+  #[test]
+  fn config() -> Result<()> {
+    let (key, value) = helper_config("https://github.example.edu/learner/quest")?.unwrap();
+    assert_eq!(key, "credential.https://github.example.edu.helper");
+    assert!(value.starts_with('!'));
+    assert!(value.ends_with(&format!(" {HELPER_ARG}")));
+    assert!(helper_config("git@github.com:learner/quest.git")?.is_none());
+    assert!(helper_config("/tmp/quest.git")?.is_none());
+    Ok(())
+  }
+
+  #[test]
+  fn clone_persists_config() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let status = command("git init --bare quest.git", dir.path()).status()?;
+    assert!(status.success());
+
+    let entry = helper_config("https://github.com/learner/quest")?.unwrap();
+    let origin = dir.path().join("quest.git").display().to_string();
+    GitRepo::clone(
+      &dir.path().join("quest"),
+      &origin,
+      std::slice::from_ref(&entry),
+    )?;
+
+    let output = command(
+      &format!("git config --get {}", entry.0),
+      &dir.path().join("quest"),
+    )
+    .output()?;
+    assert_eq!(String::from_utf8(output.stdout)?.trim_end(), entry.1);
+    Ok(())
+  }
+}
@@ -0,0 +1,447 @@
+use anyhow::{ensure, Context, Error, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use parking_lot::MappedMutexGuard;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use std::path::Path;
+use tracing::warn;
+
+use crate::{
+  credential,
+  git::{GitRepo, MergeType},
+  utils,
+};
+
+#[cfg(test)]
+pub(crate) mod fake;
+pub mod local;
+#[cfg(test)]
+pub(crate) mod model;
+pub(crate) mod rest;
+
+/// Whether an issue or PR is still open. Merged PRs are closed.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IssueState {
+  Open,
+  Closed,
+}
+
+/// A label that issues and PRs can be tagged with.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Label {
+  pub name: String,
+  /// In hex without a leading `#`, e.g. `ededed`.
+  pub color: String,
+  pub description: Option<String>,
+  /// Whether the forge gives every new repo this label, so it never needs to be created.
+  #[serde(default)]
+  pub default: bool,
+}
+
+/// An issue on a forge.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Issue {
+  pub number: u64,
+  pub title: String,
+  pub body: Option<String>,
+  /// The names of the issue's labels.
+  pub labels: Vec<String>,
+  pub state: IssueState,
+  /// Where the learner can read the issue.
+  pub html_url: String,
+  pub created_at: DateTime<Utc>,
+  pub updated_at: DateTime<Utc>,
+}
+
+/// A PR on a forge, which GitLab calls a merge request.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PullRequest {
+  pub number: u64,
+  pub title: String,
+  pub body: Option<String>,
+  /// The names of the PR's labels.
+  pub labels: Vec<String>,
+  /// The branch with the PR's changes.
+  pub head: String,
+  /// The branch the PR is merged into.
+  pub base: String,
+  pub state: IssueState,
+  /// Where the learner can read the PR.
+  pub html_url: String,
+  pub created_at: DateTime<Utc>,
+  pub updated_at: DateTime<Utc>,
+  pub merged_at: Option<DateTime<Utc>>,
+}
+
+/// A review comment on a PR, anchored to a line of a file in the PR's diff.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReviewComment {
+  /// Comments are made in order of their IDs.
+  pub id: u64,
+  pub path: String,
+  pub line: Option<u64>,
+  pub body: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FullPullRequest {
+  pub data: PullRequest,
+  pub comments: Vec<ReviewComment>,
+}
+
+/// An issue in a quest's template, with only what is needed to file it in the learner's repo.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct QuestIssue {
+  pub title: String,
+  pub body: String,
+  pub labels: Vec<String>,
+}
+
+/// A PR in a quest's template, with only what is needed to file it in the learner's repo.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct QuestPullRequest {
+  pub title: String,
+  pub body: String,
+  pub labels: Vec<String>,
+  /// The branch with the PR's changes.
+  pub head: String,
+  /// The branch the PR is merged into.
+  pub base: String,
+  pub comments: Vec<QuestReviewComment>,
+}
+
+/// A review comment on a template PR, anchored to a line of a file in the PR's diff.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct QuestReviewComment {
+  pub path: String,
+  pub line: Option<u64>,
+  pub body: String,
+}
+
+impl TryFrom<&Issue> for QuestIssue {
+  type Error = Error;
+
+  fn try_from(issue: &Issue) -> Result<Self> {
+    let body = issue
+      .body
+      .clone()
+      .with_context(|| format!("Author error: issue #{} is missing a body", issue.number))?;
+    Ok(QuestIssue {
+      title: issue.title.clone(),
+      body,
+      labels: issue.labels.clone(),
+    })
+  }
+}
+
+impl TryFrom<&FullPullRequest> for QuestPullRequest {
+  type Error = Error;
+
+  fn try_from(pr: &FullPullRequest) -> Result<Self> {
+    let data = &pr.data;
+    let body = data
+      .body
+      .clone()
+      .with_context(|| format!("Author error: PR #{} is missing a body", data.number))?;
+    Ok(QuestPullRequest {
+      title: data.title.clone(),
+      body,
+      labels: data.labels.clone(),
+      head: data.head.clone(),
+      base: data.base.clone(),
+      comments: pr.comments.iter().map(QuestReviewComment::from).collect(),
+    })
+  }
+}
+
+impl From<&ReviewComment> for QuestReviewComment {
+  fn from(comment: &ReviewComment) -> Self {
+    QuestReviewComment {
+      path: comment.path.clone(),
+      line: comment.line,
+      body: comment.body.clone(),
+    }
+  }
+}
+
+impl QuestPullRequest {
+  pub fn matches(&self, selector: &PullSelector) -> bool {
+    match selector {
+      PullSelector::Branch(branch) => &self.head == branch,
+      PullSelector::Label(label) => self.labels.contains(label),
+    }
+  }
+}
+
+#[derive(Debug)]
+pub enum PullSelector {
+  Branch(String),
+  Label(String),
+}
+
+pub fn find_pr<'a>(
+  selector: &PullSelector,
+  prs: impl IntoIterator<Item = &'a FullPullRequest> + 'a,
+) -> Option<usize> {
+  prs.into_iter().position(|pr| match selector {
+    PullSelector::Branch(branch) => &pr.data.head == branch,
+    PullSelector::Label(label) => pr.data.labels.contains(label),
+  })
+}
+
+pub fn find_issue<'a>(
+  label_name: &str,
+  issues: impl IntoIterator<Item = &'a Issue> + 'a,
+) -> Option<usize> {
+  issues
+    .into_iter()
+    .position(|issue| issue.labels.iter().any(|label| label == label_name))
+}
+
+pub const RESET_LABEL: &str = "reset";
+
+/// How git talks to a forge. Over HTTPS, git authenticates with RepoQuest's token through
+/// the [credential helper](crate::credential).
+#[derive(Serialize, Deserialize, Type, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GitProtocol {
+  Ssh,
+  Https,
+}
+
+/// How much of a forge's API quota is left.
+#[derive(Serialize, Deserialize, Type, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RateLimit {
+  /// Requests left before the quota resets, if the forge has reported it.
+  pub remaining: Option<u32>,
+  pub limit: Option<u32>,
+  #[specta(type = Option<String>)]
+  pub reset: Option<DateTime<Utc>>,
+  /// When requests can resume, if the forge is currently refusing them.
+  #[specta(type = Option<String>)]
+  pub paused_until: Option<DateTime<Utc>>,
+}
+
+impl RateLimit {
+  pub fn is_paused(&self, now: DateTime<Utc>) -> bool {
+    self.paused_until.is_some_and(|until| until > now)
+  }
+}
+
+/// A repository hosted on a forge (e.g., Github) that a quest can read from or play in.
+///
+/// Implementors provide the primitive operations, and the quest-level operations
+/// (like copying a PR from a template) are built on top of them.
+#[async_trait]
+pub trait Forge: Send + Sync + 'static {
+  fn user(&self) -> &str;
+  fn name(&self) -> &str;
+  fn remote(&self, protocol: GitProtocol) -> String;
+
+  /// Whether the repo only exists on this machine, in which case the learner closes issues
+  /// and merges PRs through RepoQuest rather than a website.
+  fn is_local(&self) -> bool {
+    false
+  }
+
+  /// The API quota as of the last request, if the forge rate limits requests.
+  fn rate_limit(&self) -> Option<RateLimit> {
+    None
+  }
+
+  /// Fails if the learner's credentials can't write to the repo, so that a quest doesn't
+  /// start only to fail on its first issue or PR.
+  async fn check_access(&self) -> Result<()> {
+    Ok(())
+  }
+
+  /// Refreshes the cached PRs and issues. Returns false if the repo does not exist.
+  async fn fetch(&self) -> Result<bool>;
+  fn prs(&self) -> MappedMutexGuard<'_, Vec<FullPullRequest>>;
+  fn issues(&self) -> MappedMutexGuard<'_, Vec<Issue>>;
+
+  /// Fills the cache read by `prs` and `issues` without contacting the forge, e.g. with what
+  /// an earlier session fetched. The next `fetch` replaces it.
+  fn restore(&self, prs: Vec<FullPullRequest>, issues: Vec<Issue>);
+
+  /// Returns every PR and issue, most recently created first, without touching the cache,
+  /// or `None` if the repo does not exist.
+  async fn list_all(&self) -> Result<Option<(Vec<PullRequest>, Vec<Issue>)>>;
+
+  async fn labels(&self) -> Result<Vec<Label>>;
+  async fn create_labels(&self, labels: &[Label]) -> Result<()>;
+  async fn create_pr(
+    &self,
+    title: &str,
+    head: &str,
+    base: &str,
+    body: &str,
+    labels: &[String],
+  ) -> Result<PullRequest>;
+  async fn copy_pr_comment(
+    &self,
+    pr: u64,
+    comment: &QuestReviewComment,
+    commit: &str,
+  ) -> Result<()>;
+  async fn create_issue(&self, title: &str, body: &str, labels: &[String]) -> Result<Issue>;
+  async fn close_issue(&self, issue: &Issue) -> Result<()>;
+  async fn merge_pr(&self, pr: &PullRequest) -> Result<()>;
+  async fn delete(&self) -> Result<()>;
+
+  /// How an issue body refers to a PR, e.g. `#3` on Github or `!3` on GitLab.
+  fn pr_reference(&self, number: u64) -> String {
+    format!("#{number}")
+  }
+
+  fn pr(&self, selector: &PullSelector) -> Option<MappedMutexGuard<'_, FullPullRequest>> {
+    let prs = self.prs();
+    let idx = find_pr(selector, prs.iter())?;
+    Some(MappedMutexGuard::map(prs, |prs| &mut prs[idx]))
+  }
+
+  fn issue(&self, label_name: &str) -> Option<MappedMutexGuard<'_, Issue>> {
+    let issues = self.issues();
+    let idx = find_issue(label_name, issues.iter())?;
+    Some(MappedMutexGuard::map(issues, |issues| &mut issues[idx]))
+  }
+
+  fn clone(&self, path: &Path, protocol: GitProtocol) -> Result<GitRepo> {
+    let remote = self.remote(protocol);
+    let config = match protocol {
+      GitProtocol::Ssh => None,
+      GitProtocol::Https => credential::helper_config(&remote)?,
+    };
+    GitRepo::clone(&path.join(self.name()), &remote, config.as_slice())
+  }
+
+  async fn copy_pr(
+    &self,
+    pr: &QuestPullRequest,
+    head: &str,
+    merge_type: MergeType,
+  ) -> Result<PullRequest> {
+    let mut body = pr.body.clone();
+
+    let is_reset = match merge_type {
+      MergeType::SolutionReset => {
+        body.push_str(r#"
+
+Note: due to a merge conflict, this PR is a hard reset to the reference solution, and may have overwritten your previous changes."#);
+        true
+      }
+
+      MergeType::StarterReset => {
+        body.push_str(r#"
+
+Note: due to a merge conflict, this PR is a hard reset to the starter code, and may have overwritten your previous changes."#);
+        true
+      }
+
+      MergeType::Success => false,
+    };
+
+    let mut labels = pr.labels.clone();
+    if is_reset {
+      labels.push(RESET_LABEL.into());
+    }
+
+    let self_pr = self
+      .create_pr(
+        &pr.title, &pr.head, "main", // don't copy base
+        &body, &labels,
+      )
+      .await
+      .context("Failed to create new PR")?;
+
+    for comment in &pr.comments {
+      self
+        .copy_pr_comment(self_pr.number, comment, head)
+        .await
+        .context("Failed to add comment to PR")?;
+    }
+
+    Ok(self_pr)
+  }
+
+  fn process_issue_body(&self, body: &str) -> String {
+    let re = Regex::new(r"\{\{ (\S+) (\S+) \}\}").unwrap();
+    let mut new_body = body.to_string();
+    let substitutions = re.captures_iter(body).filter_map(|cap| {
+      let full_match = cap.get(0).unwrap();
+      let label = &cap[1];
+      let kind = &cap[2];
+      let reference = match kind {
+        "pr" => {
+          let Some(pr) = self.pr(&PullSelector::Label(label.to_string())) else {
+            warn!("No PR with label {label}");
+            return None;
+          };
+          self.pr_reference(pr.data.number)
+        }
+        "issue" => {
+          let Some(issue) = self.issue(label) else {
+            warn!("No issue with label {label}");
+            return None;
+          };
+          format!("#{}", issue.number)
+        }
+        _ => unimplemented!(),
+      };
+
+      Some((full_match.range(), reference))
+    });
+    utils::replace_many_ranges(&mut new_body, substitutions);
+
+    new_body
+  }
+
+  async fn copy_issue(&self, issue: &QuestIssue) -> Result<Issue> {
+    let body_processed = self.process_issue_body(&issue.body);
+    let issue = self
+      .create_issue(&issue.title, &body_processed, &issue.labels)
+      .await
+      .with_context(|| format!("Failed to create issue: {}", issue.title))?;
+    Ok(issue)
+  }
+}
+
+/// An account on a forge which can create and look up repositories.
+#[async_trait]
+pub trait ForgeHost: Send + Sync + 'static {
+  /// Returns the name of the currently authenticated user.
+  async fn current_user(&self) -> Result<String>;
+
+  /// Returns a handle to a repo, without checking whether it exists.
+  fn repo(&self, user: &str, name: &str) -> Box<dyn Forge>;
+
+  /// Creates a new empty repo owned by the current user.
+  async fn create_repo(&self, name: &str) -> Result<Box<dyn Forge>>;
+
+  /// Creates a new repo owned by the current user with the contents of `template`.
+  async fn generate_repo(&self, template: &dyn Forge) -> Result<Box<dyn Forge>>;
+
+  /// Checks that the user's machine can push to repos on this host over SSH.
+  fn check_ssh(&self) -> Result<()> {
+    Ok(())
+  }
+
+  /// Picks SSH if the user's keys are set up for this host, and HTTPS otherwise.
+  fn detect_protocol(&self) -> GitProtocol {
+    match self.check_ssh() {
+      Ok(()) => GitProtocol::Ssh,
+      Err(e) => {
+        warn!("SSH is unavailable, falling back to HTTPS: {e:?}");
+        GitProtocol::Https
+      }
+    }
+  }
+
+  async fn load(&self, user: &str, name: &str) -> Result<Box<dyn Forge>> {
+    let repo = self.repo(user, name);
+    ensure!(repo.fetch().await?, "Not found");
+    Ok(repo)
+  }
+}
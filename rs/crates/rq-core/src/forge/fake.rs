@@ -0,0 +1,357 @@
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use parking_lot::{MappedMutexGuard, Mutex, MutexGuard};
+use std::{
+  collections::HashMap,
+  ffi::OsStr,
+  fs,
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+};
+
+use super::{
+  local::{git, merge_branches, RepoKey, RepoState},
+  Forge, ForgeHost, FullPullRequest, GitProtocol, Issue, IssueState, Label, PullRequest,
+  QuestReviewComment, ReviewComment,
+};
+
+struct HostState {
+  root: PathBuf,
+  user: String,
+  repos: Mutex<HashMap<RepoKey, Arc<Mutex<RepoState>>>>,
+  offline: AtomicBool,
+}
+
+/// An in-memory forge for tests, whose repos are bare git repositories under `root`.
+///
+/// Issues, PRs, labels and comments only live as long as the host.
+#[derive(Clone)]
+pub struct FakeHost(Arc<HostState>);
+
+impl FakeHost {
+  pub fn new(root: &Path, user: &str) -> Self {
+    FakeHost(Arc::new(HostState {
+      root: root.to_path_buf(),
+      user: user.to_string(),
+      repos: Mutex::new(HashMap::new()),
+      offline: AtomicBool::new(false),
+    }))
+  }
+
+  /// Makes every request fail until the host is back online, like a forge that can't be
+  /// reached. Git operations on its repos still work.
+  pub fn set_offline(&self, offline: bool) {
+    self.0.offline.store(offline, Ordering::SeqCst);
+  }
+
+  fn check_online(&self) -> Result<()> {
+    ensure!(
+      !self.0.offline.load(Ordering::SeqCst),
+      "Failed to reach the forge"
+    );
+    Ok(())
+  }
+
+  /// Path to the bare repository backing `user/name`.
+  pub fn repo_path(&self, user: &str, name: &str) -> PathBuf {
+    self.0.root.join(user).join(format!("{name}.git"))
+  }
+
+  /// Creates an empty repo owned by any user, e.g. to set up a quest template.
+  pub fn add_repo(&self, user: &str, name: &str) -> Result<FakeRepo> {
+    let path = self.repo_path(user, name);
+    fs::create_dir_all(&path)
+      .with_context(|| format!("Failed to create directory: {}", path.display()))?;
+    git(&path, &["init", "--bare", "--initial-branch=main"])?;
+    self.register(user, name)
+  }
+
+  fn register(&self, user: &str, name: &str) -> Result<FakeRepo> {
+    let key = (user.to_string(), name.to_string());
+    let mut repos = self.0.repos.lock();
+    ensure!(
+      !repos.contains_key(&key),
+      "Repo already exists: {user}/{name}"
+    );
+    repos.insert(key, Arc::default());
+    Ok(self.handle(user, name))
+  }
+
+  fn handle(&self, user: &str, name: &str) -> FakeRepo {
+    FakeRepo {
+      host: self.clone(),
+      user: user.to_string(),
+      name: name.to_string(),
+      prs: Mutex::new(None),
+      issues: Mutex::new(None),
+    }
+  }
+}
+
+#[async_trait]
+impl ForgeHost for FakeHost {
+  async fn current_user(&self) -> Result<String> {
+    self.check_online()?;
+    Ok(self.0.user.clone())
+  }
+
+  fn repo(&self, user: &str, name: &str) -> Box<dyn Forge> {
+    Box::new(self.handle(user, name))
+  }
+
+  async fn create_repo(&self, name: &str) -> Result<Box<dyn Forge>> {
+    self.check_online()?;
+    let repo = self.add_repo(&self.0.user, name)?;
+    Ok(Box::new(repo))
+  }
+
+  async fn generate_repo(&self, template: &dyn Forge) -> Result<Box<dyn Forge>> {
+    self.check_online()?;
+    // Like Github, a generated repo only contains the template's default branch.
+    let src = self.repo_path(template.user(), template.name());
+    let dst = self.repo_path(&self.0.user, template.name());
+    fs::create_dir_all(dst.parent().unwrap())?;
+    let args: [&OsStr; 7] = [
+      "clone".as_ref(),
+      "--bare".as_ref(),
+      "--single-branch".as_ref(),
+      "--branch".as_ref(),
+      "main".as_ref(),
+      src.as_ref(),
+      dst.as_ref(),
+    ];
+    git(&self.0.root, &args).context("Failed to copy template repo")?;
+    let repo = self.register(&self.0.user, template.name())?;
+    Ok(Box::new(repo))
+  }
+}
+
+pub struct FakeRepo {
+  host: FakeHost,
+  user: String,
+  name: String,
+  prs: Mutex<Option<Vec<FullPullRequest>>>,
+  issues: Mutex<Option<Vec<Issue>>>,
+}
+
+impl FakeRepo {
+  fn path(&self) -> PathBuf {
+    self.host.repo_path(&self.user, &self.name)
+  }
+
+  fn html_url(&self, kind: &str, number: u64) -> String {
+    format!(
+      "https://fake.test/{}/{}/{kind}/{number}",
+      self.user, self.name
+    )
+  }
+
+  /// Returns the repo's state, or `None` if it doesn't exist.
+  fn try_state(&self) -> Result<Option<Arc<Mutex<RepoState>>>> {
+    self.host.check_online()?;
+    let key = (self.user.clone(), self.name.clone());
+    Ok(self.host.0.repos.lock().get(&key).cloned())
+  }
+
+  fn state(&self) -> Result<Arc<Mutex<RepoState>>> {
+    self
+      .try_state()?
+      .ok_or_else(|| anyhow!("Repo not found: {}/{}", self.user, self.name))
+  }
+}
+
+#[async_trait]
+impl Forge for FakeRepo {
+  fn user(&self) -> &str {
+    &self.user
+  }
+
+  fn name(&self) -> &str {
+    &self.name
+  }
+
+  fn remote(&self, _protocol: GitProtocol) -> String {
+    self.path().display().to_string()
+  }
+
+  async fn fetch(&self) -> Result<bool> {
+    let Some(state) = self.try_state()? else {
+      return Ok(false);
+    };
+    let state = state.lock();
+    *self.prs.lock() = Some(state.prs.clone());
+    *self.issues.lock() = Some(state.issues.clone());
+    Ok(true)
+  }
+
+  fn prs(&self) -> MappedMutexGuard<'_, Vec<FullPullRequest>> {
+    MutexGuard::map(self.prs.lock(), |opt| {
+      opt.as_mut().expect("PRs not populated")
+    })
+  }
+
+  fn issues(&self) -> MappedMutexGuard<'_, Vec<Issue>> {
+    MutexGuard::map(self.issues.lock(), |opt| {
+      opt.as_mut().expect("Issues not populated")
+    })
+  }
+
+  fn restore(&self, prs: Vec<FullPullRequest>, issues: Vec<Issue>) {
+    *self.prs.lock() = Some(prs);
+    *self.issues.lock() = Some(issues);
+  }
+
+  async fn list_all(&self) -> Result<Option<(Vec<PullRequest>, Vec<Issue>)>> {
+    let Some(state) = self.try_state()? else {
+      return Ok(None);
+    };
+    let state = state.lock();
+    let prs = state.prs.iter().rev().map(|pr| pr.data.clone()).collect();
+    let issues = state.issues.iter().rev().cloned().collect();
+    Ok(Some((prs, issues)))
+  }
+
+  async fn labels(&self) -> Result<Vec<Label>> {
+    Ok(self.state()?.lock().labels.clone())
+  }
+
+  async fn create_labels(&self, labels: &[Label]) -> Result<()> {
+    let state = self.state()?;
+    let mut state = state.lock();
+    for label in labels.iter().filter(|label| !label.default) {
+      ensure!(
+        state.labels.iter().all(|other| other.name != label.name),
+        "Label already exists: {}",
+        label.name
+      );
+      state.labels.push(label.clone());
+    }
+    Ok(())
+  }
+
+  async fn create_pr(
+    &self,
+    title: &str,
+    head: &str,
+    base: &str,
+    body: &str,
+    labels: &[String],
+  ) -> Result<PullRequest> {
+    let state = self.state()?;
+    git(&self.path(), &["rev-parse", &format!("refs/heads/{head}")])
+      .with_context(|| format!("Head branch does not exist: {head}"))?;
+    let mut state = state.lock();
+    let number = state.next_id();
+    state.resolve_labels(labels);
+    let now = Utc::now();
+    let pr = PullRequest {
+      number,
+      title: title.to_string(),
+      body: Some(body.to_string()),
+      labels: labels.to_vec(),
+      head: head.to_string(),
+      base: base.to_string(),
+      state: IssueState::Open,
+      html_url: self.html_url("pull", number),
+      created_at: now,
+      updated_at: now,
+      merged_at: None,
+    };
+    state.prs.push(FullPullRequest {
+      data: pr.clone(),
+      comments: Vec::new(),
+    });
+    Ok(pr)
+  }
+
+  async fn copy_pr_comment(
+    &self,
+    pr: u64,
+    comment: &QuestReviewComment,
+    _commit: &str,
+  ) -> Result<()> {
+    let state = self.state()?;
+    let mut state = state.lock();
+    let id = state.next_id();
+    let full_pr = state
+      .prs
+      .iter_mut()
+      .find(|full_pr| full_pr.data.number == pr)
+      .ok_or_else(|| anyhow!("PR not found: {pr}"))?;
+    full_pr.comments.push(ReviewComment {
+      id,
+      path: comment.path.clone(),
+      line: comment.line,
+      body: comment.body.clone(),
+    });
+    Ok(())
+  }
+
+  async fn create_issue(&self, title: &str, body: &str, labels: &[String]) -> Result<Issue> {
+    let state = self.state()?;
+    let mut state = state.lock();
+    let number = state.next_id();
+    state.resolve_labels(labels);
+    let now = Utc::now();
+    let issue = Issue {
+      number,
+      title: title.to_string(),
+      body: Some(body.to_string()),
+      labels: labels.to_vec(),
+      state: IssueState::Open,
+      html_url: self.html_url("issues", number),
+      created_at: now,
+      updated_at: now,
+    };
+    state.issues.push(issue.clone());
+    Ok(issue)
+  }
+
+  async fn close_issue(&self, issue: &Issue) -> Result<()> {
+    let state = self.state()?;
+    let mut state = state.lock();
+    let stored = state
+      .issues
+      .iter_mut()
+      .find(|other| other.number == issue.number)
+      .ok_or_else(|| anyhow!("Issue not found: {}", issue.number))?;
+    stored.state = IssueState::Closed;
+    stored.updated_at = Utc::now();
+    Ok(())
+  }
+
+  async fn merge_pr(&self, pr: &PullRequest) -> Result<()> {
+    let state = self.state()?;
+    let mut state = state.lock();
+    let stored = (state.prs.iter_mut())
+      .find(|other| other.data.number == pr.number)
+      .ok_or_else(|| anyhow!("PR not found: {}", pr.number))?;
+    if stored.data.merged_at.is_some() {
+      bail!("PR already merged: {}", pr.number);
+    }
+
+    let message = format!("Merge pull request #{}", pr.number);
+    merge_branches(&self.path(), &stored.data.base, &stored.data.head, &message)
+      .with_context(|| format!("Failed to merge PR: {}", pr.number))?;
+
+    let now = Utc::now();
+    stored.data.state = IssueState::Closed;
+    stored.data.updated_at = now;
+    stored.data.merged_at = Some(now);
+    Ok(())
+  }
+
+  async fn delete(&self) -> Result<()> {
+    self.host.check_online()?;
+    let key = (self.user.clone(), self.name.clone());
+    self.host.0.repos.lock().remove(&key);
+    let path = self.path();
+    fs::remove_dir_all(&path)
+      .with_context(|| format!("Failed to delete repo: {}", path.display()))?;
+    Ok(())
+  }
+}
@@ -0,0 +1,628 @@
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use parking_lot::{MappedMutexGuard, Mutex, MutexGuard};
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::HashMap,
+  ffi::OsStr,
+  fmt::Write as _,
+  fs::{self, OpenOptions},
+  io::Write as _,
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+use url::Url;
+
+use super::{
+  Forge, ForgeHost, FullPullRequest, GitProtocol, Issue, IssueState, Label, PullRequest,
+  QuestReviewComment, ReviewComment,
+};
+use crate::{command::command, git::GitRepo};
+
+/// Directory inside the learner's checkout where the forge keeps a quest's issues and PRs.
+/// It is excluded from git, so it never ends up in the quest's commits.
+const STORE_DIR: &str = ".rqst";
+
+/// The user that owns quests created in local mode.
+pub const LOCAL_USER: &str = "learner";
+
+#[derive(Default, Serialize, Deserialize)]
+pub(super) struct RepoState {
+  pub(super) prs: Vec<FullPullRequest>,
+  pub(super) issues: Vec<Issue>,
+  pub(super) labels: Vec<Label>,
+  next_id: u64,
+}
+
+impl RepoState {
+  // Like Github, issues and PRs share a numbering.
+  pub(super) fn next_id(&mut self) -> u64 {
+    self.next_id += 1;
+    self.next_id
+  }
+
+  // Like Github, labels that don't exist yet are created on the fly.
+  pub(super) fn resolve_labels(&mut self, names: &[String]) {
+    for name in names {
+      if self.labels.iter().all(|label| &label.name != name) {
+        self.labels.push(Label {
+          name: name.clone(),
+          color: "ededed".into(),
+          description: None,
+          default: false,
+        });
+      }
+    }
+  }
+}
+
+pub(super) type RepoKey = (String, String);
+
+struct HostState {
+  root: PathBuf,
+  user: String,
+  repos: Mutex<HashMap<RepoKey, Arc<Mutex<RepoState>>>>,
+  /// Where each repo is checked out, which is where its issues and PRs are stored.
+  checkouts: Mutex<HashMap<RepoKey, PathBuf>>,
+}
+
+/// A forge whose repos are bare git repositories under a local directory.
+///
+/// Issues, PRs, labels and comments are stored as JSON in the `.rqst` directory of the
+/// learner's checkout, along with a markdown rendering of each issue and PR for the learner
+/// to read. This lets quests be played without any account or network. Repos that aren't
+/// checked out, like templates set up with `add_repo`, only keep their issues and PRs in
+/// memory.
+#[derive(Clone)]
+pub struct LocalHost(Arc<HostState>);
+
+// Arguments are passed to git as-is, so paths and messages don't need quoting.
+pub(super) fn git<S: AsRef<OsStr>>(dir: &Path, args: &[S]) -> Result<String> {
+  let args_str = args
+    .iter()
+    .map(|arg| arg.as_ref().to_string_lossy())
+    .collect::<Vec<_>>()
+    .join(" ");
+  tracing::debug!("git: {args_str}");
+  let output = command("git", dir)
+    .args(args)
+    .output()
+    .with_context(|| format!("git failed: {args_str}"))?;
+  ensure!(
+    output.status.success(),
+    "git failed: {args_str}, stderr:\n{}",
+    String::from_utf8_lossy(&output.stderr)
+  );
+  Ok(String::from_utf8(output.stdout)?.trim_end().to_string())
+}
+
+impl LocalHost {
+  pub fn new(root: &Path, user: &str) -> Self {
+    LocalHost(Arc::new(HostState {
+      root: root.to_path_buf(),
+      user: user.to_string(),
+      repos: Mutex::new(HashMap::new()),
+      checkouts: Mutex::new(HashMap::new()),
+    }))
+  }
+
+  /// The host used for local mode, which keeps its repos in `~/.rqst/local`.
+  pub fn open_default() -> Result<Self> {
+    let home = home::home_dir().ok_or_else(|| anyhow!("Could not find home directory"))?;
+    Ok(LocalHost::new(
+      &home.join(".rqst").join("local"),
+      LOCAL_USER,
+    ))
+  }
+
+  /// Returns the local host that a quest checked out at `dir` was created on, if any.
+  pub fn detect(dir: &Path) -> Option<Self> {
+    if !dir.join(STORE_DIR).join("state.json").exists() {
+      return None;
+    }
+    let url = GitRepo::new(dir).remote_url("origin").ok()?;
+    let path = PathBuf::from(url);
+    let name = path.file_name()?.to_str()?.strip_suffix(".git")?;
+    let user_dir = path.parent()?;
+    let user = user_dir.file_name()?.to_str()?;
+    let host = LocalHost::new(user_dir.parent()?, user);
+    let key = (user.to_string(), name.to_string());
+    host.0.checkouts.lock().insert(key, dir.to_path_buf());
+    Some(host)
+  }
+
+  /// Path to the bare repository backing `user/name`.
+  pub fn repo_path(&self, user: &str, name: &str) -> PathBuf {
+    self.0.root.join(user).join(format!("{name}.git"))
+  }
+
+  /// Creates an empty repo owned by any user, e.g. to set up a quest template.
+  pub fn add_repo(&self, user: &str, name: &str) -> Result<LocalRepo> {
+    let path = self.repo_path(user, name);
+    ensure!(!path.exists(), "Repo already exists: {user}/{name}");
+    fs::create_dir_all(&path)
+      .with_context(|| format!("Failed to create directory: {}", path.display()))?;
+    git(&path, &["init", "--bare", "--initial-branch=main"])?;
+    self.register(user, name)
+  }
+
+  fn register(&self, user: &str, name: &str) -> Result<LocalRepo> {
+    let repo = self.handle(user, name);
+    let state = Arc::new(Mutex::new(RepoState::default()));
+    self.0.repos.lock().insert(repo.key(), state);
+    Ok(repo)
+  }
+
+  fn handle(&self, user: &str, name: &str) -> LocalRepo {
+    LocalRepo {
+      host: self.clone(),
+      user: user.to_string(),
+      name: name.to_string(),
+      prs: Mutex::new(None),
+      issues: Mutex::new(None),
+    }
+  }
+}
+
+#[async_trait]
+impl ForgeHost for LocalHost {
+  async fn current_user(&self) -> Result<String> {
+    Ok(self.0.user.clone())
+  }
+
+  fn repo(&self, user: &str, name: &str) -> Box<dyn Forge> {
+    Box::new(self.handle(user, name))
+  }
+
+  async fn create_repo(&self, name: &str) -> Result<Box<dyn Forge>> {
+    let repo = self.add_repo(&self.0.user, name)?;
+    Ok(Box::new(repo))
+  }
+
+  async fn generate_repo(&self, template: &dyn Forge) -> Result<Box<dyn Forge>> {
+    // Like Github, a generated repo only contains the template's default branch.
+    let src = self.repo_path(template.user(), template.name());
+    let dst = self.repo_path(&self.0.user, template.name());
+    ensure!(
+      !dst.exists(),
+      "Repo already exists: {}/{}",
+      self.0.user,
+      template.name()
+    );
+    fs::create_dir_all(dst.parent().unwrap())?;
+    let args: [&OsStr; 7] = [
+      "clone".as_ref(),
+      "--bare".as_ref(),
+      "--single-branch".as_ref(),
+      "--branch".as_ref(),
+      "main".as_ref(),
+      src.as_ref(),
+      dst.as_ref(),
+    ];
+    git(&self.0.root, &args).context("Failed to copy template repo")?;
+    let repo = self.register(&self.0.user, template.name())?;
+    Ok(Box::new(repo))
+  }
+}
+
+pub struct LocalRepo {
+  host: LocalHost,
+  user: String,
+  name: String,
+  prs: Mutex<Option<Vec<FullPullRequest>>>,
+  issues: Mutex<Option<Vec<Issue>>>,
+}
+
+fn render_labels(labels: &[String]) -> String {
+  let names = labels
+    .iter()
+    .map(|label| format!("`{label}`"))
+    .collect::<Vec<_>>();
+  names.join(", ")
+}
+
+fn render_issue(issue: &Issue) -> String {
+  let state = match issue.state {
+    IssueState::Open => "open",
+    IssueState::Closed => "closed",
+  };
+  let mut md = format!("# {} (#{})\n\n", issue.title, issue.number);
+  writeln!(md, "**State:** {state}  ").unwrap();
+  writeln!(md, "**Labels:** {}\n", render_labels(&issue.labels)).unwrap();
+  md.push_str(issue.body.as_deref().unwrap_or_default());
+  md.push('\n');
+  md
+}
+
+fn render_pr(pr: &FullPullRequest) -> String {
+  let data = &pr.data;
+  let state = if data.merged_at.is_some() {
+    "merged"
+  } else {
+    match data.state {
+      IssueState::Open => "open",
+      IssueState::Closed => "closed",
+    }
+  };
+  let mut md = format!("# {} (#{})\n\n", data.title, data.number);
+  writeln!(md, "**State:** {state}  ").unwrap();
+  writeln!(md, "**Branches:** `{}` into `{}`  ", data.head, data.base).unwrap();
+  writeln!(md, "**Labels:** {}\n", render_labels(&data.labels)).unwrap();
+  md.push_str(data.body.as_deref().unwrap_or_default());
+  md.push('\n');
+  if !pr.comments.is_empty() {
+    md.push_str("\n## Comments\n");
+    for comment in &pr.comments {
+      let line = comment
+        .line
+        .map(|line| format!(":{line}"))
+        .unwrap_or_default();
+      write!(md, "\n**`{}{line}`**\n\n{}\n", comment.path, comment.body).unwrap();
+    }
+  }
+  md
+}
+
+impl LocalRepo {
+  fn path(&self) -> PathBuf {
+    self.host.repo_path(&self.user, &self.name)
+  }
+
+  fn store_path(&self) -> Option<PathBuf> {
+    let checkouts = self.host.0.checkouts.lock();
+    Some(checkouts.get(&self.key())?.join(STORE_DIR))
+  }
+
+  fn file_url(path: &Path) -> String {
+    Url::from_file_path(path)
+      .map(String::from)
+      .unwrap_or_else(|()| format!("file://{}", path.display()))
+  }
+
+  /// Links to the rendering of an issue or PR, or to the repo if it isn't checked out.
+  fn html_url(&self, dir: &str, number: u64) -> String {
+    match self.store_path() {
+      Some(store) => Self::file_url(&store.join(dir).join(format!("{number}.md"))),
+      None => Self::file_url(&self.path()),
+    }
+  }
+
+  fn key(&self) -> RepoKey {
+    (self.user.clone(), self.name.clone())
+  }
+
+  fn state(&self) -> Result<Arc<Mutex<RepoState>>> {
+    let mut repos = self.host.0.repos.lock();
+    if let Some(state) = repos.get(&self.key()) {
+      return Ok(Arc::clone(state));
+    }
+
+    let path = self
+      .store_path()
+      .ok_or_else(|| anyhow!("Repo not found: {}/{}", self.user, self.name))?
+      .join("state.json");
+    let contents = fs::read_to_string(&path)
+      .with_context(|| format!("Repo not found: {}/{}", self.user, self.name))?;
+    let state: RepoState = serde_json::from_str(&contents)
+      .with_context(|| format!("Failed to parse forge state: {}", path.display()))?;
+    let state = Arc::new(Mutex::new(state));
+    repos.insert(self.key(), Arc::clone(&state));
+    Ok(state)
+  }
+
+  /// Writes `state` to the checkout, along with markdown renderings of its issues and PRs.
+  fn save(&self, state: &RepoState) -> Result<()> {
+    let Some(store) = self.store_path() else {
+      return Ok(());
+    };
+    for dir in ["issues", "pulls"] {
+      fs::create_dir_all(store.join(dir))
+        .with_context(|| format!("Failed to create directory: {}", store.display()))?;
+    }
+    for issue in &state.issues {
+      let path = store.join("issues").join(format!("{}.md", issue.number));
+      fs::write(path, render_issue(issue))?;
+    }
+    for pr in &state.prs {
+      let path = store.join("pulls").join(format!("{}.md", pr.data.number));
+      fs::write(path, render_pr(pr))?;
+    }
+    let path = store.join("state.json");
+    fs::write(&path, serde_json::to_string_pretty(state)?)
+      .with_context(|| format!("Failed to write forge state: {}", path.display()))?;
+    Ok(())
+  }
+
+  fn exists(&self) -> bool {
+    let known = self.host.0.repos.lock().contains_key(&self.key())
+      || (self.store_path()).is_some_and(|store| store.join("state.json").exists());
+    known && self.path().exists()
+  }
+}
+
+/// Merges `head` into `base` in the bare repo at `path`, failing on conflicts like Github
+/// would.
+pub(super) fn merge_branches(path: &Path, base: &str, head: &str, message: &str) -> Result<()> {
+  let tree = git(path, &["merge-tree", "--write-tree", base, head])
+    .with_context(|| format!("Merge conflict between {base} and {head}"))?;
+  let tree = tree.lines().next().unwrap_or_default();
+  let commit = git(
+    path,
+    &["commit-tree", tree, "-p", base, "-p", head, "-m", message],
+  )?;
+  git(
+    path,
+    &["update-ref", &format!("refs/heads/{base}"), &commit],
+  )?;
+  Ok(())
+}
+
+#[async_trait]
+impl Forge for LocalRepo {
+  fn user(&self) -> &str {
+    &self.user
+  }
+
+  fn name(&self) -> &str {
+    &self.name
+  }
+
+  fn remote(&self, _protocol: GitProtocol) -> String {
+    self.path().display().to_string()
+  }
+
+  fn is_local(&self) -> bool {
+    true
+  }
+
+  /// Also moves the repo's issues and PRs into the new checkout.
+  fn clone(&self, path: &Path, protocol: GitProtocol) -> Result<GitRepo> {
+    let dir = path.join(self.name());
+    let repo = GitRepo::clone(&dir, &self.remote(protocol), &[])?;
+    let exclude = dir.join(".git").join("info").join("exclude");
+    fs::create_dir_all(exclude.parent().unwrap())?;
+    let mut file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&exclude)
+      .with_context(|| format!("Failed to open: {}", exclude.display()))?;
+    writeln!(file, "/{STORE_DIR}/")?;
+
+    let state = self.state()?;
+    self.host.0.checkouts.lock().insert(self.key(), dir);
+    self.save(&state.lock())?;
+    Ok(repo)
+  }
+
+  async fn fetch(&self) -> Result<bool> {
+    if !self.exists() {
+      return Ok(false);
+    }
+    let state = self.state()?;
+    let state = state.lock();
+    *self.prs.lock() = Some(state.prs.clone());
+    *self.issues.lock() = Some(state.issues.clone());
+    Ok(true)
+  }
+
+  fn prs(&self) -> MappedMutexGuard<'_, Vec<FullPullRequest>> {
+    MutexGuard::map(self.prs.lock(), |opt| {
+      opt.as_mut().expect("PRs not populated")
+    })
+  }
+
+  fn issues(&self) -> MappedMutexGuard<'_, Vec<Issue>> {
+    MutexGuard::map(self.issues.lock(), |opt| {
+      opt.as_mut().expect("Issues not populated")
+    })
+  }
+
+  fn restore(&self, prs: Vec<FullPullRequest>, issues: Vec<Issue>) {
+    *self.prs.lock() = Some(prs);
+    *self.issues.lock() = Some(issues);
+  }
+
+  async fn list_all(&self) -> Result<Option<(Vec<PullRequest>, Vec<Issue>)>> {
+    if !self.exists() {
+      return Ok(None);
+    }
+    let state = self.state()?;
+    let state = state.lock();
+    let prs = state.prs.iter().rev().map(|pr| pr.data.clone()).collect();
+    let issues = state.issues.iter().rev().cloned().collect();
+    Ok(Some((prs, issues)))
+  }
+
+  async fn labels(&self) -> Result<Vec<Label>> {
+    Ok(self.state()?.lock().labels.clone())
+  }
+
+  async fn create_labels(&self, labels: &[Label]) -> Result<()> {
+    let state = self.state()?;
+    let mut state = state.lock();
+    for label in labels.iter().filter(|label| !label.default) {
+      ensure!(
+        state.labels.iter().all(|other| other.name != label.name),
+        "Label already exists: {}",
+        label.name
+      );
+      state.labels.push(label.clone());
+    }
+    self.save(&state)
+  }
+
+  async fn create_pr(
+    &self,
+    title: &str,
+    head: &str,
+    base: &str,
+    body: &str,
+    labels: &[String],
+  ) -> Result<PullRequest> {
+    git(&self.path(), &["rev-parse", &format!("refs/heads/{head}")])
+      .with_context(|| format!("Head branch does not exist: {head}"))?;
+    let state = self.state()?;
+    let mut state = state.lock();
+    let number = state.next_id();
+    state.resolve_labels(labels);
+    let now = Utc::now();
+    let pr = PullRequest {
+      number,
+      title: title.to_string(),
+      body: Some(body.to_string()),
+      labels: labels.to_vec(),
+      head: head.to_string(),
+      base: base.to_string(),
+      state: IssueState::Open,
+      html_url: self.html_url("pulls", number),
+      created_at: now,
+      updated_at: now,
+      merged_at: None,
+    };
+    state.prs.push(FullPullRequest {
+      data: pr.clone(),
+      comments: Vec::new(),
+    });
+    self.save(&state)?;
+    Ok(pr)
+  }
+
+  async fn copy_pr_comment(
+    &self,
+    pr: u64,
+    comment: &QuestReviewComment,
+    _commit: &str,
+  ) -> Result<()> {
+    let state = self.state()?;
+    let mut state = state.lock();
+    let id = state.next_id();
+    let full_pr = state
+      .prs
+      .iter_mut()
+      .find(|full_pr| full_pr.data.number == pr)
+      .ok_or_else(|| anyhow!("PR not found: {pr}"))?;
+    full_pr.comments.push(ReviewComment {
+      id,
+      path: comment.path.clone(),
+      line: comment.line,
+      body: comment.body.clone(),
+    });
+    self.save(&state)
+  }
+
+  async fn create_issue(&self, title: &str, body: &str, labels: &[String]) -> Result<Issue> {
+    let state = self.state()?;
+    let mut state = state.lock();
+    let number = state.next_id();
+    state.resolve_labels(labels);
+    let now = Utc::now();
+    let issue = Issue {
+      number,
+      title: title.to_string(),
+      body: Some(body.to_string()),
+      labels: labels.to_vec(),
+      state: IssueState::Open,
+      html_url: self.html_url("issues", number),
+      created_at: now,
+      updated_at: now,
+    };
+    state.issues.push(issue.clone());
+    self.save(&state)?;
+    Ok(issue)
+  }
+
+  async fn close_issue(&self, issue: &Issue) -> Result<()> {
+    let state = self.state()?;
+    let mut state = state.lock();
+    let stored = state
+      .issues
+      .iter_mut()
+      .find(|other| other.number == issue.number)
+      .ok_or_else(|| anyhow!("Issue not found: {}", issue.number))?;
+    stored.state = IssueState::Closed;
+    stored.updated_at = Utc::now();
+    self.save(&state)
+  }
+
+  async fn merge_pr(&self, pr: &PullRequest) -> Result<()> {
+    let (base, head) = {
+      let state = self.state()?;
+      let state = state.lock();
+      let stored = state
+        .prs
+        .iter()
+        .find(|other| other.data.number == pr.number)
+        .ok_or_else(|| anyhow!("PR not found: {}", pr.number))?;
+      if stored.data.merged_at.is_some() {
+        bail!("PR already merged: {}", pr.number);
+      }
+      (stored.data.base.clone(), stored.data.head.clone())
+    };
+
+    let message = format!("Merge pull request #{}", pr.number);
+    merge_branches(&self.path(), &base, &head, &message)
+      .with_context(|| format!("Failed to merge PR: {}", pr.number))?;
+
+    let state = self.state()?;
+    let mut state = state.lock();
+    let stored = (state.prs.iter_mut())
+      .find(|other| other.data.number == pr.number)
+      .unwrap();
+    let now = Utc::now();
+    stored.data.state = IssueState::Closed;
+    stored.data.updated_at = now;
+    stored.data.merged_at = Some(now);
+    self.save(&state)
+  }
+
+  async fn delete(&self) -> Result<()> {
+    self.host.0.repos.lock().remove(&self.key());
+    self.host.0.checkouts.lock().remove(&self.key());
+    let path = self.path();
+    fs::remove_dir_all(&path)
+      .with_context(|| format!("Failed to delete repo: {}", path.display()))?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use tempfile::TempDir;
+
+  #[tokio::test]
+  async fn persists_state() -> Result<()> {
+    let root = TempDir::new()?;
+    let host = LocalHost::new(&root.path().join("forge"), LOCAL_USER);
+    let repo = host.create_repo("quest").await?;
+    let dir = root.path().join("learner");
+    fs::create_dir_all(&dir)?;
+    repo.clone(&dir, GitProtocol::Ssh)?;
+    let issue = repo
+      .create_issue("Stage 1", "Do the thing", &["s1".into()])
+      .await?;
+    repo.close_issue(&issue).await?;
+
+    // Everything lives in the learner's checkout, and git doesn't see it.
+    let dir = dir.join("quest");
+    assert!(dir.join(STORE_DIR).join("state.json").exists());
+    assert!(git(&dir, &["status", "--porcelain"])?.is_empty());
+
+    // A fresh host has to read everything back from the checkout.
+    let host = LocalHost::detect(&dir).unwrap();
+    let repo = host.load(LOCAL_USER, "quest").await?;
+    let issue = repo.issue("s1").unwrap().clone();
+    assert_eq!(issue.state, IssueState::Closed);
+    assert_eq!(repo.labels().await?[0].name, "s1");
+
+    let path = Url::parse(&issue.html_url)?.to_file_path().unwrap();
+    assert!(path.starts_with(&dir));
+    let md = fs::read_to_string(path)?;
+    assert!(md.contains("**State:** closed"));
+    assert!(md.contains("Do the thing"));
+
+    assert!(!host.repo(LOCAL_USER, "missing").fetch().await?);
+    Ok(())
+  }
+}
@@ -0,0 +1,171 @@
+// Github's REST responses, for tests that serve them from a mock server. Octocrab's models are
+// `#[non_exhaustive]`, so they can only be built by deserializing JSON, and these helpers fill in
+// every required field that the tests don't care about.
+
+use chrono::DateTime;
+use octocrab::models::{
+  issues::Issue,
+  pulls::{self, PullRequest},
+  IssueState, Label,
+};
+use serde_json::{json, Value};
+
+const TIMESTAMP_EPOCH: i64 = 1_704_067_200; // 2024-01-01T00:00:00Z
+
+/// Returns a timestamp `offset` seconds after a fixed epoch, so that items are created in the
+/// order of their numbers.
+fn timestamp(offset: u64) -> String {
+  DateTime::from_timestamp(TIMESTAMP_EPOCH + offset as i64, 0)
+    .unwrap()
+    .to_rfc3339()
+}
+
+pub fn author(login: &str) -> Value {
+  let url = format!("https://example.com/{login}");
+  json!({
+    "login": login,
+    "id": 1,
+    "node_id": "",
+    "avatar_url": url,
+    "gravatar_id": "",
+    "url": url,
+    "html_url": url,
+    "followers_url": url,
+    "following_url": url,
+    "gists_url": url,
+    "starred_url": url,
+    "subscriptions_url": url,
+    "organizations_url": url,
+    "repos_url": url,
+    "events_url": url,
+    "received_events_url": url,
+    "type": "User",
+    "site_admin": false,
+    "patch_url": null,
+  })
+}
+
+pub fn label(id: u64, name: &str, color: &str, description: Option<&str>) -> Label {
+  serde_json::from_value(json!({
+    "id": id,
+    "node_id": "",
+    "url": format!("https://example.com/labels/{name}"),
+    "name": name,
+    "description": description,
+    "color": color,
+    "default": false,
+  }))
+  .expect("Invalid label model")
+}
+
+pub struct IssueFields<'a> {
+  pub number: u64,
+  pub title: &'a str,
+  pub body: Option<&'a str>,
+  pub labels: &'a [Label],
+  pub state: IssueState,
+  pub author: &'a str,
+  pub html_url: &'a str,
+}
+
+impl IssueFields<'_> {
+  pub fn build(self) -> Issue {
+    let created_at = timestamp(self.number);
+    serde_json::from_value(json!({
+      "id": self.number,
+      "node_id": "",
+      "url": self.html_url,
+      "repository_url": self.html_url,
+      "labels_url": self.html_url,
+      "comments_url": self.html_url,
+      "events_url": self.html_url,
+      "html_url": self.html_url,
+      "number": self.number,
+      "state": self.state,
+      "state_reason": null,
+      "title": self.title,
+      "body": self.body,
+      "user": author(self.author),
+      "labels": self.labels,
+      "assignees": [],
+      "author_association": "NONE",
+      "locked": false,
+      "comments": 0,
+      "created_at": created_at,
+      "updated_at": created_at,
+    }))
+    .expect("Invalid issue model")
+  }
+}
+
+pub struct PullRequestFields<'a> {
+  pub number: u64,
+  pub title: &'a str,
+  pub body: Option<&'a str>,
+  pub labels: &'a [Label],
+  pub head: &'a str,
+  pub head_sha: &'a str,
+  pub base: &'a str,
+  pub state: IssueState,
+  pub merged: bool,
+  pub author: &'a str,
+  pub html_url: &'a str,
+}
+
+impl PullRequestFields<'_> {
+  pub fn build(self) -> PullRequest {
+    let created_at = timestamp(self.number);
+    serde_json::from_value(json!({
+      "url": self.html_url,
+      "id": self.number,
+      "html_url": self.html_url,
+      "number": self.number,
+      "state": self.state,
+      "title": self.title,
+      "user": author(self.author),
+      "body": self.body,
+      "labels": self.labels,
+      "created_at": created_at,
+      "updated_at": created_at,
+      "merged_at": self.merged.then_some(&created_at),
+      "head": { "ref": self.head, "sha": self.head_sha },
+      "base": { "ref": self.base, "sha": "" },
+    }))
+    .expect("Invalid pull request model")
+  }
+}
+
+pub struct CommentFields<'a> {
+  pub id: u64,
+  pub path: &'a str,
+  pub body: &'a str,
+  pub line: Option<u64>,
+  pub commit: &'a str,
+  pub author: &'a str,
+  pub html_url: &'a str,
+}
+
+impl CommentFields<'_> {
+  pub fn build(self) -> pulls::Comment {
+    let created_at = timestamp(self.id);
+    serde_json::from_value(json!({
+      "url": self.html_url,
+      "id": self.id,
+      "node_id": "",
+      "diff_hunk": "",
+      "path": self.path,
+      "commit_id": self.commit,
+      "original_commit_id": self.commit,
+      "user": author(self.author),
+      "body": self.body,
+      "created_at": created_at,
+      "updated_at": created_at,
+      "html_url": self.html_url,
+      "author_association": "NONE",
+      "_links": {},
+      "line": self.line,
+      "original_line": self.line,
+    }))
+    .expect("Invalid review comment model")
+  }
+}
@@ -0,0 +1,94 @@
+use anyhow::{anyhow, Context, Result};
+use http::Uri;
+use octocrab::Octocrab;
+use serde::de::DeserializeOwned;
+use std::sync::Arc;
+
+/// Thin wrapper over an Octocrab instance pointed at a non-Github REST API.
+#[derive(Clone)]
+pub struct RestClient {
+  /// Web URL of the instance, e.g. `https://gitlab.com`.
+  pub url: String,
+  pub api: Arc<Octocrab>,
+  /// Name of the query parameter that sets the page size, e.g. `limit` or `per_page`.
+  page_param: &'static str,
+  page_size: usize,
+}
+
+impl RestClient {
+  pub fn new(
+    url: &str,
+    api_path: &str,
+    token: &str,
+    page_param: &'static str,
+    page_size: usize,
+  ) -> Result<Self> {
+    let url = url.trim_end_matches('/').to_string();
+    let api = Octocrab::builder()
+      .base_uri(format!("{url}{api_path}"))
+      .with_context(|| format!("Invalid URL: {url}"))?
+      .personal_token(token.to_string())
+      .build()
+      .context("Failed to build REST client")?;
+    Ok(RestClient {
+      url,
+      api: Arc::new(api),
+      page_param,
+      page_size,
+    })
+  }
+
+  pub async fn get<T: DeserializeOwned>(&self, route: &str) -> octocrab::Result<T> {
+    self.api.get(route, None::<&()>).await
+  }
+
+  /// Fetches every page of a listing endpoint.
+  pub async fn get_all<T: DeserializeOwned>(&self, route: &str) -> octocrab::Result<Vec<T>> {
+    let sep = if route.contains('?') { '&' } else { '?' };
+    let mut items = Vec::new();
+    for page in 1.. {
+      let page_items: Vec<T> = self
+        .get(&format!(
+          "{route}{sep}page={page}&{}={}",
+          self.page_param, self.page_size
+        ))
+        .await?;
+      let done = page_items.len() < self.page_size;
+      items.extend(page_items);
+      if done {
+        break;
+      }
+    }
+    Ok(items)
+  }
+
+  pub async fn post<T: DeserializeOwned>(
+    &self,
+    route: &str,
+    body: serde_json::Value,
+  ) -> octocrab::Result<T> {
+    self.api.post(route, Some(&body)).await
+  }
+
+  pub async fn put<T: DeserializeOwned>(
+    &self,
+    route: &str,
+    body: serde_json::Value,
+  ) -> octocrab::Result<T> {
+    self.api.put(route, Some(&body)).await
+  }
+
+  /// Sends a DELETE request, ignoring any response body.
+  pub async fn delete(&self, route: &str) -> octocrab::Result<()> {
+    let response = self.api._delete(route, None::<&()>).await?;
+    octocrab::map_github_error(response).await?;
+    Ok(())
+  }
+
+  /// Returns the host name that git remotes on this instance use.
+  pub fn git_host(&self) -> Result<String> {
+    let uri = self.url.parse::<Uri>().context("Invalid forge URL")?;
+    let host = uri.host().ok_or_else(|| anyhow!("Forge URL has no host"))?;
+    Ok(host.to_string())
+  }
+}
@@ -1,18 +1,22 @@
 use std::{
-  collections::HashMap,
+  collections::BTreeMap,
   fs,
   io::Write,
   path::{Path, PathBuf},
-  process::Stdio,
+  process::{Command, Stdio},
+  thread,
+  time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, ensure, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 
 use crate::{
   command::command,
-  github::{GitProtocol, GithubRepo},
-  package::QuestPackage,
+  forge::{Forge, GitProtocol},
+  hooks::{HookSet, HOOKS_DIR},
+  package::{paths, PackageContents, QuestPackage},
   template::QuestTemplate,
+  utils,
 };
 
 pub struct GitRepo {
@@ -20,7 +24,17 @@ pub struct GitRepo {
 }
 
 pub const UPSTREAM: &str = "upstream";
+/// Where the branches of a package's bundle are imported, as if it were a remote.
+pub const PACKAGE: &str = "package";
+/// Where a package's bundle is fetched until its files have been checked.
+const QUARANTINE: &str = "refs/rqst-quarantine";
 pub const INITIAL_TAG: &str = "initial";
+/// How long the `post-checkout` hook may run before it's killed.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+/// The only environment variables passed to hooks that RepoQuest runs itself.
+const HOOK_ENV: &[&str] = &["PATH", "HOME"];
+/// Where the hooks that the learner allowed are copied, relative to the repo.
+const INSTALLED_HOOKS_DIR: &str = ".git/rqst-hooks";
 
 pub enum MergeType {
   Success,
@@ -51,8 +65,14 @@ impl GitRepo {
     }
   }
 
-  pub fn clone(path: &Path, url: &str) -> Result<Self> {
-    let output = command(&format!("git clone {url}"), path.parent().unwrap()).output()?;
+  /// Clones `url` into `path`, persisting each `(key, value)` pair into the new repo's config.
+  pub fn clone(path: &Path, url: &str, config: &[(String, String)]) -> Result<Self> {
+    // The URL can be a local path, so pass it as-is rather than through the shell parser.
+    let mut cmd = command("git clone", path.parent().unwrap());
+    for (key, value) in config {
+      cmd.arg("-c").arg(format!("{key}={value}"));
+    }
+    let output = cmd.arg(url).output()?;
     ensure!(
       output.status.success(),
       "`git clone {url}` failed, stderr:\n{}",
@@ -86,8 +106,9 @@ impl GitRepo {
       .map_err(|stderr| anyhow!("git failed with stderr:\n{stderr}"))
   }
 
-  pub fn setup_upstream(&self, upstream: &GithubRepo) -> Result<()> {
+  pub fn setup_upstream(&self, upstream: &dyn Forge) -> Result<()> {
     let remote = upstream.remote(GitProtocol::Https);
+    let remote = shlex::try_quote(&remote).context("Invalid upstream URL")?;
     git!(self, "remote add {UPSTREAM} {remote}")?;
     self.fetch(UPSTREAM)?;
     Ok(())
@@ -104,6 +125,11 @@ impl GitRepo {
     Ok(status.success().then_some(UPSTREAM))
   }
 
+  pub fn remote_url(&self, remote: &str) -> Result<String> {
+    let url = git_output!(self, "remote get-url {remote}")?;
+    Ok(url.trim().to_string())
+  }
+
   fn apply(&self, patch: &str) -> Result<()> {
     tracing::trace!("Applying patch:\n{patch}");
     let mut child = command("git apply -", &self.path)
@@ -143,10 +169,18 @@ impl GitRepo {
     Ok(merge_type)
   }
 
-  pub fn cherry_pick(&self, base_branch: &str, target_branch: &str) -> Result<MergeType> {
+  /// Cherry-picks the commits between two branches of `remote` onto the current branch. On a
+  /// conflict, resets the branch's contents to `target_branch` instead, and returns `reset`.
+  pub fn cherry_pick(
+    &self,
+    remote: &str,
+    base_branch: &str,
+    target_branch: &str,
+    reset: MergeType,
+  ) -> Result<MergeType> {
     let res = git!(
       self,
-      "cherry-pick {UPSTREAM}/{base_branch}..{UPSTREAM}/{target_branch}"
+      "cherry-pick {remote}/{base_branch}..{remote}/{target_branch}"
     );
 
     Ok(match res {
@@ -156,14 +190,17 @@ impl GitRepo {
 
         git!(self, "cherry-pick --abort").context("Failed to abort cherry-pick")?;
 
-        let upstream_target = format!("{UPSTREAM}/{target_branch}");
-        git!(self, "reset --hard {upstream_target}")?;
+        git!(self, "reset --hard {remote}/{target_branch}")?;
 
         git!(self, "reset --soft main").context("Failed to soft reset to main")?;
 
-        git!(self, "commit -m 'Override with reference solution'")?;
+        let message = match reset {
+          MergeType::StarterReset => "Override with starter code",
+          _ => "Override with reference solution",
+        };
+        git!(self, "commit -m '{message}'")?;
 
-        MergeType::SolutionReset
+        reset
       }
     })
   }
@@ -233,7 +270,85 @@ impl GitRepo {
     Ok(output.stdout)
   }
 
-  pub fn read_initial_files(&self) -> Result<HashMap<PathBuf, String>> {
+  /// Returns a git bundle containing `branches` and their history. The same branches always
+  /// give the same bytes.
+  pub fn bundle(&self, branches: &[String]) -> Result<Vec<u8>> {
+    // With several threads, git can pick different deltas from run to run.
+    let args = format!(
+      "git -c pack.threads=1 bundle create - {}",
+      branches.join(" ")
+    );
+    let output = command(&args, &self.path)
+      .output()
+      .with_context(|| format!("Failed to `{args}`"))?;
+    ensure!(
+      output.status.success(),
+      "git bundle failed with stderr:\n{}",
+      String::from_utf8(output.stderr)?
+    );
+    Ok(output.stdout)
+  }
+
+  /// Imports the branches of `bundle` as `package/<branch>`, unless they already were.
+  ///
+  /// Packages come from arbitrary authors, so nothing is imported if a commit in the bundle
+  /// has a file that could be written outside the repo, or a symlink or submodule.
+  pub fn import_bundle(&self, bundle: &[u8]) -> Result<()> {
+    if self
+      .git(&format!("rev-parse --verify --quiet {PACKAGE}/main"))
+      .is_ok()
+    {
+      return Ok(());
+    }
+    // Git can only fetch from a bundle on disk.
+    let bundle_path = self.path.join(".git").join("rqst-package.bundle");
+    fs::write(&bundle_path, bundle)
+      .with_context(|| format!("Failed to write: {}", bundle_path.display()))?;
+    let quoted_path = shlex::try_quote(&bundle_path.display().to_string())?.into_owned();
+    let fetched = git!(self, "fetch {quoted_path} 'refs/heads/*:{QUARANTINE}/*'");
+    fs::remove_file(&bundle_path)
+      .with_context(|| format!("Failed to remove: {}", bundle_path.display()))?;
+    fetched?;
+
+    let refs = git_output!(self, "for-each-ref --format=%(refname) {QUARANTINE}/")?;
+    let refs = refs.lines().collect::<Vec<_>>();
+    let imported = self.check_bundle_files().and_then(|()| {
+      for refname in &refs {
+        let branch = refname.trim_start_matches(QUARANTINE);
+        git!(self, "update-ref refs/remotes/{PACKAGE}{branch} {refname}")?;
+      }
+      Ok(())
+    });
+    for refname in &refs {
+      git!(self, "update-ref -d {refname}")?;
+    }
+    imported
+  }
+
+  /// Checks the files in every commit of a quarantined bundle, which is what cherry-picking
+  /// from it can write into the learner's repo.
+  fn check_bundle_files(&self) -> Result<()> {
+    let commits = git_output!(self, "rev-list --glob={QUARANTINE}/*")?;
+    for commit in commits.lines() {
+      let entries = git_output!(self, "ls-tree -r -z --full-tree {commit}")?;
+      for entry in entries.split_terminator('\0') {
+        let (info, path) = entry
+          .split_once('\t')
+          .with_context(|| format!("Unexpected ls-tree output: {entry}"))?;
+        paths::normalize(Path::new(path))
+          .with_context(|| format!("Invalid file in package commit {commit}"))?;
+        // Symlinks could point anywhere, and submodules would be fetched from anywhere.
+        let mode = info.split(' ').next().unwrap_or_default();
+        ensure!(
+          !matches!(mode, "120000" | "160000"),
+          "Package commit {commit} contains a symlink or submodule: {path}"
+        );
+      }
+    }
+    Ok(())
+  }
+
+  pub fn read_initial_files(&self) -> Result<BTreeMap<PathBuf, String>> {
     let ls_tree_out = git_output!(self, "ls-tree -r main --name-only")?;
     let files = ls_tree_out.trim().split("\n");
     files
@@ -255,7 +370,42 @@ impl GitRepo {
   }
 
   pub fn write_initial_files(&self, package: &QuestPackage) -> Result<()> {
-    for (rel_path, contents) in &package.initial {
+    match &package.contents {
+      PackageContents::Bundle { bundle } => {
+        self.import_bundle(bundle)?;
+        git!(self, "checkout -B main {PACKAGE}/main")?;
+      }
+      PackageContents::Files { initial, .. } => self.commit_initial_files(initial)?,
+    }
+
+    git!(self, "tag {INITIAL_TAG}")?;
+    git!(self, "push -u origin main")?;
+
+    git!(self, "checkout -b meta")?;
+
+    let config_str =
+      toml::to_string_pretty(&package.config).context("Failed to parse package config")?;
+    let toml_path = self.path.join("rqst.toml");
+    fs::write(&toml_path, config_str)
+      .with_context(|| format!("Failed to write TOML to: {}", toml_path.display()))?;
+
+    let pkg_path = self.path.join("package.json.gz");
+    package
+      .save(&pkg_path)
+      .with_context(|| format!("Failed to write package to: {}", pkg_path.display()))?;
+
+    git!(self, "add .")?;
+    git!(self, "commit -m 'Add meta'")?;
+    git!(self, "push -u origin meta")?;
+    git!(self, "checkout main")?;
+
+    Ok(())
+  }
+
+  /// Commits the files of a package without a bundle, which only stores text.
+  fn commit_initial_files(&self, initial: &BTreeMap<PathBuf, String>) -> Result<()> {
+    for (rel_path, contents) in initial {
+      paths::ensure_no_symlinks(&self.path, rel_path)?;
       let abs_path = self.path.join(rel_path);
       if let Some(dir) = abs_path.parent() {
         fs::create_dir_all(dir)
@@ -265,12 +415,11 @@ impl GitRepo {
         .with_context(|| format!("Failed to write: {}", abs_path.display()))?;
     }
 
-    // HACK:Eventually we should either directly package a git repo in the file
-    // or include the permissions
+    // These packages don't record file modes, so assume that hooks are meant to be executable.
     #[cfg(unix)]
     {
       use std::os::unix::fs::PermissionsExt;
-      let hooks_dir = self.path.join(".githooks");
+      let hooks_dir = self.path.join(HOOKS_DIR);
       if hooks_dir.exists() {
         let hooks = fs::read_dir(&hooks_dir)
           .with_context(|| format!("Failed to read hooks directory: {}", hooks_dir.display()))?;
@@ -290,44 +439,203 @@ impl GitRepo {
 
     git!(self, "add .")?;
     git!(self, "commit -m 'Initial commit'")?;
-    git!(self, "tag {INITIAL_TAG}")?;
-    git!(self, "push -u origin main")?;
+    Ok(())
+  }
 
-    git!(self, "checkout -b meta")?;
+  pub fn config_get(&self, key: &str) -> Result<Option<String>> {
+    // `git config --get` fails when the key is unset.
+    let value = self.git_core(&format!("config --local --get {key}"))?;
+    Ok(value.ok().map(|value| value.trim().to_string()))
+  }
 
-    let config_str =
-      toml::to_string_pretty(&package.config).context("Failed to parse package config")?;
-    let toml_path = self.path.join("rqst.toml");
-    fs::write(&toml_path, config_str)
-      .with_context(|| format!("Failed to write TOML to: {}", toml_path.display()))?;
+  pub fn config_set(&self, key: &str, value: &str) -> Result<()> {
+    let value = shlex::try_quote(value)?.into_owned();
+    git!(self, "config --local {key} {value}")
+  }
 
-    let pkg_path = self.path.join("package.json.gz");
-    package
-      .save(&pkg_path)
-      .with_context(|| format!("Failed to write package to: {}", pkg_path.display()))?;
+  /// Runs `hooks` from a copy in `.git`, so that later changes to the quest's hooks don't run
+  /// until the learner allows them. The first time a set of hooks is installed, its
+  /// `post-checkout` hook is run to set up the quest.
+  pub fn install_hooks(&self, hooks: &HookSet) -> Result<()> {
+    let installed = self.path.join(INSTALLED_HOOKS_DIR);
+    let marker = installed.join(".sha256");
+    if fs::read_to_string(&marker).is_ok_and(|sha256| sha256 == hooks.sha256) {
+      return Ok(());
+    }
 
-    git!(self, "add .")?;
-    git!(self, "commit -m 'Add meta'")?;
-    git!(self, "push -u origin meta")?;
-    git!(self, "checkout main")?;
+    if installed.exists() {
+      fs::remove_dir_all(&installed)
+        .with_context(|| format!("Failed to remove: {}", installed.display()))?;
+    }
+    fs::create_dir_all(&installed)
+      .with_context(|| format!("Failed to create directory: {}", installed.display()))?;
+    for hook in &hooks.hooks {
+      let src = self.path.join(HOOKS_DIR).join(&hook.name);
+      let contents =
+        fs::read(&src).with_context(|| format!("Failed to read: {}", src.display()))?;
+      ensure!(
+        utils::sha256(&contents) == hook.sha256,
+        "Hook `{}` changed after it was allowed",
+        hook.name
+      );
+      let dst = installed.join(&hook.name);
+      fs::write(&dst, contents).with_context(|| format!("Failed to write: {}", dst.display()))?;
+      #[cfg(unix)]
+      {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&dst, fs::Permissions::from_mode(0o755))
+          .with_context(|| format!("Failed to set hook permissions: {}", dst.display()))?;
+      }
+    }
+    git!(self, "config --local core.hooksPath {INSTALLED_HOOKS_DIR}")?;
+    // Written before running `post-checkout` so that a failing hook isn't rerun on every update.
+    fs::write(&marker, &hooks.sha256)
+      .with_context(|| format!("Failed to write: {}", marker.display()))?;
+
+    if hooks.hooks.iter().any(|hook| hook.name == "post-checkout") {
+      run_hook(&installed.join("post-checkout"), &self.path, HOOK_TIMEOUT)?;
+    }
 
     Ok(())
   }
 
-  pub fn install_hooks(&self) -> Result<()> {
-    let hooks_dir = self.path.join(".githooks");
-    if hooks_dir.exists() {
-      let post_checkout = hooks_dir.join("post-checkout");
-      if post_checkout.exists() {
-        let status = command(&post_checkout.display().to_string(), &self.path)
-          .status()
-          .context("post-checkout hook failed")?;
-        ensure!(status.success(), "post-checkout hook failed");
-      }
+  /// Makes sure that none of the quest's hooks run.
+  pub fn uninstall_hooks(&self) -> Result<()> {
+    if self.config_get("core.hooksPath")?.is_some() {
+      git!(self, "config --local --unset core.hooksPath")?;
+    }
+    let installed = self.path.join(INSTALLED_HOOKS_DIR);
+    if installed.exists() {
+      fs::remove_dir_all(&installed)
+        .with_context(|| format!("Failed to remove: {}", installed.display()))?;
+    }
+    Ok(())
+  }
+}
 
-      git!(self, "config --local core.hooksPath .githooks")?;
+/// Runs `hook` in `dir` without the rest of RepoQuest's environment, e.g. tokens, and kills it
+/// if it runs longer than `timeout`.
+fn run_hook(hook: &Path, dir: &Path, timeout: Duration) -> Result<()> {
+  let name = hook.file_name().unwrap_or_default().to_string_lossy();
+  let mut cmd = Command::new(hook);
+  cmd.current_dir(dir).env_clear().stdin(Stdio::null());
+  for key in HOOK_ENV {
+    if let Some(value) = std::env::var_os(key) {
+      cmd.env(key, value);
     }
+  }
+  let mut child = cmd
+    .spawn()
+    .with_context(|| format!("Failed to run {name} hook"))?;
+
+  let deadline = Instant::now() + timeout;
+  let status = loop {
+    if let Some(status) = child
+      .try_wait()
+      .with_context(|| format!("{name} hook failed"))?
+    {
+      break status;
+    }
+    if Instant::now() >= deadline {
+      let _ = child.kill();
+      let _ = child.wait();
+      bail!("{name} hook timed out after {timeout:?}");
+    }
+    thread::sleep(Duration::from_millis(50));
+  };
+  ensure!(status.success(), "{name} hook failed");
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use tempfile::TempDir;
+
+  /// Bundles the `main` branch of a new repo with a single commit, made by `setup`.
+  fn make_bundle(setup: impl FnOnce(&Path) -> Result<()>) -> Result<Vec<u8>> {
+    let dir = TempDir::new()?;
+    let repo = GitRepo::new(dir.path());
+    git!(repo, "init -b main")?;
+    setup(dir.path())?;
+    git!(repo, "add -A")?;
+    git!(
+      repo,
+      "-c user.name=author -c user.email=author@example.com commit -m files"
+    )?;
+    repo.bundle(&["main".into()])
+  }
+
+  fn learner_repo() -> Result<(TempDir, GitRepo)> {
+    let dir = TempDir::new()?;
+    let repo = GitRepo::new(dir.path());
+    git!(repo, "init -b main")?;
+    Ok((dir, repo))
+  }
+
+  #[test]
+  fn import_bundle() -> Result<()> {
+    let bundle = make_bundle(|dir| Ok(fs::write(dir.join("README.md"), "# Quest\n")?))?;
+    let (_dir, repo) = learner_repo()?;
+    repo.import_bundle(&bundle)?;
+    assert_eq!(
+      repo.read_file(&format!("{PACKAGE}/main"), "README.md")?,
+      "# Quest\n"
+    );
+    Ok(())
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn refuse_symlink_bundle() -> Result<()> {
+    let bundle = make_bundle(|dir| {
+      fs::write(dir.join("README.md"), "# Quest\n")?;
+      std::os::unix::fs::symlink("/etc", dir.join("etc"))?;
+      Ok(())
+    })?;
+    let (_dir, repo) = learner_repo()?;
+    let err = repo.import_bundle(&bundle).unwrap_err();
+    assert!(format!("{err:#}").contains("symlink"), "{err:#}");
+
+    // Nothing from the bundle is left behind to check out.
+    assert!(git!(repo, "rev-parse --verify --quiet {PACKAGE}/main").is_err());
+    assert!(git_output!(repo, "for-each-ref {QUARANTINE}/")?.is_empty());
+    Ok(())
+  }
+
+  #[cfg(unix)]
+  fn write_hook(dir: &Path, contents: &str) -> Result<PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+    let hook = dir.join("post-checkout");
+    fs::write(&hook, contents)?;
+    fs::set_permissions(&hook, fs::Permissions::from_mode(0o755))?;
+    Ok(hook)
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn hook_environment() -> Result<()> {
+    let dir = TempDir::new()?;
+    let dir = dir.path().join("quest with spaces");
+    fs::create_dir(&dir)?;
+    let hook = write_hook(&dir, "#!/bin/sh\nenv > env.txt\n")?;
+    run_hook(&hook, &dir, HOOK_TIMEOUT)?;
+
+    // Cargo sets this for tests, but hooks shouldn't see anything of RepoQuest's environment.
+    let env = fs::read_to_string(dir.join("env.txt"))?;
+    assert!(!env.contains("CARGO_MANIFEST_DIR="), "{env}");
+    Ok(())
+  }
 
+  #[cfg(unix)]
+  #[test]
+  fn hook_timeout() -> Result<()> {
+    let dir = TempDir::new()?;
+    let hook = write_hook(dir.path(), "#!/bin/sh\nsleep 10\n")?;
+    let start = Instant::now();
+    let err = run_hook(&hook, dir.path(), Duration::from_millis(200)).unwrap_err();
+    assert!(err.to_string().contains("timed out"), "{err:#}");
+    assert!(start.elapsed() < Duration::from_secs(5));
     Ok(())
   }
 }
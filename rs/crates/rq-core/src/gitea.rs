@@ -0,0 +1,682 @@
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures_util::future::try_join_all;
+use parking_lot::{MappedMutexGuard, Mutex, MutexGuard};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use specta::Type;
+use std::{fs, path::Path};
+use tokio::try_join;
+
+use crate::{
+  command::command,
+  forge::{
+    rest::RestClient, Forge, ForgeHost, FullPullRequest, GitProtocol, Issue, IssueState, Label,
+    PullRequest, QuestReviewComment, ReviewComment,
+  },
+  github::is_not_found,
+};
+
+/// Connection settings for a Gitea or Forgejo instance, read from `~/.rqst-gitea.toml`.
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct GiteaConfig {
+  /// Base URL of the instance, e.g. `https://codeberg.org`.
+  pub url: String,
+  pub token: String,
+}
+
+pub fn get_gitea_config() -> Result<Option<GiteaConfig>> {
+  let Some(home) = home::home_dir() else {
+    return Ok(None);
+  };
+  let path = home.join(".rqst-gitea.toml");
+  if !path.exists() {
+    return Ok(None);
+  }
+  let contents =
+    fs::read_to_string(&path).with_context(|| format!("Failed to read: {}", path.display()))?;
+  let config = toml::from_str(&contents)
+    .with_context(|| format!("Failed to parse Gitea config: {}", path.display()))?;
+  Ok(Some(config))
+}
+
+// Gitea caps page sizes at 50 by default.
+const PAGE_LIMIT: usize = 50;
+
+#[derive(Deserialize)]
+struct GiteaUser {
+  login: String,
+}
+
+#[derive(Deserialize)]
+struct GiteaLabel {
+  id: u64,
+  name: String,
+  color: String,
+  description: Option<String>,
+}
+
+impl GiteaLabel {
+  fn to_label(&self) -> Label {
+    Label {
+      name: self.name.clone(),
+      color: self.color.trim_start_matches('#').to_string(),
+      description: self.description.clone(),
+      default: false,
+    }
+  }
+}
+
+fn label_names(labels: &[GiteaLabel]) -> Vec<String> {
+  labels.iter().map(|label| label.name.clone()).collect()
+}
+
+#[derive(Deserialize)]
+struct GiteaBranch {
+  #[serde(rename = "ref")]
+  ref_field: String,
+}
+
+#[derive(Deserialize)]
+struct GiteaPullRequest {
+  number: u64,
+  title: String,
+  body: Option<String>,
+  labels: Vec<GiteaLabel>,
+  head: GiteaBranch,
+  base: GiteaBranch,
+  state: IssueState,
+  html_url: String,
+  created_at: DateTime<Utc>,
+  updated_at: DateTime<Utc>,
+  merged_at: Option<DateTime<Utc>>,
+}
+
+impl GiteaPullRequest {
+  fn to_pull_request(&self) -> PullRequest {
+    PullRequest {
+      number: self.number,
+      title: self.title.clone(),
+      body: self.body.clone(),
+      labels: label_names(&self.labels),
+      head: self.head.ref_field.clone(),
+      base: self.base.ref_field.clone(),
+      state: self.state,
+      html_url: self.html_url.clone(),
+      created_at: self.created_at,
+      updated_at: self.updated_at,
+      merged_at: self.merged_at,
+    }
+  }
+}
+
+#[derive(Deserialize)]
+struct GiteaIssue {
+  number: u64,
+  title: String,
+  body: Option<String>,
+  labels: Vec<GiteaLabel>,
+  state: IssueState,
+  html_url: String,
+  created_at: DateTime<Utc>,
+  updated_at: DateTime<Utc>,
+}
+
+impl GiteaIssue {
+  fn to_issue(&self) -> Issue {
+    Issue {
+      number: self.number,
+      title: self.title.clone(),
+      body: self.body.clone(),
+      labels: label_names(&self.labels),
+      state: self.state,
+      html_url: self.html_url.clone(),
+      created_at: self.created_at,
+      updated_at: self.updated_at,
+    }
+  }
+}
+
+#[derive(Deserialize)]
+struct GiteaReview {
+  id: u64,
+}
+
+#[derive(Deserialize)]
+struct GiteaReviewComment {
+  id: u64,
+  body: String,
+  path: String,
+  position: Option<u64>,
+}
+
+impl GiteaReviewComment {
+  fn to_comment(&self) -> ReviewComment {
+    ReviewComment {
+      id: self.id,
+      path: self.path.clone(),
+      line: self.position,
+      body: self.body.clone(),
+    }
+  }
+}
+
+/// An account on a Gitea or Forgejo instance.
+pub struct GiteaHost {
+  client: RestClient,
+}
+
+impl GiteaHost {
+  pub fn new(config: &GiteaConfig) -> Result<Self> {
+    let client = RestClient::new(&config.url, "/api/v1", &config.token, "limit", PAGE_LIMIT)
+      .context("Failed to build Gitea connector")?;
+    Ok(GiteaHost { client })
+  }
+
+  fn handle(&self, user: &str, name: &str) -> GiteaRepo {
+    GiteaRepo {
+      user: user.to_string(),
+      name: name.to_string(),
+      client: self.client.clone(),
+      prs: Mutex::new(None),
+      issues: Mutex::new(None),
+    }
+  }
+}
+
+#[async_trait]
+impl ForgeHost for GiteaHost {
+  async fn current_user(&self) -> Result<String> {
+    let user: GiteaUser = self
+      .client
+      .get("/user")
+      .await
+      .context("Failed to query Gitea for current user")?;
+    Ok(user.login)
+  }
+
+  fn repo(&self, user: &str, name: &str) -> Box<dyn Forge> {
+    Box::new(self.handle(user, name))
+  }
+
+  async fn create_repo(&self, name: &str) -> Result<Box<dyn Forge>> {
+    let user = self.current_user().await?;
+    let _repo: serde_json::Value = self
+      .client
+      .post(
+        "/user/repos",
+        json!({
+          "name": name,
+          "private": true,
+          "default_branch": "main",
+        }),
+      )
+      .await
+      .context("Failed to create repo")?;
+    Ok(Box::new(self.handle(&user, name)))
+  }
+
+  async fn generate_repo(&self, template: &dyn Forge) -> Result<Box<dyn Forge>> {
+    // The template must be marked as a template repository in its Gitea settings.
+    let user = self.current_user().await?;
+    let route = format!("/repos/{}/{}/generate", template.user(), template.name());
+    let _repo: serde_json::Value = self
+      .client
+      .post(
+        &route,
+        json!({
+          "owner": user,
+          "name": template.name(),
+          "git_content": true,
+        }),
+      )
+      .await
+      .with_context(|| {
+        format!(
+          "Failed to generate repo from template {}/{}",
+          template.user(),
+          template.name()
+        )
+      })?;
+    Ok(Box::new(self.handle(&user, template.name())))
+  }
+
+  fn check_ssh(&self) -> Result<()> {
+    let host = self.client.git_host()?;
+    let output = command(&format!("ssh -T git@{host}"), Path::new("/")).output()?;
+    // Gitea closes the connection with status 0 or 1 depending on the version.
+    match output.status.code() {
+      Some(0 | 1) => Ok(()),
+      _ => {
+        let stderr = String::from_utf8(output.stderr)?;
+        bail!("Failed to establish a secure connection to {host} with error:\n{stderr}")
+      }
+    }
+  }
+}
+
+pub struct GiteaRepo {
+  user: String,
+  name: String,
+  client: RestClient,
+  prs: Mutex<Option<Vec<FullPullRequest>>>,
+  issues: Mutex<Option<Vec<Issue>>>,
+}
+
+impl GiteaRepo {
+  fn route(&self, path: &str) -> String {
+    format!("/repos/{}/{}{path}", self.user, self.name)
+  }
+
+  async fn list_prs(&self) -> octocrab::Result<Vec<GiteaPullRequest>> {
+    self.client.get_all(&self.route("/pulls?state=all")).await
+  }
+
+  async fn list_issues(&self) -> octocrab::Result<Vec<GiteaIssue>> {
+    self
+      .client
+      .get_all(&self.route("/issues?state=all&type=issues"))
+      .await
+  }
+
+  async fn list_pr_comments(&self, pr: u64) -> Result<Vec<ReviewComment>> {
+    let reviews: Vec<GiteaReview> = self
+      .client
+      .get_all(&self.route(&format!("/pulls/{pr}/reviews")))
+      .await?;
+    let comments = try_join_all(reviews.iter().map(|review| {
+      let route = self.route(&format!("/pulls/{pr}/reviews/{}/comments", review.id));
+      async move { self.client.get::<Vec<GiteaReviewComment>>(&route).await }
+    }))
+    .await?;
+    Ok(
+      comments
+        .iter()
+        .flatten()
+        .map(GiteaReviewComment::to_comment)
+        .collect(),
+    )
+  }
+
+  /// Gitea refers to labels by ID, so we look them up by name.
+  async fn label_ids(&self, names: &[String]) -> Result<Vec<u64>> {
+    let labels: Vec<GiteaLabel> = self.client.get_all(&self.route("/labels")).await?;
+    names
+      .iter()
+      .map(|name| {
+        labels
+          .iter()
+          .find(|label| &label.name == name)
+          .map(|label| label.id)
+          .ok_or_else(|| anyhow!("Missing label: {name}"))
+      })
+      .collect()
+  }
+}
+
+#[async_trait]
+impl Forge for GiteaRepo {
+  fn user(&self) -> &str {
+    &self.user
+  }
+
+  fn name(&self) -> &str {
+    &self.name
+  }
+
+  fn remote(&self, protocol: GitProtocol) -> String {
+    match protocol {
+      GitProtocol::Https => format!("{}/{}/{}.git", self.client.url, self.user, self.name),
+      GitProtocol::Ssh => {
+        let host = self.client.git_host().unwrap_or_default();
+        format!("git@{host}:{}/{}.git", self.user, self.name)
+      }
+    }
+  }
+
+  async fn fetch(&self) -> Result<bool> {
+    let (prs, issues) = match try_join!(self.list_prs(), self.list_issues()) {
+      Ok(lists) => lists,
+      Err(e) if is_not_found(&e) => return Ok(false),
+      Err(e) => return Err(e.into()),
+    };
+
+    let full_prs = try_join_all(prs.iter().map(|pr| async move {
+      let comments = self
+        .list_pr_comments(pr.number)
+        .await
+        .with_context(|| format!("Failed to fetch comments for PR {}", pr.number))?;
+      Ok::<_, anyhow::Error>(FullPullRequest {
+        data: pr.to_pull_request(),
+        comments,
+      })
+    }))
+    .await?;
+
+    *self.prs.lock() = Some(full_prs);
+    *self.issues.lock() = Some(issues.iter().map(GiteaIssue::to_issue).collect());
+
+    Ok(true)
+  }
+
+  fn prs(&self) -> MappedMutexGuard<'_, Vec<FullPullRequest>> {
+    MutexGuard::map(self.prs.lock(), |opt| {
+      opt.as_mut().expect("PRs not populated")
+    })
+  }
+
+  fn issues(&self) -> MappedMutexGuard<'_, Vec<Issue>> {
+    MutexGuard::map(self.issues.lock(), |opt| {
+      opt.as_mut().expect("Issues not populated")
+    })
+  }
+
+  fn restore(&self, prs: Vec<FullPullRequest>, issues: Vec<Issue>) {
+    *self.prs.lock() = Some(prs);
+    *self.issues.lock() = Some(issues);
+  }
+
+  async fn list_all(&self) -> Result<Option<(Vec<PullRequest>, Vec<Issue>)>> {
+    let (mut prs, mut issues) = match try_join!(self.list_prs(), self.list_issues()) {
+      Ok(lists) => lists,
+      Err(e) if is_not_found(&e) => return Ok(None),
+      Err(e) => return Err(e.into()),
+    };
+    prs.sort_by_key(|pr| std::cmp::Reverse(pr.number));
+    issues.sort_by_key(|issue| std::cmp::Reverse(issue.number));
+    let prs = prs.iter().map(GiteaPullRequest::to_pull_request).collect();
+    let issues = issues.iter().map(GiteaIssue::to_issue).collect();
+    Ok(Some((prs, issues)))
+  }
+
+  async fn labels(&self) -> Result<Vec<Label>> {
+    let labels: Vec<GiteaLabel> = self
+      .client
+      .get_all(&self.route("/labels"))
+      .await
+      .context("Failed to fetch labels")?;
+    Ok(labels.iter().map(GiteaLabel::to_label).collect())
+  }
+
+  async fn create_labels(&self, labels: &[Label]) -> Result<()> {
+    let route = self.route("/labels");
+    try_join_all(labels.iter().filter(|label| !label.default).map(|label| {
+      self.client.post::<serde_json::Value>(
+        &route,
+        json!({
+          "name": label.name,
+          "color": format!("#{}", label.color),
+          "description": label.description.as_deref().unwrap_or(""),
+        }),
+      )
+    }))
+    .await
+    .context("Failed to create labels")?;
+    Ok(())
+  }
+
+  async fn create_pr(
+    &self,
+    title: &str,
+    head: &str,
+    base: &str,
+    body: &str,
+    labels: &[String],
+  ) -> Result<PullRequest> {
+    let labels = self.label_ids(labels).await?;
+    let pr: GiteaPullRequest = self
+      .client
+      .post(
+        &self.route("/pulls"),
+        json!({
+          "title": title,
+          "head": head,
+          "base": base,
+          "body": body,
+          "labels": labels,
+        }),
+      )
+      .await
+      .context("Failed to create PR")?;
+    Ok(pr.to_pull_request())
+  }
+
+  async fn copy_pr_comment(
+    &self,
+    pr: u64,
+    comment: &QuestReviewComment,
+    commit: &str,
+  ) -> Result<()> {
+    // Gitea only supports line comments as part of a review.
+    let comment_json = json!({
+      "body": "",
+      "commit_id": commit,
+      "event": "COMMENT",
+      "comments": [{
+        "path": comment.path,
+        "body": comment.body,
+        "new_position": comment.line,
+      }],
+    });
+    let _review: serde_json::Value = self
+      .client
+      .post(
+        &self.route(&format!("/pulls/{pr}/reviews")),
+        comment_json.clone(),
+      )
+      .await
+      .with_context(|| format!("Failed to copy PR comment: {comment_json:#?}"))?;
+    Ok(())
+  }
+
+  async fn create_issue(&self, title: &str, body: &str, labels: &[String]) -> Result<Issue> {
+    let labels = self.label_ids(labels).await?;
+    let issue: GiteaIssue = self
+      .client
+      .post(
+        &self.route("/issues"),
+        json!({
+          "title": title,
+          "body": body,
+          "labels": labels,
+        }),
+      )
+      .await?;
+    Ok(issue.to_issue())
+  }
+
+  async fn close_issue(&self, issue: &Issue) -> Result<()> {
+    let _issue: serde_json::Value = self
+      .client
+      .api
+      .patch(
+        self.route(&format!("/issues/{}", issue.number)),
+        Some(&json!({ "state": "closed" })),
+      )
+      .await
+      .with_context(|| format!("Failed to close issue: {}", issue.number))?;
+    Ok(())
+  }
+
+  async fn merge_pr(&self, pr: &PullRequest) -> Result<()> {
+    let route = self.route(&format!("/pulls/{}/merge", pr.number));
+    let response = self
+      .client
+      .api
+      ._post(route, Some(&json!({ "Do": "merge" })))
+      .await;
+    octocrab::map_github_error(response?)
+      .await
+      .with_context(|| format!("Failed to merge PR: {}", pr.number))?;
+    Ok(())
+  }
+
+  async fn delete(&self) -> Result<()> {
+    self
+      .client
+      .delete(&self.route(""))
+      .await
+      .context("Failed to delete repo")?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use wiremock::{
+    matchers::{body_partial_json, method, path, query_param},
+    Mock, MockServer, ResponseTemplate,
+  };
+
+  fn user() -> serde_json::Value {
+    json!({ "login": "learner" })
+  }
+
+  fn label(id: u64, name: &str) -> serde_json::Value {
+    json!({ "id": id, "name": name, "color": "e11d21", "description": "" })
+  }
+
+  fn issue(number: u64, label_name: &str) -> serde_json::Value {
+    json!({
+      "number": number,
+      "title": format!("Issue {number}"),
+      "body": "Do the thing",
+      "labels": [label(1, label_name)],
+      "state": "open",
+      "html_url": format!("https://gitea.test/learner/quest/issues/{number}"),
+      "user": user(),
+      "created_at": "2024-05-01T12:00:00Z",
+      "updated_at": "2024-05-02T12:00:00Z",
+    })
+  }
+
+  async fn mock_get(server: &MockServer, route: &str, body: serde_json::Value) {
+    Mock::given(method("GET"))
+      .and(path(format!("/api/v1/repos/learner/quest{route}")))
+      .respond_with(ResponseTemplate::new(200).set_body_json(body))
+      .mount(server)
+      .await;
+  }
+
+  fn host(server: &MockServer) -> GiteaHost {
+    GiteaHost::new(&GiteaConfig {
+      url: server.uri(),
+      token: "token".into(),
+    })
+    .unwrap()
+  }
+
+  #[tokio::test]
+  async fn fetch() -> Result<()> {
+    let server = MockServer::start().await;
+    mock_get(
+      &server,
+      "/pulls",
+      json!([{
+        "number": 1,
+        "title": "Starter code",
+        "body": "Here is some code",
+        "labels": [label(1, "s1")],
+        "head": { "ref": "s1-a", "sha": "abc123" },
+        "base": { "ref": "main", "sha": "def456" },
+        "state": "closed",
+        "merged": true,
+        "html_url": "https://gitea.test/learner/quest/pulls/1",
+        "user": user(),
+        "created_at": "2024-05-01T12:00:00Z",
+        "updated_at": "2024-05-02T12:00:00Z",
+        "merged_at": "2024-05-02T12:00:00Z",
+      }]),
+    )
+    .await;
+    mock_get(&server, "/pulls/1/reviews", json!([{ "id": 7 }])).await;
+    mock_get(
+      &server,
+      "/pulls/1/reviews/7/comments",
+      json!([{
+        "id": 9,
+        "body": "Look here",
+        "path": "src/lib.rs",
+        "position": 4,
+        "commit_id": "abc123",
+        "html_url": "https://gitea.test/learner/quest/pulls/1#issuecomment-9",
+        "user": user(),
+      }]),
+    )
+    .await;
+
+    // The second page of issues is only fetched because the first page is full.
+    let first_page = (1..=PAGE_LIMIT as u64)
+      .map(|n| issue(n + 1, "s1"))
+      .collect::<Vec<_>>();
+    Mock::given(method("GET"))
+      .and(path("/api/v1/repos/learner/quest/issues"))
+      .and(query_param("page", "1"))
+      .respond_with(ResponseTemplate::new(200).set_body_json(first_page))
+      .mount(&server)
+      .await;
+    Mock::given(method("GET"))
+      .and(path("/api/v1/repos/learner/quest/issues"))
+      .and(query_param("page", "2"))
+      .respond_with(ResponseTemplate::new(200).set_body_json(json!([issue(100, "s2")])))
+      .mount(&server)
+      .await;
+
+    let repo = host(&server).load("learner", "quest").await?;
+
+    let prs = repo.prs();
+    assert_eq!(prs.len(), 1);
+    assert_eq!(prs[0].data.head, "s1-a");
+    assert_eq!(prs[0].data.merged_at, Some("2024-05-02T12:00:00Z".parse()?));
+    assert_eq!(prs[0].comments.len(), 1);
+    assert_eq!(prs[0].comments[0].line, Some(4));
+    drop(prs);
+
+    assert_eq!(repo.issues().len(), PAGE_LIMIT + 1);
+    assert_eq!(repo.issue("s2").unwrap().number, 100);
+
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn fetch_missing_repo() -> Result<()> {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+      .respond_with(ResponseTemplate::new(404).set_body_json(json!({
+        "message": "The target couldn't be found.",
+        "url": "https://gitea.test/api/swagger",
+      })))
+      .mount(&server)
+      .await;
+
+    let repo = host(&server).repo("learner", "quest");
+    assert!(!repo.fetch().await?);
+    assert!(repo.list_all().await?.is_none());
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn create_issue() -> Result<()> {
+    let server = MockServer::start().await;
+    mock_get(&server, "/labels", json!([label(1, "s1"), label(2, "s2")])).await;
+    Mock::given(method("POST"))
+      .and(path("/api/v1/repos/learner/quest/issues"))
+      .and(body_partial_json(
+        json!({ "title": "Issue 3", "labels": [2] }),
+      ))
+      .respond_with(ResponseTemplate::new(201).set_body_json(issue(3, "s2")))
+      .expect(1)
+      .mount(&server)
+      .await;
+
+    let repo = host(&server).repo("learner", "quest");
+    let issue = repo
+      .create_issue("Issue 3", "Do the thing", &["s2".into()])
+      .await?;
+    assert_eq!(issue.number, 3);
+    assert_eq!(issue.labels, ["s2"]);
+    Ok(())
+  }
+}
@@ -1,79 +1,110 @@
 use anyhow::{bail, ensure, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use futures_util::future::try_join_all;
 use http::StatusCode;
+use hyper_util::{client::legacy::Client, rt::TokioExecutor};
 use octocrab::{
   issues::IssueHandler,
-  models::{
-    issues::Issue,
-    pulls::{self, PullRequest},
-    repos::Branch,
-    IssueState, Label,
-  },
+  models::{self, pulls, repos::Branch},
+  params::{issues, pulls as pull_params, Direction},
   pulls::PullRequestHandler,
   repos::RepoHandler,
-  GitHubError, Octocrab,
+  AuthState, GitHubError, Octocrab, OctocrabBuilder,
 };
-use parking_lot::{MappedMutexGuard, Mutex, MutexGuard};
-use regex::Regex;
+use parking_lot::{MappedMutexGuard, Mutex, MutexGuard, RwLock};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use specta::Type;
-use std::{env, fs, path::Path, sync::Arc, time::Duration};
+use std::{cmp::Reverse, env, fmt, fs, path::Path, sync::Arc, time::Duration};
 use tokio::{time::timeout, try_join};
-use tracing::warn;
+use tower::Layer;
+use tower_http::follow_redirect::FollowRedirectLayer;
+use url::Url;
 
 use crate::{
   command::command,
-  git::{GitRepo, MergeType},
-  package::QuestPackage,
-  utils,
+  forge::{
+    Forge, ForgeHost, FullPullRequest, GitProtocol, Issue, IssueState, Label, PullRequest,
+    QuestReviewComment, RateLimit, ReviewComment,
+  },
 };
 
-#[derive(Clone, Serialize, Deserialize)]
-pub struct FullPullRequest {
-  pub data: PullRequest,
-  pub comments: Vec<pulls::Comment>,
-}
+pub mod cassette;
+pub mod etag_cache;
+pub mod graphql;
+pub mod oauth;
+pub mod rate_limit;
+
+use cassette::Cassette;
+use etag_cache::{EtagCacheLayer, ResponseCache};
+use graphql::QueriedPr;
+use oauth::{DeviceLogin, OAuthClient, OAuthToken, TokenRefreshLayer, TokenRefresher};
+use rate_limit::{RateLimitLayer, RateLimiter};
+
+// The largest page size that Github allows.
+const PAGE_SIZE: u8 = 100;
 
 pub struct GithubRepo {
   user: String,
   name: String,
   gh: Arc<Octocrab>,
+  server: GithubServer,
   prs: Mutex<Option<Vec<FullPullRequest>>>,
   issues: Mutex<Option<Vec<Issue>>>,
+  /// When the most recently updated PR or issue in the cache was updated.
+  synced_at: Mutex<Option<DateTime<Utc>>>,
 }
 
-#[derive(Debug)]
-pub enum PullSelector {
-  Branch(String),
-  Label(String),
+/// The Github instance that quests are hosted on: either github.com or a Github Enterprise Server.
+#[derive(Serialize, Deserialize, Type, Debug, Clone, PartialEq, Eq)]
+pub struct GithubServer {
+  /// Host name used for git remotes, e.g. `github.example.edu`.
+  pub host: String,
+  /// Base URI of the REST API, e.g. `https://github.example.edu/api/v3`.
+  pub api_url: String,
 }
 
-pub fn find_pr<'a>(
-  selector: &PullSelector,
-  prs: impl IntoIterator<Item = &'a FullPullRequest> + 'a,
-) -> Option<usize> {
-  prs.into_iter().position(|pr| match selector {
-    PullSelector::Branch(branch) => &pr.data.head.ref_field == branch,
-    PullSelector::Label(label) => pr
-      .data
-      .labels
-      .as_ref()
-      .map(|labels| labels.iter().any(|l| &l.name == label))
-      .unwrap_or(false),
-  })
+impl GithubServer {
+  /// Returns the server at `host`. Enterprise servers serve their API under `/api/v3`.
+  pub fn for_host(host: &str) -> Self {
+    if host == GITHUB_HOST {
+      return GithubServer::default();
+    }
+    GithubServer {
+      host: host.to_string(),
+      api_url: format!("https://{host}/api/v3"),
+    }
+  }
+
+  /// Returns the route to the GraphQL API relative to `api_url`, if it has one.
+  ///
+  /// Enterprise servers serve GraphQL at `/api/graphql`, outside of the REST API's path,
+  /// which Octocrab can't reach since it prefixes every route with that path.
+  fn graphql_route(&self) -> Option<&'static str> {
+    let url = Url::parse(&self.api_url).ok()?;
+    (url.path() == "/").then_some("/graphql")
+  }
 }
 
-pub fn find_issue<'a>(
-  label_name: &str,
-  issues: impl IntoIterator<Item = &'a Issue> + 'a,
-) -> Option<usize> {
-  issues
-    .into_iter()
-    .position(|issue| issue.labels.iter().any(|label| label.name == label_name))
+impl Default for GithubServer {
+  fn default() -> Self {
+    GithubServer {
+      host: GITHUB_HOST.to_string(),
+      api_url: cassette::GITHUB_API.to_string(),
+    }
+  }
 }
 
-const RESET_LABEL: &str = "reset";
+const GITHUB_HOST: &str = "github.com";
+
+// The server that the global Octocrab instance talks to, set by `init_octocrab`.
+static SERVER: RwLock<Option<GithubServer>> = RwLock::new(None);
+
+/// Returns the server that the global Octocrab instance talks to.
+pub fn current_server() -> GithubServer {
+  SERVER.read().clone().unwrap_or_default()
+}
 
 pub async fn load_user() -> Result<String> {
   let user = octocrab::instance()
@@ -84,15 +115,15 @@ pub async fn load_user() -> Result<String> {
   Ok(user.login)
 }
 
-/// Checks that the user's SSH keys are configured such that `git clone git@github.com:...` can be run.
-pub fn check_ssh() -> Result<()> {
-  let output = command("ssh -T git@github.com", Path::new("/")).output()?;
+/// Checks that the user's SSH keys are configured such that `git clone git@<host>:...` can be run.
+pub fn check_ssh(host: &str) -> Result<()> {
+  let output = command(&format!("ssh -T git@{host}"), Path::new("/")).output()?;
   match output.status.code() {
     // `ssh` exits with status 1 for "success" here, and status 255 for failure
     Some(1) => Ok(()),
     _ => {
       let stderr = String::from_utf8(output.stderr)?;
-      if stderr.trim() == "git@github.com: Permission denied (publickey)." {
+      if stderr.trim() == format!("git@{host}: Permission denied (publickey).") {
         bail!("Your machine is not setup for a secure connection to Github. Please follow the instructions here: https://docs.github.com/en/authentication/troubleshooting-ssh/error-permission-denied-publickey");
       } else {
         bail!("Failed to establish a secure connection to Github with error:\n{stderr}")
@@ -101,11 +132,6 @@ pub fn check_ssh() -> Result<()> {
   }
 }
 
-pub enum GitProtocol {
-  Ssh,
-  Https,
-}
-
 #[derive(PartialEq, Eq, Debug)]
 pub enum TestRepoResult {
   HasContent,
@@ -113,14 +139,33 @@ pub enum TestRepoResult {
   NotFound,
 }
 
+pub(crate) fn is_not_found(e: &octocrab::Error) -> bool {
+  matches!(
+    e,
+    octocrab::Error::GitHub {
+      source: GitHubError {
+        status_code: StatusCode::NOT_FOUND,
+        ..
+      },
+      ..
+    }
+  )
+}
+
 impl GithubRepo {
   pub fn new(user: &str, name: &str) -> Self {
+    GithubRepo::with_client(octocrab::instance(), current_server(), user, name)
+  }
+
+  pub fn with_client(gh: Arc<Octocrab>, server: GithubServer, user: &str, name: &str) -> Self {
     GithubRepo {
       user: user.to_string(),
       name: name.to_string(),
-      gh: octocrab::instance(),
+      gh,
+      server,
       prs: Mutex::new(None),
       issues: Mutex::new(None),
+      synced_at: Mutex::new(None),
     }
   }
 
@@ -130,57 +175,6 @@ impl GithubRepo {
     Ok(repo)
   }
 
-  /// Returns true if repo
-  pub async fn fetch(&self) -> Result<bool> {
-    let (pr_handler, issue_handler) = (self.pr_handler(), self.issue_handler());
-    let res = try_join!(
-      pr_handler.list().state(octocrab::params::State::All).send(),
-      issue_handler
-        .list()
-        .state(octocrab::params::State::All)
-        .send()
-    );
-    let (mut pr_page, mut issue_page) = match res {
-      Ok(pages) => pages,
-      Err(octocrab::Error::GitHub {
-        source: GitHubError {
-          status_code: StatusCode::NOT_FOUND,
-          ..
-        },
-        ..
-      }) => return Ok(false),
-      Err(e) => return Err(e.into()),
-    };
-    let (prs, mut issues) = (pr_page.take_items(), issue_page.take_items());
-
-    let full_prs = try_join_all(prs.into_iter().map(|pr| async move {
-      let comment_pages = self
-        .pr_handler()
-        .list_comments(Some(pr.number))
-        .send()
-        .await
-        .with_context(|| format!("Failed to fetch comments for PR {}", pr.number))?;
-      let comments = comment_pages.into_iter().collect::<Vec<_>>();
-      Ok::<_, anyhow::Error>(FullPullRequest { data: pr, comments })
-    }))
-    .await?;
-
-    // Pull requests are considered issues, so filter them out
-    issues.retain(|issue| issue.pull_request.is_none());
-
-    *self.prs.lock() = Some(full_prs);
-    *self.issues.lock() = Some(issues);
-
-    Ok(true)
-  }
-
-  pub fn remote(&self, protocol: GitProtocol) -> String {
-    match protocol {
-      GitProtocol::Https => format!("https://github.com/{}/{}", self.user, self.name),
-      GitProtocol::Ssh => format!("git@github.com:{}/{}.git", self.user, self.name),
-    }
-  }
-
   pub async fn test_repo(&self) -> Result<TestRepoResult> {
     let result = self.repo_handler().list_commits().send().await;
     match result {
@@ -192,13 +186,7 @@ impl GithubRepo {
           },
         ..
       }) => Ok(TestRepoResult::NoContent),
-      Err(octocrab::Error::GitHub {
-        source: GitHubError {
-          status_code: StatusCode::NOT_FOUND,
-          ..
-        },
-        ..
-      }) => Ok(TestRepoResult::NotFound),
+      Err(e) if is_not_found(&e) => Ok(TestRepoResult::NotFound),
       Ok(_) => Ok(TestRepoResult::HasContent),
       Err(e) => {
         if let octocrab::Error::GitHub {
@@ -214,11 +202,6 @@ impl GithubRepo {
     }
   }
 
-  pub fn clone(&self, path: &Path) -> Result<GitRepo> {
-    let remote = self.remote(GitProtocol::Ssh);
-    GitRepo::clone(&path.join(&self.name), &remote)
-  }
-
   // There is some unknown delay between creating a repo from a template and its contents being added.
   // We have to wait until that happens
   async fn wait_for_content(&self, expected: TestRepoResult) -> Result<()> {
@@ -242,20 +225,6 @@ impl GithubRepo {
     Ok(())
   }
 
-  async fn create_labels(&self, labels: &[Label]) -> Result<()> {
-    let issues = self.issue_handler();
-    try_join_all(labels.iter().filter(|label| !label.default).map(|label| {
-      issues.create_label(
-        &label.name,
-        &label.color,
-        label.description.as_deref().unwrap_or(""),
-      )
-    }))
-    .await
-    .context("Failed to create labels")?;
-    Ok(())
-  }
-
   async fn unsubscribe(&self) -> Result<()> {
     let route = format!("/repos/{}/{}/subscription", self.user, self.name);
     self
@@ -272,196 +241,448 @@ impl GithubRepo {
     Ok(())
   }
 
-  pub async fn instantiate_from_package(package: &QuestPackage) -> Result<GithubRepo> {
-    let user = load_user().await.context("Failed to load user")?;
-    let params = json!({
-        "name": &package.config.repo,
-        "private": true,
-    });
-    octocrab::instance()
-      .post::<_, serde_json::Value>("/user/repos", Some(&params))
-      .await
-      .context("Failed to create repo")?;
-    let repo = GithubRepo::new(&user, &package.config.repo);
-    repo
-      .wait_for_content(TestRepoResult::NoContent)
-      .await
-      .context("Github repo was not properly initialized")?;
-    repo
-      .unsubscribe()
-      .await
-      .context("Failed to unsubscribe from repo")?;
-    repo
-      .create_labels(&package.labels)
-      .await
-      .context("Failed to transfer package labels to repo")?;
-    Ok(repo)
+  pub fn repo_handler(&self) -> RepoHandler<'_> {
+    self.gh.repos(&self.user, &self.name)
   }
 
-  pub async fn instantiate_from_repo(base: &GithubRepo) -> Result<GithubRepo> {
-    let user = load_user().await?;
-    let name = &base.name;
-    base
-      .repo_handler()
-      .generate(name)
-      .owner(&user)
-      // TODO: make this configurable? Right now we don't want privacy so we can see learner progress
-      // .private(true)
+  /// Lists PRs and issues through GraphQL where the server allows, and REST otherwise.
+  async fn query(
+    &self,
+    with_comments: bool,
+    since: Option<DateTime<Utc>>,
+  ) -> Result<Option<(Vec<QueriedPr>, Vec<Issue>)>> {
+    if let Some(route) = self.server.graphql_route() {
+      // GraphQL queries can't be conditional, so first check that something changed.
+      if let Some(since) = since {
+        if self.last_updated().await?.is_some_and(|last| last <= since) {
+          return Ok(Some((Vec::new(), Vec::new())));
+        }
+      }
+      let (gh, user, name) = (&self.gh, &self.user, &self.name);
+      let queried = graphql::query(gh, route, user, name, with_comments, since).await?;
+      let Some((prs, issues)) = queried else {
+        return Ok(None);
+      };
+
+      // Only items with too many labels to fit in the query need more requests.
+      let prs = try_join_all(prs.into_iter().map(|mut pr| async move {
+        if pr.labels_truncated {
+          pr.data.labels = self.issue_labels(pr.data.number).await?;
+        }
+        Ok::<_, anyhow::Error>(pr)
+      }));
+      let issues = try_join_all(issues.into_iter().map(|mut issue| async move {
+        if issue.labels_truncated {
+          issue.data.labels = self.issue_labels(issue.data.number).await?;
+        }
+        Ok::<_, anyhow::Error>(issue.data)
+      }));
+      let (prs, issues) = try_join!(prs, issues)?;
+      return Ok(Some((prs, issues)));
+    }
+    let listed = match since {
+      Some(since) => self.list_changed_rest(since).await?,
+      None => self.list_rest().await?,
+    };
+    let Some((prs, issues)) = listed else {
+      return Ok(None);
+    };
+    let prs = prs
+      .into_iter()
+      .map(|pr| {
+        Ok(QueriedPr {
+          data: PullRequest::try_from(pr)?,
+          comments: None,
+          labels_truncated: false,
+        })
+      })
+      .collect::<Result<_>>()?;
+    let issues = issues.into_iter().map(Issue::from).collect();
+    Ok(Some((prs, issues)))
+  }
+
+  /// Returns when an issue or PR was last updated, if the repo has any. Unlike a GraphQL
+  /// query this request never changes, so the ETag cache answers it without costing quota
+  /// while nothing has changed.
+  async fn last_updated(&self) -> Result<Option<DateTime<Utc>>> {
+    let res = self
+      .issue_handler()
+      .list()
+      .state(octocrab::params::State::All)
+      .sort(issues::Sort::Updated)
+      .direction(Direction::Descending)
+      .per_page(1)
       .send()
-      .await
-      .with_context(|| format!("Failed to clone template repo {}/{}", base.user, base.name))?;
+      .await;
+    match res {
+      Ok(page) => Ok(page.items.first().map(|issue| issue.updated_at)),
+      // Let the query report that the repo is gone.
+      Err(e) if is_not_found(&e) => Ok(None),
+      Err(e) => Err(e.into()),
+    }
+  }
 
-    let repo = GithubRepo::new(&user, name);
-    repo
-      .wait_for_content(TestRepoResult::HasContent)
+  async fn list_rest(
+    &self,
+  ) -> Result<Option<(Vec<models::pulls::PullRequest>, Vec<models::issues::Issue>)>> {
+    let pr_handler = self.pr_handler();
+    let pr_page_future = pr_handler
+      .list()
+      .state(octocrab::params::State::All)
+      .sort(pull_params::Sort::Created)
+      .direction(Direction::Descending)
+      .per_page(PAGE_SIZE)
+      .send();
+
+    let issue_handler = self.issue_handler();
+    let issue_page_future = issue_handler
+      .list()
+      .state(octocrab::params::State::All)
+      .sort(issues::Sort::Created)
+      .direction(Direction::Descending)
+      .per_page(PAGE_SIZE)
+      .send();
+
+    let (pr_page, issue_page) = match try_join!(pr_page_future, issue_page_future) {
+      Ok(result) => result,
+      Err(e) if is_not_found(&e) => return Ok(None),
+      Err(e) => return Err(e.into()),
+    };
+
+    let (prs, mut issues) = try_join!(self.gh.all_pages(pr_page), self.gh.all_pages(issue_page))
+      .context("Failed to fetch PRs and issues")?;
+    issues.retain(|issue| issue.pull_request.is_none());
+
+    Ok(Some((prs, issues)))
+  }
+
+  /// Lists the PRs and issues updated since `since`. The issues API reports changes to both.
+  async fn list_changed_rest(
+    &self,
+    since: DateTime<Utc>,
+  ) -> Result<Option<(Vec<models::pulls::PullRequest>, Vec<models::issues::Issue>)>> {
+    let res = self
+      .issue_handler()
+      .list()
+      .state(octocrab::params::State::All)
+      .since(since)
+      .per_page(PAGE_SIZE)
+      .send()
+      .await;
+    let page = match res {
+      Ok(page) => page,
+      Err(e) if is_not_found(&e) => return Ok(None),
+      Err(e) => return Err(e.into()),
+    };
+    let (prs, issues): (Vec<_>, Vec<_>) = self
+      .gh
+      .all_pages(page)
       .await
-      .context("Github repo was not properly initialized")?;
+      .context("Failed to fetch changed issues")?
+      .into_iter()
+      .partition(|issue| issue.pull_request.is_some());
 
-    // Unsubscribe from repo notifications to avoid annoying emails.
-    repo
-      .unsubscribe()
+    let pr_handler = self.pr_handler();
+    let prs = try_join_all(prs.iter().map(|pr| pr_handler.get(pr.number)))
       .await
-      .context("Failed to unsubscribe from repo")?;
+      .context("Failed to fetch changed PRs")?;
+    Ok(Some((prs, issues)))
+  }
 
-    // Copy all issue labels.
-    let mut page = base
+  /// Lists the labels of an issue or PR, which share a numbering.
+  async fn issue_labels(&self, number: u64) -> Result<Vec<String>> {
+    let page = self
       .issue_handler()
-      .list_labels_for_repo()
+      .list_labels_for_issue(number)
+      .per_page(PAGE_SIZE)
       .send()
       .await
-      .context("Failed to fetch labels from upstream repo")?;
-    let labels = page.take_items();
-    repo
-      .create_labels(&labels)
+      .with_context(|| format!("Failed to fetch labels for #{number}"))?;
+    let labels = self
+      .gh
+      .all_pages(page)
       .await
-      .context("Failed to transfer upstream labels to repo")?;
-
-    Ok(repo)
+      .with_context(|| format!("Failed to fetch labels for #{number}"))?;
+    Ok(labels.into_iter().map(|label| label.name).collect())
   }
 
-  pub fn repo_handler(&self) -> RepoHandler {
-    self.gh.repos(&self.user, &self.name)
+  async fn comments(&self, pr: u64) -> Result<Vec<ReviewComment>> {
+    let page = self
+      .pr_handler()
+      .list_comments(Some(pr))
+      .per_page(PAGE_SIZE)
+      .send()
+      .await
+      .with_context(|| format!("Failed to fetch comments for PR {pr}"))?;
+    let comments = self
+      .gh
+      .all_pages(page)
+      .await
+      .with_context(|| format!("Failed to fetch comments for PR {pr}"))?;
+    Ok(comments.into_iter().map(ReviewComment::from).collect())
   }
 
   pub async fn branches(&self) -> Result<Vec<Branch>> {
-    let pages = self
+    let page = self
       .repo_handler()
       .list_branches()
+      .per_page(PAGE_SIZE)
       .send()
       .await
       .context("Failed to fetch branches")?;
-    let branches = pages.into_iter().collect::<Vec<_>>();
+    let branches = self.gh.all_pages(page).await?;
     Ok(branches)
   }
 
-  pub fn pr_handler(&self) -> PullRequestHandler {
+  pub fn pr_handler(&self) -> PullRequestHandler<'_> {
     self.gh.pulls(&self.user, &self.name)
   }
 
-  pub fn prs(&self) -> MappedMutexGuard<'_, Vec<FullPullRequest>> {
-    MutexGuard::map(self.prs.lock(), |opt| {
-      opt.as_mut().expect("PRs not populated")
-    })
+  pub fn issue_handler(&self) -> IssueHandler<'_> {
+    self.gh.issues(&self.user, &self.name)
+  }
+}
+
+/// Replaces the items in `cached` that have a newer version in `changed` and adds the new ones,
+/// keeping the most recently created first.
+fn merge<T>(cached: &mut Vec<T>, changed: Vec<T>, number: impl Fn(&T) -> u64) {
+  for item in changed {
+    match cached
+      .iter()
+      .position(|other| number(other) == number(&item))
+    {
+      Some(index) => cached[index] = item,
+      None => cached.push(item),
+    }
   }
+  cached.sort_by_key(|item| Reverse(number(item)));
+}
 
-  pub fn pr(&self, selector: &PullSelector) -> Option<MappedMutexGuard<'_, FullPullRequest>> {
-    let prs = self.prs();
-    let idx = find_pr(selector, prs.iter())?;
-    Some(MappedMutexGuard::map(prs, |prs| &mut prs[idx]))
+// Octocrab's models are converted into the forge's own here, so that the rest of RepoQuest
+// doesn't depend on the shape of Github's API.
+
+impl From<models::IssueState> for IssueState {
+  fn from(state: models::IssueState) -> Self {
+    match state {
+      models::IssueState::Open => IssueState::Open,
+      _ => IssueState::Closed,
+    }
   }
+}
 
-  pub fn issue_handler(&self) -> IssueHandler {
-    self.gh.issues(&self.user, &self.name)
+impl From<models::Label> for Label {
+  fn from(label: models::Label) -> Self {
+    Label {
+      name: label.name,
+      color: label.color,
+      description: label.description,
+      default: label.default,
+    }
   }
+}
 
-  pub fn issues(&self) -> MappedMutexGuard<'_, Vec<Issue>> {
-    MutexGuard::map(self.issues.lock(), |opt| {
-      opt.as_mut().expect("Issues not populated")
+impl From<models::issues::Issue> for Issue {
+  fn from(issue: models::issues::Issue) -> Self {
+    Issue {
+      number: issue.number,
+      title: issue.title,
+      body: issue.body,
+      labels: issue.labels.into_iter().map(|label| label.name).collect(),
+      state: issue.state.into(),
+      html_url: issue.html_url.into(),
+      created_at: issue.created_at,
+      updated_at: issue.updated_at,
+    }
+  }
+}
+
+impl TryFrom<models::pulls::PullRequest> for PullRequest {
+  type Error = anyhow::Error;
+
+  // Github always includes these fields, but Octocrab doesn't require them.
+  fn try_from(pr: models::pulls::PullRequest) -> Result<Self> {
+    let missing = |field: &str| format!("Github returned PR #{} without its {field}", pr.number);
+    Ok(PullRequest {
+      number: pr.number,
+      title: pr.title.with_context(|| missing("title"))?,
+      body: pr.body,
+      labels: (pr.labels.into_iter().flatten())
+        .map(|label| label.name)
+        .collect(),
+      head: pr.head.ref_field,
+      base: pr.base.ref_field,
+      state: pr.state.with_context(|| missing("state"))?.into(),
+      html_url: pr.html_url.with_context(|| missing("URL"))?.into(),
+      created_at: pr.created_at.with_context(|| missing("creation time"))?,
+      updated_at: pr.updated_at.with_context(|| missing("update time"))?,
+      merged_at: pr.merged_at,
     })
   }
+}
 
-  pub fn issue(&self, label_name: &str) -> Option<MappedMutexGuard<'_, Issue>> {
-    let issues = self.issues();
-    let idx = find_issue(label_name, issues.iter())?;
-    Some(MappedMutexGuard::map(issues, |issues| &mut issues[idx]))
+impl From<pulls::Comment> for ReviewComment {
+  fn from(comment: pulls::Comment) -> Self {
+    ReviewComment {
+      id: comment.id.0,
+      path: comment.path,
+      line: comment.line,
+      body: comment.body,
+    }
   }
+}
 
-  pub async fn copy_pr(
-    &self,
-    pr: &FullPullRequest,
-    head: &str,
-    merge_type: MergeType,
-  ) -> Result<PullRequest> {
-    let pulls = self.pr_handler();
-    let mut body = pr
-      .data
-      .body
-      .as_ref()
-      .expect("Author error: PR missing body")
-      .clone();
+#[async_trait]
+impl Forge for GithubRepo {
+  fn user(&self) -> &str {
+    &self.user
+  }
 
-    let is_reset = match merge_type {
-      MergeType::SolutionReset => {
-        body.push_str(r#"
+  fn name(&self) -> &str {
+    &self.name
+  }
 
-Note: due to a merge conflict, this PR is a hard reset to the reference solution, and may have overwritten your previous changes."#);
-        true
+  fn remote(&self, protocol: GitProtocol) -> String {
+    match protocol {
+      GitProtocol::Https => {
+        format!("https://{}/{}/{}", self.server.host, self.user, self.name)
       }
+      GitProtocol::Ssh => format!("git@{}:{}/{}.git", self.server.host, self.user, self.name),
+    }
+  }
 
-      MergeType::StarterReset => {
-        body.push_str(r#"
+  fn rate_limit(&self) -> Option<RateLimit> {
+    RateLimiter::for_host(&self.server.host).status()
+  }
 
-Note: due to a merge conflict, this PR is a hard reset to the starter code, and may have overwritten your previous changes."#);
-        true
-      }
+  async fn check_access(&self) -> Result<()> {
+    match repo_access(&self.gh, &self.user, &self.name).await? {
+      TokenStatus::Valid => Ok(()),
+      status => bail!("{status}"),
+    }
+  }
 
-      MergeType::Success => false,
+  async fn fetch(&self) -> Result<bool> {
+    // After the first fetch, only download what changed since then.
+    let since = *self.synced_at.lock();
+    let Some((prs, issues)) = self.query(true, since).await? else {
+      return Ok(false);
     };
 
-    let request = pulls
-      .create(
-        pr.data
-          .title
-          .as_ref()
-          .expect("Author error: PR missing title"),
-        &pr.data.head.ref_field,
-        "main", // don't copy base
+    // Only PRs with too many comments to fit in the query need more requests.
+    let full_prs = try_join_all(prs.into_iter().map(|pr| async move {
+      let comments = match pr.comments {
+        Some(comments) => comments,
+        None => self.comments(pr.data.number).await?,
+      };
+      Ok::<_, anyhow::Error>(FullPullRequest {
+        data: pr.data,
+        comments,
+      })
+    }))
+    .await?;
+
+    let latest = (full_prs.iter().map(|pr| pr.data.updated_at))
+      .chain(issues.iter().map(|issue| issue.updated_at))
+      .max();
+    let (mut cached_prs, mut cached_issues) = (self.prs.lock(), self.issues.lock());
+    match (since, cached_prs.as_mut(), cached_issues.as_mut()) {
+      (Some(_), Some(cached_prs), Some(cached_issues)) => {
+        merge(cached_prs, full_prs, |pr| pr.data.number);
+        merge(cached_issues, issues, |issue| issue.number);
+      }
+      _ => {
+        *cached_prs = Some(full_prs);
+        *cached_issues = Some(issues);
+      }
+    }
+    *self.synced_at.lock() = latest.max(since);
+
+    Ok(true)
+  }
+
+  fn prs(&self) -> MappedMutexGuard<'_, Vec<FullPullRequest>> {
+    MutexGuard::map(self.prs.lock(), |opt| {
+      opt.as_mut().expect("PRs not populated")
+    })
+  }
+
+  fn issues(&self) -> MappedMutexGuard<'_, Vec<Issue>> {
+    MutexGuard::map(self.issues.lock(), |opt| {
+      opt.as_mut().expect("Issues not populated")
+    })
+  }
+
+  fn restore(&self, prs: Vec<FullPullRequest>, issues: Vec<Issue>) {
+    *self.prs.lock() = Some(prs);
+    *self.issues.lock() = Some(issues);
+  }
+
+  async fn list_all(&self) -> Result<Option<(Vec<PullRequest>, Vec<Issue>)>> {
+    let prs_and_issues = self.query(false, None).await?;
+    Ok(prs_and_issues.map(|(prs, issues)| (prs.into_iter().map(|pr| pr.data).collect(), issues)))
+  }
+
+  async fn labels(&self) -> Result<Vec<Label>> {
+    let page = self
+      .issue_handler()
+      .list_labels_for_repo()
+      .per_page(PAGE_SIZE)
+      .send()
+      .await
+      .context("Failed to fetch labels")?;
+    let labels = self
+      .gh
+      .all_pages(page)
+      .await
+      .context("Failed to fetch labels")?;
+    Ok(labels.into_iter().map(Label::from).collect())
+  }
+
+  async fn create_labels(&self, labels: &[Label]) -> Result<()> {
+    let issues = self.issue_handler();
+    try_join_all(labels.iter().filter(|label| !label.default).map(|label| {
+      issues.create_label(
+        &label.name,
+        &label.color,
+        label.description.as_deref().unwrap_or(""),
       )
-      .body(body);
-    let self_pr = request.send().await.context("Failed to create new PR")?;
+    }))
+    .await
+    .context("Failed to create labels")?;
+    Ok(())
+  }
 
-    // TODO: lots of parallelism below we should exploit
+  async fn create_pr(
+    &self,
+    title: &str,
+    head: &str,
+    base: &str,
+    body: &str,
+    labels: &[String],
+  ) -> Result<PullRequest> {
+    let pr = self
+      .pr_handler()
+      .create(title, head, base)
+      .body(body)
+      .send()
+      .await
+      .context("Failed to create PR")?;
 
-    let mut labels = match &pr.data.labels {
-      Some(labels) => labels
-        .iter()
-        .map(|label| label.name.clone())
-        .collect::<Vec<_>>(),
-      None => Vec::new(),
-    };
-    if is_reset {
-      labels.push(RESET_LABEL.into());
-    }
     self
       .issue_handler()
-      .add_labels(self_pr.number, &labels)
+      .add_labels(pr.number, labels)
       .await
       .context("Failed to add labels to PR")?;
 
-    for comment in &pr.comments {
-      self
-        .copy_pr_comment(self_pr.number, comment, head)
-        .await
-        .context("Failed to add comment to PR")?;
-    }
-
-    Ok(self_pr)
+    let mut pr = PullRequest::try_from(pr)?;
+    pr.labels = labels.to_vec();
+    Ok(pr)
   }
 
-  pub async fn copy_pr_comment(
+  async fn copy_pr_comment(
     &self,
     pr: u64,
-    comment: &pulls::Comment,
+    comment: &QuestReviewComment,
     commit: &str,
   ) -> Result<()> {
     let route = format!("/repos/{}/{}/pulls/{pr}/comments", self.user, self.name);
@@ -479,70 +700,29 @@ Note: due to a merge conflict, this PR is a hard reset to the starter code, and
     Ok(())
   }
 
-  fn process_issue_body(&self, body: &str) -> String {
-    let re = Regex::new(r"\{\{ (\S+) (\S+) \}\}").unwrap();
-    let mut new_body = body.to_string();
-    let substitutions = re.captures_iter(body).filter_map(|cap| {
-      let full_match = cap.get(0).unwrap();
-      let label = &cap[1];
-      let kind = &cap[2];
-      let number = match kind {
-        "pr" => {
-          let Some(pr) = self.pr(&PullSelector::Label(label.to_string())) else {
-            warn!("No PR with label {label}");
-            return None;
-          };
-          pr.data.number
-        }
-        "issue" => {
-          let Some(issue) = self.issue(label) else {
-            warn!("No issue with label {label}");
-            return None;
-          };
-          issue.number
-        }
-        _ => unimplemented!(),
-      };
-
-      Some((full_match.range(), format!("#{number}")))
-    });
-    utils::replace_many_ranges(&mut new_body, substitutions);
-
-    new_body
-  }
-
-  pub async fn copy_issue(&self, issue: &Issue) -> Result<Issue> {
-    let body = issue.body.as_ref().unwrap();
-    let body_processed = self.process_issue_body(body);
+  async fn create_issue(&self, title: &str, body: &str, labels: &[String]) -> Result<Issue> {
     let issue = self
       .issue_handler()
-      .create(&issue.title)
-      .body(body_processed)
-      .labels(
-        issue
-          .labels
-          .iter()
-          .map(|label| label.name.clone())
-          .collect::<Vec<_>>(),
-      )
+      .create(title)
+      .body(body)
+      .labels(labels.to_vec())
       .send()
-      .await
-      .with_context(|| format!("Failed to create issue: {}", issue.title))?;
-    Ok(issue)
+      .await?;
+    Ok(issue.into())
   }
 
-  pub async fn close_issue(&self, issue: &Issue) -> Result<()> {
+  async fn close_issue(&self, issue: &Issue) -> Result<()> {
     self
       .issue_handler()
       .update(issue.number)
-      .state(IssueState::Closed)
+      .state(models::IssueState::Closed)
       .send()
       .await
       .with_context(|| format!("Failed to close issue: {}", issue.number))?;
     Ok(())
   }
 
-  pub async fn merge_pr(&self, pr: &PullRequest) -> Result<()> {
+  async fn merge_pr(&self, pr: &PullRequest) -> Result<()> {
     self
       .pr_handler()
       .merge(pr.number)
@@ -552,7 +732,7 @@ Note: due to a merge conflict, this PR is a hard reset to the starter code, and
     Ok(())
   }
 
-  pub async fn delete(&self) -> Result<()> {
+  async fn delete(&self) -> Result<()> {
     self
       .repo_handler()
       .delete()
@@ -562,61 +742,1093 @@ Note: due to a merge conflict, this PR is a hard reset to the starter code, and
   }
 }
 
+/// The Github account associated with an Octocrab instance, by default the global one.
+pub struct GithubHost {
+  gh: Arc<Octocrab>,
+  server: GithubServer,
+}
+
+impl GithubHost {
+  pub fn new() -> Self {
+    GithubHost::with_client(octocrab::instance(), current_server())
+  }
+
+  pub fn with_client(gh: Arc<Octocrab>, server: GithubServer) -> Self {
+    GithubHost { gh, server }
+  }
+
+  fn handle(&self, user: &str, name: &str) -> GithubRepo {
+    GithubRepo::with_client(Arc::clone(&self.gh), self.server.clone(), user, name)
+  }
+}
+
+impl Default for GithubHost {
+  fn default() -> Self {
+    GithubHost::new()
+  }
+}
+
+#[async_trait]
+impl ForgeHost for GithubHost {
+  async fn current_user(&self) -> Result<String> {
+    let user = self
+      .gh
+      .current()
+      .user()
+      .await
+      .context("Failed to query Github connector for current user")?;
+    Ok(user.login)
+  }
+
+  fn repo(&self, user: &str, name: &str) -> Box<dyn Forge> {
+    Box::new(self.handle(user, name))
+  }
+
+  async fn create_repo(&self, name: &str) -> Result<Box<dyn Forge>> {
+    let user = self.current_user().await.context("Failed to load user")?;
+    let params = json!({
+        "name": name,
+        "private": true,
+    });
+    self
+      .gh
+      .post::<_, serde_json::Value>("/user/repos", Some(&params))
+      .await
+      .context("Failed to create repo")?;
+    let repo = self.handle(&user, name);
+    repo
+      .wait_for_content(TestRepoResult::NoContent)
+      .await
+      .context("Github repo was not properly initialized")?;
+    repo
+      .unsubscribe()
+      .await
+      .context("Failed to unsubscribe from repo")?;
+    Ok(Box::new(repo))
+  }
+
+  async fn generate_repo(&self, template: &dyn Forge) -> Result<Box<dyn Forge>> {
+    let user = self.current_user().await?;
+    let name = template.name();
+    self
+      .gh
+      .repos(template.user(), name)
+      .generate(name)
+      .owner(&user)
+      // TODO: make this configurable? Right now we don't want privacy so we can see learner progress
+      // .private(true)
+      .send()
+      .await
+      .with_context(|| {
+        format!(
+          "Failed to clone template repo {}/{}",
+          template.user(),
+          template.name()
+        )
+      })?;
+
+    let repo = self.handle(&user, name);
+    repo
+      .wait_for_content(TestRepoResult::HasContent)
+      .await
+      .context("Github repo was not properly initialized")?;
+
+    // Unsubscribe from repo notifications to avoid annoying emails.
+    repo
+      .unsubscribe()
+      .await
+      .context("Failed to unsubscribe from repo")?;
+
+    Ok(Box::new(repo))
+  }
+
+  fn check_ssh(&self) -> Result<()> {
+    check_ssh(&self.server.host)
+  }
+}
+
+/// A Github token together with the server it was issued by.
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct GithubCredentials {
+  pub token: String,
+  pub server: GithubServer,
+}
+
 #[derive(Serialize, Deserialize, Type, Debug, Clone)]
 #[serde(tag = "type", content = "value")]
 pub enum GithubToken {
-  Found(String),
+  Found(GithubCredentials),
   NotFound,
+  /// A token was found, but Github won't accept it for quests.
+  Invalid(TokenStatus),
   Error(String),
 }
 
-macro_rules! token_try {
-  ($e:expr) => {{
-    match $e {
-      Ok(x) => x,
-      Err(e) => return GithubToken::Error(format!("{e:?}")),
+/// Whether Github accepts a token for everything a quest needs to do.
+#[derive(Serialize, Deserialize, Type, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", content = "value")]
+pub enum TokenStatus {
+  Valid,
+  /// A classic token without these scopes.
+  MissingScopes(Vec<String>),
+  Expired,
+  /// Github does not recognize the token, usually because it was revoked or has expired.
+  Revoked,
+  /// A fine-grained token or app token without access to repositories. Contains the
+  /// permissions Github asked for, if it said.
+  MissingPermissions(Vec<String>),
+}
+
+impl fmt::Display for TokenStatus {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TokenStatus::Valid => write!(f, "Your Github token is valid."),
+      TokenStatus::MissingScopes(scopes) => write!(
+        f,
+        "Your Github token is missing the scopes: {}. Generate a new token with these scopes selected.",
+        scopes.join(", ")
+      ),
+      TokenStatus::Expired => write!(
+        f,
+        "Your Github token has expired. Log in again or generate a new token."
+      ),
+      TokenStatus::Revoked => write!(
+        f,
+        "Github does not accept your token. It may have been revoked or expired. Log in again or generate a new token."
+      ),
+      TokenStatus::MissingPermissions(permissions) => {
+        write!(f, "Your Github token cannot access your repositories.")?;
+        if !permissions.is_empty() {
+          write!(f, " It needs the permissions: {}.", permissions.join(", "))?;
+        }
+        write!(
+          f,
+          " Grant it read and write access to all repositories, or use a classic token with the repo scope."
+        )
+      }
+    }
+  }
+}
+
+const REQUIRED_SCOPES: [&str; 1] = ["repo"];
+
+fn header_str<'a>(headers: &'a http::HeaderMap, name: &str) -> Option<&'a str> {
+  headers.get(name)?.to_str().ok()
+}
+
+/// Parses Github's expiration header, e.g. `2024-06-01 12:00:00 UTC`.
+fn parse_expiration(header: &str) -> Option<DateTime<Utc>> {
+  let header = header.replace(" UTC", " +0000");
+  let time = DateTime::parse_from_str(&header, "%Y-%m-%d %H:%M:%S %z").ok()?;
+  Some(time.with_timezone(&Utc))
+}
+
+/// Checks the scopes, permissions and expiry of a token against the API it was issued for.
+/// The permissions of fine-grained tokens are only checked if given the quest's `repo`, as
+/// `(owner, name)`.
+pub async fn validate_token(
+  credentials: &GithubCredentials,
+  repo: Option<(&str, &str)>,
+) -> Result<TokenStatus> {
+  let gh = Octocrab::builder()
+    .base_uri(credentials.server.api_url.as_str())
+    .with_context(|| format!("Invalid Github API URL: {}", credentials.server.api_url))?
+    .personal_token(credentials.token.clone())
+    .build()
+    .context("Failed to build Github connector")?;
+
+  let response = gh._get("/user").await.context("Failed to contact Github")?;
+  match response.status() {
+    StatusCode::UNAUTHORIZED => return Ok(TokenStatus::Revoked),
+    status if !status.is_success() => bail!("Github responded with status {status}"),
+    _ => {}
+  }
+
+  let expiration = header_str(response.headers(), "github-authentication-token-expiration");
+  if expiration
+    .and_then(parse_expiration)
+    .is_some_and(|expiration| expiration <= Utc::now())
+  {
+    return Ok(TokenStatus::Expired);
+  }
+
+  // Only classic tokens have scopes.
+  if let Some(scopes) = header_str(response.headers(), "x-oauth-scopes") {
+    let granted = scopes.split(',').map(str::trim).collect::<Vec<_>>();
+    let missing = REQUIRED_SCOPES
+      .iter()
+      .filter(|scope| !granted.contains(scope))
+      .map(ToString::to_string)
+      .collect::<Vec<_>>();
+    return Ok(if missing.is_empty() {
+      TokenStatus::Valid
+    } else {
+      TokenStatus::MissingScopes(missing)
+    });
+  }
+
+  // Other tokens carry per-repository permissions, which Github won't list directly, so
+  // they can only be checked against the quest's repo.
+  match repo {
+    Some((owner, name)) => repo_access(&gh, owner, name).await,
+    None => Ok(TokenStatus::Valid),
+  }
+}
+
+/// The permissions that quests need on fine-grained tokens, for when Github doesn't say.
+const REQUIRED_PERMISSIONS: [&str; 3] = ["contents=write", "issues=write", "pull_requests=write"];
+
+/// Checks that `gh`'s token can write to the repo `owner/name`.
+async fn repo_access(gh: &Octocrab, owner: &str, name: &str) -> Result<TokenStatus> {
+  let response = gh
+    ._get(format!("/repos/{owner}/{name}"))
+    .await
+    .context("Failed to contact Github")?;
+  match response.status() {
+    // Github hides repos that the token can't read.
+    StatusCode::FORBIDDEN | StatusCode::NOT_FOUND => {
+      let permissions = header_str(response.headers(), "x-accepted-github-permissions")
+        .map(|header| {
+          header
+            .split([';', ','])
+            .map(str::trim)
+            .filter(|permission| !permission.is_empty())
+            .map(ToString::to_string)
+            .collect()
+        })
+        .unwrap_or_default();
+      return Ok(TokenStatus::MissingPermissions(permissions));
+    }
+    status if !status.is_success() => bail!("Github responded with status {status}"),
+    _ => {}
+  }
+
+  #[derive(Deserialize)]
+  struct RepoPermissions {
+    permissions: Option<models::Permissions>,
+  }
+  let body = gh
+    .body_to_string(response)
+    .await
+    .context("Failed to read Github response")?;
+  let repo: RepoPermissions = serde_json::from_str(&body).context("Failed to parse Github repo")?;
+  Ok(
+    if repo.permissions.is_some_and(|permissions| permissions.push) {
+      TokenStatus::Valid
+    } else {
+      TokenStatus::MissingPermissions(REQUIRED_PERMISSIONS.map(String::from).to_vec())
+    },
+  )
+}
+
+/// Settings for a Github Enterprise Server, read from `~/.rqst-github.toml`.
+#[derive(Deserialize)]
+struct GithubConfig {
+  host: String,
+  api_url: Option<String>,
+  /// If not provided, the token is read from `~/.rqst-token` or the `gh` CLI as usual.
+  token: Option<String>,
+  /// Client ID of an OAuth app on the server, for logging in through the browser.
+  client_id: Option<String>,
+}
+
+impl GithubConfig {
+  fn server(&self) -> GithubServer {
+    let default = GithubServer::for_host(&self.host);
+    GithubServer {
+      api_url: self.api_url.clone().unwrap_or(default.api_url),
+      ..default
     }
-  }};
+  }
 }
 
-fn read_github_token_from_fs() -> GithubToken {
-  let home = match home::home_dir() {
-    Some(dir) => dir,
-    None => return GithubToken::NotFound,
+fn read_github_config() -> Result<Option<GithubConfig>> {
+  let Some(home) = home::home_dir() else {
+    return Ok(None);
+  };
+  let path = home.join(".rqst-github.toml");
+  if !path.exists() {
+    return Ok(None);
+  }
+  let contents =
+    fs::read_to_string(&path).with_context(|| format!("Failed to read: {}", path.display()))?;
+  let config = toml::from_str(&contents)
+    .with_context(|| format!("Failed to parse Github config: {}", path.display()))?;
+  Ok(Some(config))
+}
+
+fn read_github_token_from_fs() -> Result<Option<String>> {
+  let Some(home) = home::home_dir() else {
+    return Ok(None);
   };
   let path = home.join(".rqst-token");
   if path.exists() {
-    let token = token_try!(fs::read_to_string(path));
-    GithubToken::Found(token.trim_end().to_string())
+    let token = fs::read_to_string(path)?;
+    Ok(Some(token.trim_end().to_string()))
   } else {
-    GithubToken::NotFound
+    Ok(None)
   }
 }
 
-fn generate_github_token_from_cli() -> GithubToken {
-  let res = command("gh auth token", &env::current_dir().unwrap()).output();
+fn generate_github_token_from_cli(host: &str) -> Result<Option<String>> {
+  let res = command(
+    &format!("gh auth token --hostname {host}"),
+    &env::current_dir()?,
+  )
+  .output();
   match res {
     Ok(token_output) if token_output.status.success() => {
-      let token = token_try!(String::from_utf8(token_output.stdout));
-      let token_clean = token.trim_end().to_string();
-      GithubToken::Found(token_clean)
+      let token = String::from_utf8(token_output.stdout)?;
+      Ok(Some(token.trim_end().to_string()))
     }
-    _ => GithubToken::NotFound,
+    _ => Ok(None),
   }
 }
 
-pub fn get_github_token() -> GithubToken {
-  match read_github_token_from_fs() {
-    GithubToken::NotFound => generate_github_token_from_cli(),
-    result => result,
+/// What's left of a previous device login.
+enum StoredToken {
+  Usable(String),
+  /// The user needs to log in again.
+  Expired,
+  Missing,
+}
+
+/// Returns the token from a previous device login, refreshing it if it has expired.
+async fn read_oauth_token(server: &GithubServer, client_id: Option<&str>) -> Result<StoredToken> {
+  let Some(token) = OAuthToken::load()? else {
+    return Ok(StoredToken::Missing);
+  };
+  if token.host != server.host {
+    return Ok(StoredToken::Missing);
+  }
+
+  let now = Utc::now();
+  if !token.is_expired(now) {
+    return Ok(StoredToken::Usable(token.access_token));
+  }
+  if !token.can_refresh(now) {
+    return Ok(StoredToken::Expired);
+  }
+
+  let client = OAuthClient::for_server(server, client_id)?;
+  match token.refresh(&client).await {
+    Ok(token) => {
+      token.save()?;
+      Ok(StoredToken::Usable(token.access_token))
+    }
+    Err(e) => {
+      tracing::warn!("{e:?}");
+      Ok(StoredToken::Expired)
+    }
   }
 }
 
-pub fn init_octocrab(token: &str) -> Result<()> {
-  let crab_inst = Octocrab::builder()
-    .personal_token(token.to_string())
-    .build()
-    .context("Failed to build Github connector")?;
+async fn find_github_credentials() -> Result<GithubToken> {
+  let config = read_github_config()?;
+  let server = config
+    .as_ref()
+    .map_or_else(GithubServer::default, GithubConfig::server);
+  let client_id = config
+    .as_ref()
+    .and_then(|config| config.client_id.as_deref());
+  let mut oauth_expired = false;
+  let token = match config.as_ref().and_then(|config| config.token.clone()) {
+    Some(token) => Some(token),
+    None => match read_oauth_token(&server, client_id).await? {
+      StoredToken::Usable(token) => Some(token),
+      stored => {
+        oauth_expired = matches!(stored, StoredToken::Expired);
+        match read_github_token_from_fs()? {
+          Some(token) => Some(token),
+          None => generate_github_token_from_cli(&server.host)?,
+        }
+      }
+    },
+  };
+  Ok(match token {
+    Some(token) => GithubToken::Found(GithubCredentials { token, server }),
+    None if oauth_expired => GithubToken::Invalid(TokenStatus::Expired),
+    None => GithubToken::NotFound,
+  })
+}
+
+/// Returns the token RepoQuest uses for `host`, without contacting Github to check or refresh
+/// it, or `None` if `host` isn't the configured server. An expired login is skipped.
+pub fn stored_github_token(host: &str) -> Result<Option<String>> {
+  let config = read_github_config()?;
+  let server = config
+    .as_ref()
+    .map_or_else(GithubServer::default, GithubConfig::server);
+  if server.host != host {
+    return Ok(None);
+  }
+  if let Some(token) = config.and_then(|config| config.token) {
+    return Ok(Some(token));
+  }
+  let oauth_token =
+    OAuthToken::load()?.filter(|token| token.host == server.host && !token.is_expired(Utc::now()));
+  if let Some(token) = oauth_token {
+    return Ok(Some(token.access_token));
+  }
+  match read_github_token_from_fs()? {
+    Some(token) => Ok(Some(token)),
+    None => generate_github_token_from_cli(&server.host),
+  }
+}
+
+/// Finds the user's Github token and checks that Github accepts it.
+pub async fn get_github_token() -> GithubToken {
+  let credentials = match find_github_credentials().await {
+    Ok(GithubToken::Found(credentials)) => credentials,
+    Ok(token) => return token,
+    Err(e) => return GithubToken::Error(format!("{e:?}")),
+  };
+  match validate_token(&credentials, None).await {
+    Ok(TokenStatus::Valid) => GithubToken::Found(credentials),
+    Ok(status) => GithubToken::Invalid(status),
+    Err(e) => {
+      // The token may well be fine, e.g. if we are offline. Let the quest surface any error.
+      tracing::warn!("Failed to validate Github token: {e:?}");
+      GithubToken::Found(credentials)
+    }
+  }
+}
+
+/// Starts logging into the configured server through the browser.
+pub async fn start_device_login() -> Result<DeviceLogin> {
+  let config = read_github_config()?;
+  let server = config
+    .as_ref()
+    .map_or_else(GithubServer::default, GithubConfig::server);
+  let client_id = config
+    .as_ref()
+    .and_then(|config| config.client_id.as_deref());
+  let client = OAuthClient::for_server(&server, client_id)?;
+  DeviceLogin::start(client, server).await
+}
+
+/// Waits for the user to complete `login`, then saves the token for future sessions.
+pub async fn finish_device_login(login: &DeviceLogin) -> Result<GithubCredentials> {
+  let token = login.finish().await?;
+  token.save()?;
+  Ok(GithubCredentials {
+    token: token.access_token,
+    server: login.server().clone(),
+  })
+}
+
+/// Builds a client for the API at `api_url` that backs off when Github rate limits it,
+/// and that only downloads responses which changed since it last requested them. With a
+/// `refresher`, the client keeps the token from a device login fresh.
+fn build_client(
+  token: &str,
+  api_url: &str,
+  limiter: RateLimiter,
+  refresher: Option<TokenRefresher>,
+) -> Result<Octocrab> {
+  let client = Client::builder(TokioExecutor::new()).build(cassette::connector()?);
+  let client = RateLimitLayer::new(limiter).layer(client);
+  let client = EtagCacheLayer::new(ResponseCache::default()).layer(client);
+  let client = FollowRedirectLayer::new().layer(client);
+  let client = TokenRefreshLayer::new(refresher).layer(client);
+  let (base_uri, headers, auth) = cassette::layers(api_url, Some(token))?;
+  let crab = OctocrabBuilder::new_empty()
+    .with_service(client)
+    .with_layer(&base_uri)
+    .with_layer(&headers)
+    .with_layer(&auth)
+    .with_auth(AuthState::None)
+    .build()?;
+  Ok(crab)
+}
+
+/// Returns a refresher for the credentials' token if it came from a device login that can
+/// be refreshed.
+fn oauth_refresher(credentials: &GithubCredentials) -> Option<TokenRefresher> {
+  let refresher = || -> Result<Option<TokenRefresher>> {
+    let Some(token) = OAuthToken::load()? else {
+      return Ok(None);
+    };
+    let server = &credentials.server;
+    if token.host != server.host
+      || token.access_token != credentials.token
+      || token.refresh_token.is_none()
+    {
+      return Ok(None);
+    }
+    let config = read_github_config()?;
+    let client_id = config
+      .as_ref()
+      .and_then(|config| config.client_id.as_deref());
+    let client = OAuthClient::for_server(server, client_id)?;
+    Ok(Some(TokenRefresher::new(token, client)?))
+  };
+  // The token works for now either way, so don't fail the session over it.
+  refresher().unwrap_or_else(|e| {
+    tracing::warn!("Failed to set up Github token refresh: {e:?}");
+    None
+  })
+}
+
+/// Initializes the global Octocrab instance to talk to the credentials' server.
+///
+/// If `RQST_RECORD` is set to a path, every request to Github is recorded into a cassette
+/// at that path, e.g. to attach to a bug report.
+pub fn init_octocrab(credentials: &GithubCredentials) -> Result<()> {
+  let GithubCredentials { token, server } = credentials;
+  let crab_inst = match env::var_os("RQST_RECORD") {
+    Some(path) => Cassette::recording_to(Path::new(&path))
+      .record_client(token, &server.api_url)
+      .context("Failed to build recording Github connector")?,
+    None => build_client(
+      token,
+      &server.api_url,
+      RateLimiter::for_host(&server.host),
+      oauth_refresher(credentials),
+    )
+    .context("Failed to build Github connector")?,
+  };
   octocrab::initialise(crab_inst);
+  *SERVER.write() = Some(server.clone());
   Ok(())
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::forge::{model, PullSelector};
+  use cassette::{Interaction, RecordedRequest, RecordedResponse};
+  use serde_json::Value;
+  use wiremock::{
+    matchers::{body_partial_json, method, path, path_regex, query_param, query_param_is_missing},
+    Mock, MockServer, ResponseTemplate,
+  };
+
+  const HOST: &str = "github.example.edu";
+
+  #[test]
+  fn enterprise_config() -> Result<()> {
+    let config: GithubConfig = toml::from_str(&format!("host = \"{HOST}\""))?;
+    assert_eq!(config.server(), GithubServer::for_host(HOST));
+    assert_eq!(config.server().api_url, "https://github.example.edu/api/v3");
+    assert!(config.token.is_none());
+
+    let config: GithubConfig = toml::from_str(&format!(
+      "host = \"{HOST}\"\napi_url = \"https://api.example.edu\"\ntoken = \"abc\""
+    ))?;
+    assert_eq!(config.server().host, HOST);
+    assert_eq!(config.server().api_url, "https://api.example.edu");
+    assert_eq!(config.token.as_deref(), Some("abc"));
+
+    assert_eq!(
+      GithubServer::for_host("github.com"),
+      GithubServer::default()
+    );
+    Ok(())
+  }
+
+  async fn token_status(user: ResponseTemplate, repo: Option<ResponseTemplate>) -> TokenStatus {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+      .and(path("/user"))
+      .respond_with(user)
+      .mount(&server)
+      .await;
+    // Listing repos works for any token that can log in, whatever its permissions.
+    Mock::given(method("GET"))
+      .and(path("/user/repos"))
+      .respond_with(ResponseTemplate::new(200).set_body_json(json!([])))
+      .mount(&server)
+      .await;
+    if let Some(repo) = &repo {
+      Mock::given(method("GET"))
+        .and(path("/repos/learner/quest"))
+        .respond_with(repo.clone())
+        .mount(&server)
+        .await;
+    }
+    let credentials = GithubCredentials {
+      token: "token".into(),
+      server: GithubServer {
+        host: HOST.into(),
+        api_url: server.uri(),
+      },
+    };
+    let repo = repo.is_some().then_some(("learner", "quest"));
+    validate_token(&credentials, repo).await.unwrap()
+  }
+
+  fn user() -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(model::author("learner"))
+  }
+
+  #[tokio::test]
+  async fn validate_classic_token() {
+    let valid = user().insert_header("x-oauth-scopes", "read:org, repo");
+    assert_eq!(token_status(valid, None).await, TokenStatus::Valid);
+
+    let missing = user().insert_header("x-oauth-scopes", "read:user");
+    assert_eq!(
+      token_status(missing, None).await,
+      TokenStatus::MissingScopes(vec!["repo".into()])
+    );
+
+    let expired = user()
+      .insert_header("x-oauth-scopes", "repo")
+      .insert_header(
+        "github-authentication-token-expiration",
+        "2020-01-01 00:00:00 UTC",
+      );
+    assert_eq!(token_status(expired, None).await, TokenStatus::Expired);
+
+    let revoked = ResponseTemplate::new(401).set_body_json(json!({
+      "message": "Bad credentials",
+      "documentation_url": "https://docs.github.com/rest"
+    }));
+    assert_eq!(token_status(revoked, None).await, TokenStatus::Revoked);
+  }
+
+  #[tokio::test]
+  async fn validate_fine_grained_token() {
+    let repo = |push: bool| {
+      ResponseTemplate::new(200).set_body_json(json!({
+        "name": "quest",
+        "permissions": { "admin": false, "push": push, "pull": true },
+      }))
+    };
+    assert_eq!(token_status(user(), None).await, TokenStatus::Valid);
+    assert_eq!(
+      token_status(user(), Some(repo(true))).await,
+      TokenStatus::Valid
+    );
+
+    // The token can see the quest, but not file issues or PRs on it.
+    assert_eq!(
+      token_status(user(), Some(repo(false))).await,
+      TokenStatus::MissingPermissions(REQUIRED_PERMISSIONS.map(String::from).to_vec())
+    );
+
+    let hidden = ResponseTemplate::new(404)
+      .set_body_json(json!({
+        "message": "Not Found",
+        "documentation_url": "https://docs.github.com/rest"
+      }))
+      .insert_header("x-accepted-github-permissions", "metadata=read");
+    assert_eq!(
+      token_status(user(), Some(hidden)).await,
+      TokenStatus::MissingPermissions(vec!["metadata=read".into()])
+    );
+  }
+
+  #[tokio::test]
+  async fn enterprise_host() -> Result<()> {
+    let server = GithubServer::for_host(HOST);
+    let cassette = Cassette::new(vec![Interaction {
+      request: RecordedRequest {
+        method: "GET".into(),
+        uri: "/api/v3/user".into(),
+        body: None,
+      },
+      response: RecordedResponse::json(200, model::author("learner")),
+    }]);
+    let gh = cassette.replay_client(&server.api_url)?;
+    let host = GithubHost::with_client(Arc::new(gh), server);
+    assert_eq!(host.current_user().await?, "learner");
+
+    let repo = host.repo("learner", "quest");
+    assert_eq!(
+      repo.remote(GitProtocol::Ssh),
+      "git@github.example.edu:learner/quest.git"
+    );
+    assert_eq!(
+      repo.remote(GitProtocol::Https),
+      "https://github.example.edu/learner/quest"
+    );
+    Ok(())
+  }
+  /// Serves `items` from `route` in two pages, linked the way Github links them.
+  async fn mount_pages(server: &MockServer, route: &str, items: Vec<Value>) {
+    let (first, rest) = items.split_at(PAGE_SIZE as usize);
+    let next = format!("{}{route}?per_page={PAGE_SIZE}&page=2", server.uri());
+    Mock::given(method("GET"))
+      .and(path(route))
+      .and(query_param_is_missing("page"))
+      .respond_with(
+        ResponseTemplate::new(200)
+          .set_body_json(first)
+          .insert_header("link", format!("<{next}>; rel=\"next\"")),
+      )
+      .mount(server)
+      .await;
+    Mock::given(method("GET"))
+      .and(path(route))
+      .and(query_param("page", "2"))
+      .respond_with(ResponseTemplate::new(200).set_body_json(rest))
+      .mount(server)
+      .await;
+  }
+
+  fn issue(number: u64) -> Value {
+    json!(model::IssueFields {
+      number,
+      title: "Issue",
+      body: Some("body"),
+      labels: &[],
+      state: models::IssueState::Open,
+      author: "learner",
+      html_url: "https://github.com/learner/quest/issues",
+    }
+    .build())
+  }
+
+  fn pr(number: u64) -> Value {
+    json!(model::PullRequestFields {
+      number,
+      title: "PR",
+      body: Some("body"),
+      labels: &[],
+      head: "feature",
+      head_sha: "abc",
+      base: "main",
+      state: models::IssueState::Open,
+      merged: false,
+      author: "learner",
+      html_url: "https://github.com/learner/quest/pulls",
+    }
+    .build())
+  }
+
+  fn comment(id: u64) -> Value {
+    json!(model::CommentFields {
+      id,
+      path: "src/lib.rs",
+      body: "comment",
+      line: Some(1),
+      commit: "abc",
+      author: "learner",
+      html_url: "https://github.com/learner/quest/pulls",
+    }
+    .build())
+  }
+
+  #[tokio::test]
+  async fn paginate() -> Result<()> {
+    // Enterprise servers are read through REST rather than GraphQL.
+    let server = MockServer::start().await;
+    let api_url = format!("{}/api/v3", server.uri());
+    let repo_path = "/api/v3/repos/learner/quest";
+    mount_pages(
+      &server,
+      &format!("{repo_path}/issues"),
+      (1..=150).map(issue).collect(),
+    )
+    .await;
+    mount_pages(
+      &server,
+      &format!("{repo_path}/pulls"),
+      (151..=270).map(pr).collect(),
+    )
+    .await;
+    mount_pages(
+      &server,
+      &format!("{repo_path}/pulls/151/comments"),
+      (1..=101).map(comment).collect(),
+    )
+    .await;
+    Mock::given(method("GET"))
+      .and(path_regex(
+        r"^/api/v3/repos/learner/quest/pulls/\d+/comments$",
+      ))
+      .respond_with(ResponseTemplate::new(200).set_body_json(json!([])))
+      .mount(&server)
+      .await;
+    let labels = (1..=110)
+      .map(|id| json!(model::label(id, &format!("label-{id}"), "ffffff", None)))
+      .collect();
+    mount_pages(&server, &format!("{repo_path}/labels"), labels).await;
+
+    let gh = Octocrab::builder().base_uri(api_url.as_str())?.build()?;
+    let server = GithubServer {
+      host: HOST.into(),
+      api_url,
+    };
+    let repo = GithubRepo::with_client(Arc::new(gh), server, "learner", "quest");
+
+    assert!(repo.fetch().await?);
+    assert_eq!(repo.issues().len(), 150);
+    assert_eq!(repo.prs().len(), 120);
+    let pr = repo.pr(&PullSelector::Branch("feature".into())).unwrap();
+    assert_eq!(pr.comments.len(), 101);
+    drop(pr);
+
+    let (prs, issues) = repo.list_all().await?.unwrap();
+    assert_eq!((prs.len(), issues.len()), (120, 150));
+    assert_eq!(repo.labels().await?.len(), 110);
+    Ok(())
+  }
+
+  fn updated(day: u32) -> String {
+    format!("2024-01-{day:02}T00:00:00Z")
+  }
+
+  fn pr_node(number: u64, day: u32, threads: Value) -> Value {
+    json!({
+      "number": number,
+      "title": format!("PR {number}"),
+      "body": "body",
+      "url": format!("https://github.com/learner/quest/pull/{number}"),
+      "state": "MERGED",
+      "createdAt": updated(1),
+      "updatedAt": updated(day),
+      "mergedAt": updated(day),
+      "headRefName": format!("branch-{number}"),
+      "headRefOid": "abc",
+      "baseRefName": "main",
+      "author": { "login": "learner" },
+      "labels": {
+        "pageInfo": { "hasNextPage": false },
+        "nodes": [{ "name": "chapter-1", "color": "ffffff", "description": null }],
+      },
+      "reviewThreads": threads,
+    })
+  }
+
+  fn thread(ids: &[u64]) -> Value {
+    let comments = ids
+      .iter()
+      .map(|id| {
+        json!({
+          "databaseId": id,
+          "path": "src/lib.rs",
+          "body": format!("comment {id}"),
+          "line": 1,
+          "url": "https://github.com/learner/quest/pull/1",
+          "commit": { "oid": "abc" },
+          "author": null,
+        })
+      })
+      .collect::<Vec<_>>();
+    json!({ "comments": { "pageInfo": { "hasNextPage": false }, "nodes": comments } })
+  }
+
+  fn issue_node(number: u64, day: u32, state: &str) -> Value {
+    json!({
+      "number": number,
+      "title": format!("Issue {number}"),
+      "body": "body",
+      "url": format!("https://github.com/learner/quest/issues/{number}"),
+      "state": state,
+      "createdAt": updated(1),
+      "updatedAt": updated(day),
+      "author": { "login": "learner" },
+      "labels": { "pageInfo": { "hasNextPage": false }, "nodes": [] },
+    })
+  }
+
+  fn page(has_next_page: bool, cursor: &str, nodes: Vec<Value>) -> Value {
+    json!({
+      "pageInfo": { "hasNextPage": has_next_page, "endCursor": cursor },
+      "nodes": nodes,
+    })
+  }
+
+  #[tokio::test]
+  async fn query_graphql() -> Result<()> {
+    let server = MockServer::start().await;
+    let threads = json!({
+      "pageInfo": { "hasNextPage": false },
+      "nodes": [thread(&[3, 1]), thread(&[2])],
+    });
+    // This issue has too many labels to fit, so they are read through REST instead.
+    let mut issue = issue_node(1, 1, "CLOSED");
+    issue["labels"] = json!({ "pageInfo": { "hasNextPage": true }, "nodes": [] });
+    Mock::given(method("POST"))
+      .and(path("/graphql"))
+      .and(body_partial_json(json!({
+        "variables": { "name": "quest", "prs": null, "withIssues": true }
+      })))
+      .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+        "data": { "repository": {
+          "pullRequests": page(true, "next", vec![pr_node(3, 3, threads)]),
+          "issues": page(false, "end", vec![issue]),
+        } }
+      })))
+      .expect(1)
+      .mount(&server)
+      .await;
+    // This PR has too many comments to fit, so they are read through REST instead.
+    let truncated = json!({ "pageInfo": { "hasNextPage": true }, "nodes": [] });
+    Mock::given(method("POST"))
+      .and(path("/graphql"))
+      .and(body_partial_json(json!({
+        "variables": { "name": "quest", "prs": "next", "withIssues": false }
+      })))
+      .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+        "data": { "repository": {
+          "pullRequests": page(false, "end", vec![pr_node(2, 2, truncated)]),
+        } }
+      })))
+      .expect(1)
+      .mount(&server)
+      .await;
+    let labels = (1..=21)
+      .map(|id| json!(model::label(id, &format!("label-{id}"), "ffffff", None)))
+      .collect::<Vec<_>>();
+    Mock::given(method("GET"))
+      .and(path("/repos/learner/quest/issues/1/labels"))
+      .respond_with(ResponseTemplate::new(200).set_body_json(labels))
+      .expect(1)
+      .mount(&server)
+      .await;
+    Mock::given(method("GET"))
+      .and(path("/repos/learner/quest/pulls/2/comments"))
+      .respond_with(ResponseTemplate::new(200).set_body_json(json!([comment(4)])))
+      .expect(1)
+      .mount(&server)
+      .await;
+    Mock::given(method("POST"))
+      .and(path("/graphql"))
+      .and(body_partial_json(
+        json!({ "variables": { "name": "missing" } }),
+      ))
+      .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+        "data": { "repository": null },
+        "errors": [{ "type": "NOT_FOUND", "message": "Could not resolve to a Repository" }],
+      })))
+      .mount(&server)
+      .await;
+    // Without a cursor the next page can't be read, so the query fails rather than restarting.
+    let no_cursor = json!({ "pageInfo": { "hasNextPage": true, "endCursor": null }, "nodes": [] });
+    Mock::given(method("POST"))
+      .and(path("/graphql"))
+      .and(body_partial_json(
+        json!({ "variables": { "name": "no-cursor" } }),
+      ))
+      .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+        "data": { "repository": { "pullRequests": no_cursor, "issues": page(false, "end", vec![]) } }
+      })))
+      .expect(1)
+      .mount(&server)
+      .await;
+
+    let gh = Arc::new(Octocrab::builder().base_uri(server.uri())?.build()?);
+    let repo =
+      GithubRepo::with_client(Arc::clone(&gh), GithubServer::default(), "learner", "quest");
+    assert!(repo.fetch().await?);
+
+    let prs = repo.prs();
+    assert_eq!(prs.len(), 2);
+    assert_eq!(prs[0].data.head, "branch-3");
+    assert_eq!(prs[0].data.created_at, updated(1).parse::<DateTime<Utc>>()?);
+    assert_eq!(prs[0].data.merged_at, Some(updated(3).parse()?));
+    let ids = prs[0].comments.iter().map(|c| c.id).collect::<Vec<_>>();
+    assert_eq!(ids, [1, 2, 3]);
+    assert_eq!(prs[1].comments[0].id, 4);
+    drop(prs);
+    assert_eq!(repo.issues()[0].state, IssueState::Closed);
+    assert_eq!(repo.issues()[0].labels.len(), 21);
+
+    let missing = GithubRepo::with_client(
+      Arc::clone(&gh),
+      GithubServer::default(),
+      "learner",
+      "missing",
+    );
+    assert!(!missing.fetch().await?);
+
+    let no_cursor = GithubRepo::with_client(gh, GithubServer::default(), "learner", "no-cursor");
+    assert!(no_cursor.fetch().await.is_err());
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn refresh_changes() -> Result<()> {
+    let server = MockServer::start().await;
+    let no_threads = || json!({ "pageInfo": { "hasNextPage": false }, "nodes": [] });
+    Mock::given(method("POST"))
+      .and(path("/graphql"))
+      .and(body_partial_json(json!({ "variables": { "since": null } })))
+      .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+        "data": { "repository": {
+          "pullRequests": page(false, "end", vec![
+            pr_node(3, 3, no_threads()),
+            pr_node(2, 2, no_threads()),
+          ]),
+          "issues": page(false, "end", vec![issue_node(1, 1, "OPEN")]),
+        } }
+      })))
+      .expect(1)
+      .mount(&server)
+      .await;
+    // Github filters issues by `since`, but PRs are read until the first unchanged one.
+    let threads = json!({ "pageInfo": { "hasNextPage": false }, "nodes": [thread(&[5])] });
+    Mock::given(method("POST"))
+      .and(path("/graphql"))
+      .and(body_partial_json(json!({
+        "variables": { "since": updated(3), "prs": null }
+      })))
+      .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+        "data": { "repository": {
+          "pullRequests": page(true, "next", vec![
+            pr_node(3, 4, threads),
+            pr_node(2, 2, no_threads()),
+          ]),
+          "issues": page(false, "end", vec![issue_node(4, 4, "OPEN")]),
+        } }
+      })))
+      .expect(1)
+      .mount(&server)
+      .await;
+    Mock::given(method("POST"))
+      .and(path("/graphql"))
+      .and(body_partial_json(json!({ "variables": { "prs": "next" } })))
+      .respond_with(ResponseTemplate::new(500))
+      .expect(0)
+      .mount(&server)
+      .await;
+    // Before querying for changes, the repo's last update is checked through REST.
+    let mut issue = serde_json::to_value(
+      model::IssueFields {
+        number: 4,
+        title: "Issue 4",
+        body: Some("body"),
+        labels: &[],
+        state: models::IssueState::Open,
+        author: "learner",
+        html_url: "https://github.com/learner/quest/issues/4",
+      }
+      .build(),
+    )
+    .unwrap();
+    issue["updated_at"] = json!(updated(4));
+    Mock::given(method("GET"))
+      .and(path("/repos/learner/quest/issues"))
+      .and(query_param("sort", "updated"))
+      .and(query_param("per_page", "1"))
+      .respond_with(ResponseTemplate::new(200).set_body_json(json!([issue])))
+      .expect(2)
+      .mount(&server)
+      .await;
+
+    let gh = Octocrab::builder().base_uri(server.uri())?.build()?;
+    let repo = GithubRepo::with_client(Arc::new(gh), GithubServer::default(), "learner", "quest");
+    assert!(repo.fetch().await?);
+    assert!(repo.prs()[0].comments.is_empty());
+
+    assert!(repo.fetch().await?);
+    // Nothing changed since, so there is nothing to query.
+    assert!(repo.fetch().await?);
+    let prs = repo.prs();
+    let numbers = prs.iter().map(|pr| pr.data.number).collect::<Vec<_>>();
+    assert_eq!(numbers, [3, 2]);
+    assert_eq!(prs[0].comments.len(), 1);
+    drop(prs);
+    let numbers = repo
+      .issues()
+      .iter()
+      .map(|issue| issue.number)
+      .collect::<Vec<_>>();
+    assert_eq!(numbers, [4, 1]);
+    Ok(())
+  }
+}
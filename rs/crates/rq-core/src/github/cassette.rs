@@ -0,0 +1,525 @@
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use http::{
+  header::{SET_COOKIE, USER_AGENT},
+  HeaderName, HeaderValue, Request, Response, StatusCode, Uri,
+};
+use http_body::Body;
+use http_body_util::{BodyExt, Full};
+use hyper_rustls::HttpsConnector;
+use hyper_util::{
+  client::legacy::{connect::HttpConnector, Client},
+  rt::TokioExecutor,
+};
+use octocrab::{
+  service::middleware::{
+    auth_header::AuthHeaderLayer, base_uri::BaseUriLayer, extra_headers::ExtraHeadersLayer,
+  },
+  AuthState, Octocrab, OctocrabBuilder,
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::BTreeMap,
+  fs,
+  future::Future,
+  path::{Path, PathBuf},
+  pin::Pin,
+  sync::Arc,
+  task::{Context as TaskContext, Poll},
+};
+use tower::{BoxError, Service};
+
+pub const GITHUB_API: &str = "https://api.github.com";
+
+/// A request or response body. JSON bodies are stored as JSON so cassettes are easy to read and edit.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordedBody {
+  Json(serde_json::Value),
+  Text(String),
+}
+
+impl RecordedBody {
+  fn from_bytes(bytes: &[u8]) -> Option<Self> {
+    if bytes.is_empty() {
+      return None;
+    }
+    Some(match serde_json::from_slice(bytes) {
+      Ok(json) => RecordedBody::Json(json),
+      Err(_) => RecordedBody::Text(String::from_utf8_lossy(bytes).into_owned()),
+    })
+  }
+
+  fn to_bytes(&self) -> Bytes {
+    match self {
+      RecordedBody::Json(json) => Bytes::from(json.to_string()),
+      RecordedBody::Text(text) => Bytes::from(text.clone()),
+    }
+  }
+}
+
+/// A request as sent to the API. Headers are not recorded, so cassettes never contain credentials.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RecordedRequest {
+  pub method: String,
+  /// Path and query of the request, so a cassette can be replayed against any base URI.
+  pub uri: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub body: Option<RecordedBody>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecordedResponse {
+  pub status: u16,
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub headers: BTreeMap<String, String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub body: Option<RecordedBody>,
+}
+
+impl RecordedResponse {
+  pub fn json(status: u16, body: serde_json::Value) -> Self {
+    RecordedResponse {
+      status,
+      headers: BTreeMap::from([("content-type".into(), "application/json".into())]),
+      body: Some(RecordedBody::Json(body)),
+    }
+  }
+
+  fn to_response(&self) -> Result<Response<Full<Bytes>>, BoxError> {
+    let body = self.body.as_ref().map(RecordedBody::to_bytes);
+    let mut response = Response::new(Full::new(body.unwrap_or_default()));
+    *response.status_mut() = StatusCode::from_u16(self.status)?;
+    for (key, value) in &self.headers {
+      response.headers_mut().append(
+        HeaderName::try_from(key.as_str())?,
+        HeaderValue::try_from(value.as_str())?,
+      );
+    }
+    Ok(response)
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Interaction {
+  pub request: RecordedRequest,
+  pub response: RecordedResponse,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct CassetteFile {
+  interactions: Vec<Interaction>,
+}
+
+struct CassetteState {
+  interactions: Mutex<Vec<Interaction>>,
+  path: Option<PathBuf>,
+}
+
+/// A log of HTTP interactions with the Github API.
+///
+/// A recording client appends every request it makes to the cassette, and a replaying client
+/// answers requests from the cassette without touching the network. Replayed requests are
+/// matched by method, URI and body, taking the earliest unused interaction, so polling the
+/// same endpoint replays its responses in the order they were recorded.
+#[derive(Clone)]
+pub struct Cassette(Arc<CassetteState>);
+
+impl Default for Cassette {
+  fn default() -> Self {
+    Cassette::new(Vec::new())
+  }
+}
+
+impl Cassette {
+  pub fn new(interactions: Vec<Interaction>) -> Self {
+    Cassette(Arc::new(CassetteState {
+      interactions: Mutex::new(interactions),
+      path: None,
+    }))
+  }
+
+  pub fn load(path: &Path) -> Result<Self> {
+    let contents = fs::read_to_string(path)
+      .with_context(|| format!("Failed to read cassette: {}", path.display()))?;
+    let file: CassetteFile = serde_json::from_str(&contents)
+      .with_context(|| format!("Failed to parse cassette: {}", path.display()))?;
+    Ok(Cassette::new(file.interactions))
+  }
+
+  /// Creates an empty cassette that is written to `path` after every recorded interaction,
+  /// so the recording survives a crash.
+  pub fn recording_to(path: &Path) -> Self {
+    Cassette(Arc::new(CassetteState {
+      interactions: Mutex::new(Vec::new()),
+      path: Some(path.to_path_buf()),
+    }))
+  }
+
+  pub fn save(&self, path: &Path) -> Result<()> {
+    let file = CassetteFile {
+      interactions: self.interactions(),
+    };
+    fs::write(path, serde_json::to_string_pretty(&file)?)
+      .with_context(|| format!("Failed to write cassette: {}", path.display()))
+  }
+
+  pub fn interactions(&self) -> Vec<Interaction> {
+    self.0.interactions.lock().clone()
+  }
+
+  pub fn push(&self, request: RecordedRequest, response: RecordedResponse) {
+    self
+      .0
+      .interactions
+      .lock()
+      .push(Interaction { request, response });
+    if let Some(path) = &self.0.path {
+      if let Err(e) = self.save(path) {
+        tracing::warn!("{e:?}");
+      }
+    }
+  }
+
+  fn take(&self, request: &RecordedRequest) -> Option<Interaction> {
+    let mut interactions = self.0.interactions.lock();
+    let index = interactions
+      .iter()
+      .position(|interaction| &interaction.request == request)?;
+    Some(interactions.remove(index))
+  }
+
+  /// Returns an Octocrab instance that answers every request from this cassette.
+  pub fn replay_client(&self, base_uri: &str) -> Result<Octocrab> {
+    let (base_uri, headers, auth) = layers(base_uri, None)?;
+    let client = OctocrabBuilder::new_empty()
+      .with_service(ReplayService(self.clone()))
+      .with_layer(&base_uri)
+      .with_layer(&headers)
+      .with_layer(&auth)
+      .with_auth(AuthState::None)
+      .build()?;
+    Ok(client)
+  }
+
+  /// Returns an Octocrab instance that talks to the real API and records into this cassette.
+  pub fn record_client(&self, token: &str, base_uri: &str) -> Result<Octocrab> {
+    let service = RecordService {
+      inner: Client::builder(TokioExecutor::new()).build(connector()?),
+      cassette: self.clone(),
+    };
+    let (base_uri, headers, auth) = layers(base_uri, Some(token))?;
+    let client = OctocrabBuilder::new_empty()
+      .with_service(service)
+      .with_layer(&base_uri)
+      .with_layer(&headers)
+      .with_layer(&auth)
+      .with_auth(AuthState::None)
+      .build()?;
+    Ok(client)
+  }
+}
+
+pub(super) fn connector() -> Result<HttpsConnector<HttpConnector>> {
+  let connector = hyper_rustls::HttpsConnectorBuilder::new()
+    .with_native_roots()
+    .context("Failed to load TLS root certificates")?
+    .https_or_http()
+    .enable_http1()
+    .build();
+  Ok(connector)
+}
+
+// Octocrab's request body type is private, so we can't write a function generic over services
+// that accept it. Instead, each client is assembled by hand from the same layers.
+pub(super) fn layers(
+  base_uri: &str,
+  token: Option<&str>,
+) -> Result<(BaseUriLayer, ExtraHeadersLayer, AuthHeaderLayer)> {
+  let base_uri = base_uri
+    .parse::<Uri>()
+    .with_context(|| format!("Invalid API URI: {base_uri}"))?;
+  let headers = vec![(USER_AGENT, HeaderValue::from_static("octocrab"))];
+  let auth = token
+    .map(|token| HeaderValue::try_from(format!("Bearer {token}")))
+    .transpose()
+    .context("Invalid token")?;
+  Ok((
+    BaseUriLayer::new(base_uri.clone()),
+    ExtraHeadersLayer::new(Arc::new(headers)),
+    AuthHeaderLayer::new(auth, base_uri.clone(), base_uri),
+  ))
+}
+
+/// Buffers a request body so it can be both recorded and sent.
+async fn buffer_request<B>(
+  request: Request<B>,
+) -> Result<(RecordedRequest, Request<Full<Bytes>>), BoxError>
+where
+  B: Body<Data = Bytes>,
+  B::Error: Into<BoxError>,
+{
+  let (parts, body) = request.into_parts();
+  let bytes = body.collect().await.map_err(Into::into)?.to_bytes();
+  let recorded = RecordedRequest {
+    method: parts.method.to_string(),
+    // Octocrab leaves a dangling `?` on requests with no query parameters.
+    uri: parts
+      .uri
+      .path_and_query()
+      .map_or(parts.uri.path(), |path| path.as_str())
+      .trim_end_matches('?')
+      .to_string(),
+    body: RecordedBody::from_bytes(&bytes),
+  };
+  Ok((recorded, Request::from_parts(parts, Full::new(bytes))))
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = Result<T, BoxError>> + Send>>;
+
+#[derive(Clone)]
+struct ReplayService(Cassette);
+
+impl<B> Service<Request<B>> for ReplayService
+where
+  B: Body<Data = Bytes> + Send + 'static,
+  B::Error: Into<BoxError>,
+{
+  type Response = Response<Full<Bytes>>;
+  type Error = BoxError;
+  type Future = BoxFuture<Self::Response>;
+
+  fn poll_ready(&mut self, _cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn call(&mut self, request: Request<B>) -> Self::Future {
+    let cassette = self.0.clone();
+    Box::pin(async move {
+      let (request, _) = buffer_request(request).await?;
+      let interaction = cassette.take(&request).ok_or_else(|| {
+        BoxError::from(format!(
+          "No recorded response for: {} {}",
+          request.method, request.uri
+        ))
+      })?;
+      interaction.response.to_response()
+    })
+  }
+}
+
+#[derive(Clone)]
+struct RecordService<S> {
+  inner: S,
+  cassette: Cassette,
+}
+
+impl<S, B, RB> Service<Request<B>> for RecordService<S>
+where
+  S: Service<Request<Full<Bytes>>, Response = Response<RB>> + Clone + Send + 'static,
+  S::Future: Send,
+  S::Error: Into<BoxError>,
+  B: Body<Data = Bytes> + Send + 'static,
+  B::Error: Into<BoxError>,
+  RB: Body<Data = Bytes> + Send,
+  RB::Error: Into<BoxError>,
+{
+  type Response = Response<Full<Bytes>>;
+  type Error = BoxError;
+  type Future = BoxFuture<Self::Response>;
+
+  fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx).map_err(Into::into)
+  }
+
+  fn call(&mut self, request: Request<B>) -> Self::Future {
+    // Use the service that was polled ready, leaving a fresh clone in its place.
+    let clone = self.inner.clone();
+    let mut inner = std::mem::replace(&mut self.inner, clone);
+    let cassette = self.cassette.clone();
+    Box::pin(async move {
+      let (recorded, request) = buffer_request(request).await?;
+      let response = inner.call(request).await.map_err(Into::into)?;
+      let (parts, body) = response.into_parts();
+      let bytes = body.collect().await.map_err(Into::into)?.to_bytes();
+      let headers = parts
+        .headers
+        .iter()
+        .filter(|(key, _)| *key != SET_COOKIE)
+        .filter_map(|(key, value)| Some((key.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+      cassette.push(
+        recorded,
+        RecordedResponse {
+          status: parts.status.as_u16(),
+          headers,
+          body: RecordedBody::from_bytes(&bytes),
+        },
+      );
+      Ok(Response::from_parts(parts, Full::new(bytes)))
+    })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    forge::{
+      model::{self, IssueFields, PullRequestFields},
+      Forge, ForgeHost,
+    },
+    github::{GithubHost, GithubRepo, GithubServer},
+  };
+  use octocrab::models::IssueState;
+  use serde_json::json;
+  use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+  };
+
+  const URL: &str = "https://github.com/learner/quest";
+
+  fn interaction(
+    method: &str,
+    uri: &str,
+    body: Option<serde_json::Value>,
+    response: RecordedResponse,
+  ) -> Interaction {
+    Interaction {
+      request: RecordedRequest {
+        method: method.into(),
+        uri: uri.into(),
+        body: body.map(RecordedBody::Json),
+      },
+      response,
+    }
+  }
+
+  async fn mock_get(server: &MockServer, route: &str, body: serde_json::Value) {
+    Mock::given(method("GET"))
+      .and(path(route))
+      .respond_with(ResponseTemplate::new(200).set_body_json(body))
+      .mount(server)
+      .await;
+  }
+
+  #[tokio::test]
+  async fn record_then_replay() -> Result<()> {
+    let labels = [model::label(1, "chapter-1", "e11d21", None)];
+    let issue = IssueFields {
+      number: 1,
+      title: "Issue 1",
+      body: Some("Do the thing"),
+      labels: &labels,
+      state: IssueState::Open,
+      author: "learner",
+      html_url: URL,
+    }
+    .build();
+    let pr = PullRequestFields {
+      number: 2,
+      title: "Starter code",
+      body: Some("Here is some code"),
+      labels: &labels,
+      head: "chapter-1-a",
+      head_sha: "abc123",
+      base: "main",
+      state: IssueState::Open,
+      merged: false,
+      author: "learner",
+      html_url: URL,
+    }
+    .build();
+
+    // Use an Enterprise server, which is read through several REST requests.
+    let enterprise = GithubServer::for_host("github.example.edu");
+    let server = MockServer::start().await;
+    let repo_path = "/api/v3/repos/learner/quest";
+    mock_get(&server, &format!("{repo_path}/pulls"), json!([pr])).await;
+    mock_get(&server, &format!("{repo_path}/issues"), json!([issue])).await;
+    mock_get(&server, &format!("{repo_path}/pulls/2/comments"), json!([])).await;
+
+    let dir = tempfile::tempdir()?;
+    let cassette_path = dir.path().join("cassette.json");
+    let client = Cassette::recording_to(&cassette_path)
+      .record_client("secret", &format!("{}/api/v3", server.uri()))?;
+    let repo = GithubRepo::with_client(Arc::new(client), enterprise.clone(), "learner", "quest");
+    assert!(repo.fetch().await?);
+    drop(server);
+
+    let contents = fs::read_to_string(&cassette_path)?;
+    assert!(!contents.contains("secret"));
+
+    let cassette = Cassette::load(&cassette_path)?;
+    assert_eq!(cassette.interactions().len(), 3);
+    let client = cassette.replay_client(&enterprise.api_url)?;
+    let repo = GithubRepo::with_client(Arc::new(client), enterprise, "learner", "quest");
+    assert!(repo.fetch().await?);
+    assert_eq!(repo.issues()[0].title, "Issue 1");
+    assert_eq!(repo.prs()[0].data.number, 2);
+    assert!(cassette.interactions().is_empty());
+
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn generate_repo_waits_for_content() -> Result<()> {
+    let empty = RecordedResponse::json(
+      409,
+      json!({
+        "message": "Git Repository is empty.",
+        "documentation_url": "https://docs.github.com/rest"
+      }),
+    );
+    let cassette = Cassette::new(vec![
+      interaction(
+        "GET",
+        "/user",
+        None,
+        RecordedResponse::json(200, model::author("learner")),
+      ),
+      interaction(
+        "POST",
+        "/repos/teacher/quest/generate",
+        Some(json!({ "name": "quest", "owner": "learner" })),
+        RecordedResponse::json(201, json!({})),
+      ),
+      interaction("GET", "/repos/learner/quest/commits", None, empty.clone()),
+      interaction("GET", "/repos/learner/quest/commits", None, empty),
+      interaction(
+        "GET",
+        "/repos/learner/quest/commits",
+        None,
+        RecordedResponse::json(200, json!([])),
+      ),
+      interaction(
+        "PUT",
+        "/repos/learner/quest/subscription",
+        Some(json!({ "subscribed": false, "ignored": true })),
+        RecordedResponse::json(200, json!({})),
+      ),
+    ]);
+    let gh = Arc::new(cassette.replay_client(GITHUB_API)?);
+    let host = GithubHost::with_client(Arc::clone(&gh), GithubServer::default());
+    let template = GithubRepo::with_client(gh, GithubServer::default(), "teacher", "quest");
+    let repo = host.generate_repo(&template).await?;
+    assert_eq!(repo.user(), "learner");
+    assert!(cassette.interactions().is_empty());
+
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn replay_missing_interaction() -> Result<()> {
+    let cassette = Cassette::default();
+    let host = GithubHost::with_client(
+      Arc::new(cassette.replay_client(GITHUB_API)?),
+      GithubServer::default(),
+    );
+    let err = host.current_user().await.unwrap_err();
+    assert!(format!("{err:?}").contains("No recorded response for: GET /user"));
+    Ok(())
+  }
+}
@@ -0,0 +1,271 @@
+//! Makes Github API reads conditional, so that polling a quest whose PRs and issues haven't
+//! changed costs neither quota nor the time to download them again.
+//!
+//! Only REST reads can be conditional, since GraphQL queries are POSTs. Polling through
+//! GraphQL instead checks when the repo last changed through REST before querying it.
+//!
+//! See <https://docs.github.com/en/rest/using-the-rest-api/best-practices-for-using-the-rest-api#use-conditional-requests-if-appropriate>.
+
+use bytes::Bytes;
+use http::{
+  header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+  HeaderMap, Method, Request, Response, StatusCode,
+};
+use http_body::Body;
+use http_body_util::{BodyExt, Full};
+use parking_lot::Mutex;
+use std::{
+  collections::HashMap,
+  future::Future,
+  pin::Pin,
+  sync::Arc,
+  task::{Context, Poll},
+};
+use tower::{BoxError, Layer, Service};
+
+/// A successful response, kept until Github says it is stale.
+#[derive(Clone)]
+struct CachedResponse {
+  headers: HeaderMap,
+  body: Bytes,
+}
+
+impl CachedResponse {
+  fn to_response(&self) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(self.body.clone()));
+    *response.headers_mut() = self.headers.clone();
+    response
+  }
+}
+
+/// Most responses that a cache keeps. Polling repeats a handful of requests, so this leaves
+/// plenty of room for them while one-off requests are evicted.
+const CAPACITY: usize = 256;
+
+#[derive(Default)]
+struct CacheState {
+  /// Responses by URI, with when they were last used.
+  responses: HashMap<String, (u64, CachedResponse)>,
+  clock: u64,
+}
+
+/// The last successful response to the most recently used GET requests made through a
+/// client, by URI.
+#[derive(Clone)]
+pub struct ResponseCache {
+  state: Arc<Mutex<CacheState>>,
+  capacity: usize,
+}
+
+impl Default for ResponseCache {
+  fn default() -> Self {
+    ResponseCache::with_capacity(CAPACITY)
+  }
+}
+
+impl ResponseCache {
+  fn with_capacity(capacity: usize) -> Self {
+    ResponseCache {
+      state: Arc::default(),
+      capacity,
+    }
+  }
+
+  fn get(&self, uri: &str) -> Option<CachedResponse> {
+    let mut state = self.state.lock();
+    state.clock += 1;
+    let clock = state.clock;
+    let (used, response) = state.responses.get_mut(uri)?;
+    *used = clock;
+    Some(response.clone())
+  }
+
+  fn insert(&self, uri: String, response: CachedResponse) {
+    let mut state = self.state.lock();
+    state.clock += 1;
+    let clock = state.clock;
+    state.responses.insert(uri, (clock, response));
+    if state.responses.len() > self.capacity {
+      let oldest = (state.responses.iter())
+        .min_by_key(|(_, (used, _))| *used)
+        .map(|(uri, _)| uri.clone());
+      if let Some(oldest) = oldest {
+        state.responses.remove(&oldest);
+      }
+    }
+  }
+}
+
+/// Adds `If-None-Match` and `If-Modified-Since` to requests with a cached response, and
+/// answers them from the cache when Github responds 304 Not Modified.
+pub struct EtagCacheLayer(ResponseCache);
+
+impl EtagCacheLayer {
+  pub fn new(cache: ResponseCache) -> Self {
+    EtagCacheLayer(cache)
+  }
+}
+
+impl<S> Layer<S> for EtagCacheLayer {
+  type Service = EtagCacheService<S>;
+
+  fn layer(&self, inner: S) -> Self::Service {
+    EtagCacheService {
+      inner,
+      cache: self.0.clone(),
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct EtagCacheService<S> {
+  inner: S,
+  cache: ResponseCache,
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = Result<T, BoxError>> + Send>>;
+
+impl<S, B, RB> Service<Request<B>> for EtagCacheService<S>
+where
+  S: Service<Request<B>, Response = Response<RB>> + Send,
+  S::Future: Send + 'static,
+  S::Error: Into<BoxError>,
+  RB: Body<Data = Bytes> + Send,
+  RB::Error: Into<BoxError>,
+{
+  type Response = Response<Full<Bytes>>;
+  type Error = BoxError;
+  type Future = BoxFuture<Self::Response>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx).map_err(Into::into)
+  }
+
+  fn call(&mut self, mut request: Request<B>) -> Self::Future {
+    let key = (request.method() == Method::GET).then(|| request.uri().to_string());
+    let cached = key.as_ref().and_then(|key| self.cache.get(key));
+    if let Some(cached) = &cached {
+      let conditions = [(ETAG, IF_NONE_MATCH), (LAST_MODIFIED, IF_MODIFIED_SINCE)];
+      for (validator, condition) in conditions {
+        if let Some(value) = cached.headers.get(validator) {
+          request.headers_mut().insert(condition, value.clone());
+        }
+      }
+    }
+
+    let cache = self.cache.clone();
+    let future = self.inner.call(request);
+    Box::pin(async move {
+      let response = future.await.map_err(Into::into)?;
+      if let (StatusCode::NOT_MODIFIED, Some(cached)) = (response.status(), cached) {
+        return Ok(cached.to_response());
+      }
+
+      let (parts, body) = response.into_parts();
+      let body = body.collect().await.map_err(Into::into)?.to_bytes();
+      let has_validator =
+        parts.headers.contains_key(ETAG) || parts.headers.contains_key(LAST_MODIFIED);
+      if let (StatusCode::OK, Some(key), true) = (parts.status, key, has_validator) {
+        cache.insert(
+          key,
+          CachedResponse {
+            headers: parts.headers.clone(),
+            body: body.clone(),
+          },
+        );
+      }
+      Ok(Response::from_parts(parts, Full::new(body)))
+    })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::{
+    forge::{model, Forge},
+    github::{rate_limit::RateLimiter, GithubRepo, GithubServer},
+  };
+  use wiremock::{
+    matchers::{header, header_exists, method, path},
+    Mock, MockServer, ResponseTemplate,
+  };
+
+  #[tokio::test]
+  async fn not_modified() -> anyhow::Result<()> {
+    let server = MockServer::start().await;
+    let issue = model::IssueFields {
+      number: 1,
+      title: "Issue",
+      body: Some("body"),
+      labels: &[],
+      state: octocrab::models::IssueState::Open,
+      author: "learner",
+      html_url: "https://github.com/learner/quest/issues/1",
+    }
+    .build();
+    // Enterprise servers are polled through REST, where conditional requests apply.
+    let repo_path = "/api/v3/repos/learner/quest";
+    Mock::given(method("GET"))
+      .and(path(format!("{repo_path}/issues")))
+      .and(header("if-none-match", "\"issues\""))
+      .respond_with(ResponseTemplate::new(304))
+      .expect(1)
+      .mount(&server)
+      .await;
+    Mock::given(method("GET"))
+      .and(path(format!("{repo_path}/issues")))
+      .respond_with(
+        ResponseTemplate::new(200)
+          .set_body_json([issue])
+          .insert_header("etag", "\"issues\""),
+      )
+      .expect(1)
+      .mount(&server)
+      .await;
+    // Responses without validators are fetched in full every time.
+    Mock::given(method("GET"))
+      .and(path(format!("{repo_path}/pulls")))
+      .and(header_exists("if-none-match"))
+      .respond_with(ResponseTemplate::new(304))
+      .expect(0)
+      .mount(&server)
+      .await;
+    Mock::given(method("GET"))
+      .and(path(format!("{repo_path}/pulls")))
+      .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([])))
+      .expect(2)
+      .mount(&server)
+      .await;
+
+    let api_url = format!("{}/api/v3", server.uri());
+    let gh = crate::github::build_client("token", &api_url, RateLimiter::default(), None)?;
+    let server = GithubServer {
+      host: "github.example.edu".into(),
+      api_url,
+    };
+    let repo = GithubRepo::with_client(Arc::new(gh), server, "learner", "quest");
+    for _ in 0..2 {
+      let (_, issues) = repo.list_all().await?.unwrap();
+      assert_eq!(issues.len(), 1);
+      assert_eq!(issues[0].title, "Issue");
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn evicts_least_recently_used() {
+    let cache = ResponseCache::with_capacity(2);
+    let response = || CachedResponse {
+      headers: HeaderMap::new(),
+      body: Bytes::new(),
+    };
+    cache.insert("/a".into(), response());
+    cache.insert("/b".into(), response());
+    assert!(cache.get("/a").is_some());
+    cache.insert("/c".into(), response());
+    assert!(cache.get("/a").is_some());
+    assert!(cache.get("/b").is_none());
+    assert!(cache.get("/c").is_some());
+  }
+}
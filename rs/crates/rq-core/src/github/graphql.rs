@@ -0,0 +1,342 @@
+//! Fetches a repo's PRs, their review comments and its issues in a single GraphQL query,
+//! rather than one REST request per PR.
+//!
+//! Both lists are ordered by when they were last updated, so that a refresh can stop reading
+//! once it reaches the items that haven't changed since the previous one.
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use octocrab::Octocrab;
+use serde::Deserialize;
+use serde_json::json;
+use std::cmp::Reverse;
+
+use crate::forge::{Issue, IssueState, PullRequest, ReviewComment};
+
+// Page sizes are chosen to keep the query under Github's limit of 500,000 nodes.
+const QUERY: &str = r#"
+query(
+  $owner: String!, $name: String!, $withComments: Boolean!, $since: DateTime,
+  $withPrs: Boolean!, $prs: String, $withIssues: Boolean!, $issues: String
+) {
+  repository(owner: $owner, name: $name) {
+    pullRequests(first: 50, after: $prs, orderBy: {field: UPDATED_AT, direction: DESC})
+      @include(if: $withPrs) {
+      pageInfo { hasNextPage endCursor }
+      nodes {
+        number title body url state createdAt updatedAt mergedAt headRefName baseRefName
+        labels(first: 20) { pageInfo { hasNextPage } nodes { name } }
+        reviewThreads(first: 100) @include(if: $withComments) {
+          pageInfo { hasNextPage }
+          nodes {
+            comments(first: 20) {
+              pageInfo { hasNextPage }
+              nodes { databaseId path body line }
+            }
+          }
+        }
+      }
+    }
+    issues(
+      first: 100, after: $issues, orderBy: {field: UPDATED_AT, direction: DESC},
+      filterBy: {since: $since}
+    ) @include(if: $withIssues) {
+      pageInfo { hasNextPage endCursor }
+      nodes {
+        number title body url state createdAt updatedAt
+        labels(first: 20) { pageInfo { hasNextPage } nodes { name } }
+      }
+    }
+  }
+}
+"#;
+
+#[derive(Deserialize)]
+struct Response {
+  data: Option<Data>,
+  #[serde(default)]
+  errors: Vec<Error>,
+}
+
+#[derive(Deserialize)]
+struct Error {
+  #[serde(rename = "type")]
+  kind: Option<String>,
+  message: String,
+}
+
+#[derive(Deserialize)]
+struct Data {
+  repository: Option<Repository>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Repository {
+  pull_requests: Option<Connection<PrNode>>,
+  issues: Option<Connection<IssueNode>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Connection<T> {
+  page_info: PageInfo,
+  nodes: Vec<T>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PageInfo {
+  has_next_page: bool,
+  end_cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LabelNode {
+  name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LabelPageInfo {
+  has_next_page: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LabelConnection {
+  page_info: LabelPageInfo,
+  nodes: Vec<LabelNode>,
+}
+
+impl LabelConnection {
+  fn names(&self) -> Vec<String> {
+    self.nodes.iter().map(|label| label.name.clone()).collect()
+  }
+}
+
+fn issue_state(state: &str) -> IssueState {
+  match state {
+    "OPEN" => IssueState::Open,
+    _ => IssueState::Closed,
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PrNode {
+  number: u64,
+  title: String,
+  body: String,
+  url: String,
+  state: String,
+  created_at: DateTime<Utc>,
+  updated_at: DateTime<Utc>,
+  merged_at: Option<DateTime<Utc>>,
+  head_ref_name: String,
+  base_ref_name: String,
+  labels: LabelConnection,
+  review_threads: Option<Connection<ThreadNode>>,
+}
+
+#[derive(Deserialize)]
+struct ThreadNode {
+  comments: Connection<CommentNode>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CommentNode {
+  database_id: u64,
+  path: String,
+  body: String,
+  line: Option<u64>,
+}
+
+impl CommentNode {
+  fn build(&self) -> ReviewComment {
+    ReviewComment {
+      id: self.database_id,
+      path: self.path.clone(),
+      line: self.line,
+      body: self.body.clone(),
+    }
+  }
+}
+
+/// A PR from the query, with its review comments if they fit in the query.
+pub struct QueriedPr {
+  pub data: PullRequest,
+  /// `None` if the comments were not queried, or if there were too many to fit.
+  pub comments: Option<Vec<ReviewComment>>,
+  /// Whether the PR has more labels than fit in the query, so `data` is missing some.
+  pub labels_truncated: bool,
+}
+
+/// An issue from the query.
+pub struct QueriedIssue {
+  pub data: Issue,
+  /// Whether the issue has more labels than fit in the query, so `data` is missing some.
+  pub labels_truncated: bool,
+}
+
+impl PrNode {
+  fn build(&self) -> QueriedPr {
+    let data = PullRequest {
+      number: self.number,
+      title: self.title.clone(),
+      body: Some(self.body.clone()),
+      labels: self.labels.names(),
+      head: self.head_ref_name.clone(),
+      base: self.base_ref_name.clone(),
+      state: issue_state(&self.state),
+      html_url: self.url.clone(),
+      created_at: self.created_at,
+      updated_at: self.updated_at,
+      merged_at: self.merged_at,
+    };
+
+    let comments = self.review_threads.as_ref().and_then(|threads| {
+      let truncated = threads.page_info.has_next_page
+        || threads
+          .nodes
+          .iter()
+          .any(|thread| thread.comments.page_info.has_next_page);
+      if truncated {
+        return None;
+      }
+      let mut comments = threads
+        .nodes
+        .iter()
+        .flat_map(|thread| thread.comments.nodes.iter().map(CommentNode::build))
+        .collect::<Vec<_>>();
+      // Match the REST API, which lists comments in the order they were made.
+      comments.sort_by_key(|comment| comment.id);
+      Some(comments)
+    });
+
+    QueriedPr {
+      data,
+      comments,
+      labels_truncated: self.labels.page_info.has_next_page,
+    }
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IssueNode {
+  number: u64,
+  title: String,
+  body: String,
+  url: String,
+  state: String,
+  created_at: DateTime<Utc>,
+  updated_at: DateTime<Utc>,
+  labels: LabelConnection,
+}
+
+impl IssueNode {
+  fn build(&self) -> QueriedIssue {
+    QueriedIssue {
+      data: Issue {
+        number: self.number,
+        title: self.title.clone(),
+        body: Some(self.body.clone()),
+        labels: self.labels.names(),
+        state: issue_state(&self.state),
+        html_url: self.url.clone(),
+        created_at: self.created_at,
+        updated_at: self.updated_at,
+      },
+      labels_truncated: self.labels.page_info.has_next_page,
+    }
+  }
+}
+
+/// Returns the cursor of the page after `page_info`, or `None` if there is none or it isn't
+/// `wanted`. Github should always give a cursor when there is a next page, but without one
+/// the query would start over from the first page.
+fn next_page(page_info: PageInfo, wanted: bool) -> Result<Option<Option<String>>> {
+  if !(wanted && page_info.has_next_page) {
+    return Ok(None);
+  }
+  let cursor = page_info
+    .end_cursor
+    .context("Github listed another page without a cursor to read it")?;
+  Ok(Some(Some(cursor)))
+}
+
+/// Returns the PRs and issues in `owner/name` updated since `since` (or all of them),
+/// most recently created first, or `None` if the repo does not exist.
+/// `route` is where the client reaches the GraphQL API.
+pub async fn query(
+  gh: &Octocrab,
+  route: &str,
+  owner: &str,
+  name: &str,
+  with_comments: bool,
+  since: Option<DateTime<Utc>>,
+) -> Result<Option<(Vec<QueriedPr>, Vec<QueriedIssue>)>> {
+  let (mut prs, mut issues) = (Vec::new(), Vec::new());
+  // The cursor of the next page of each list, while there are pages left.
+  let (mut pr_cursor, mut issue_cursor) = (Some(None::<String>), Some(None::<String>));
+  while pr_cursor.is_some() || issue_cursor.is_some() {
+    let request = json!({
+      "query": QUERY,
+      "variables": {
+        "owner": owner,
+        "name": name,
+        "withComments": with_comments,
+        "since": since,
+        "withPrs": pr_cursor.is_some(),
+        "prs": pr_cursor.clone().flatten(),
+        "withIssues": issue_cursor.is_some(),
+        "issues": issue_cursor.clone().flatten(),
+      },
+    });
+    let response: Response = gh
+      .post(route, Some(&request))
+      .await
+      .context("Failed to query PRs and issues")?;
+
+    let repository = response.data.and_then(|data| data.repository);
+    let Some(repository) = repository else {
+      if response.errors.is_empty()
+        || response
+          .errors
+          .iter()
+          .any(|error| error.kind.as_deref() == Some("NOT_FOUND"))
+      {
+        return Ok(None);
+      }
+      let messages = response.errors.into_iter().map(|error| error.message);
+      bail!(
+        "Failed to query PRs and issues: {}",
+        messages.collect::<Vec<_>>().join(", ")
+      );
+    };
+
+    pr_cursor = match repository.pull_requests {
+      Some(connection) => {
+        // PRs can't be filtered by update time, so stop at the first one that is too old.
+        let is_changed = |pr: &&PrNode| since.is_none_or(|since| pr.updated_at >= since);
+        let changed = connection.nodes.iter().take_while(is_changed).count();
+        prs.extend(connection.nodes[..changed].iter().map(PrNode::build));
+        next_page(connection.page_info, changed == connection.nodes.len())?
+      }
+      None => None,
+    };
+    issue_cursor = match repository.issues {
+      Some(connection) => {
+        issues.extend(connection.nodes.iter().map(IssueNode::build));
+        next_page(connection.page_info, true)?
+      }
+      None => None,
+    };
+  }
+
+  prs.sort_by_key(|pr| Reverse(pr.data.number));
+  issues.sort_by_key(|issue| Reverse(issue.data.number));
+  Ok(Some((prs, issues)))
+}
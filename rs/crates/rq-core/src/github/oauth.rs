@@ -0,0 +1,525 @@
+//! Github's OAuth device flow, which lets a learner log in through their browser instead of
+//! pasting a personal access token.
+//!
+//! See <https://docs.github.com/en/apps/oauth-apps/building-oauth-apps/authorizing-oauth-apps#device-flow>.
+
+use std::{
+  fs,
+  future::Future,
+  path::{Path, PathBuf},
+  pin::Pin,
+  sync::Arc,
+  task::{self, Poll},
+  time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use http::{
+  header::{ACCEPT, AUTHORIZATION},
+  HeaderValue, Request, Response, StatusCode,
+};
+use octocrab::{
+  auth::{DeviceCodes, OAuth},
+  Octocrab,
+};
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use specta::Type;
+use tokio::{sync::Mutex, time::timeout};
+use tower::{BoxError, Layer, Service, ServiceExt};
+
+use super::{rate_limit::clone_request, GithubServer};
+use crate::utils::write_private;
+
+/// Client ID of the RepoQuest OAuth app on github.com, provided when building releases.
+/// Enterprise servers need their own app, configured as `client_id` in `~/.rqst-github.toml`.
+const DEFAULT_CLIENT_ID: Option<&str> = option_env!("RQST_GITHUB_CLIENT_ID");
+
+const SCOPES: [&str; 1] = ["repo"];
+
+// Treat tokens as expired slightly early so they don't expire mid-request.
+const EXPIRY_MARGIN: TimeDelta = TimeDelta::minutes(5);
+
+/// An OAuth token persisted to `~/.rqst-oauth.toml`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct OAuthToken {
+  /// Host of the server that issued the token.
+  pub host: String,
+  pub access_token: String,
+  /// Tokens only expire if the OAuth app opts into token expiration.
+  pub expires_at: Option<DateTime<Utc>>,
+  pub refresh_token: Option<String>,
+  pub refresh_token_expires_at: Option<DateTime<Utc>>,
+}
+
+fn expires_at(now: DateTime<Utc>, seconds: Option<usize>) -> Option<DateTime<Utc>> {
+  seconds.map(|seconds| now + TimeDelta::seconds(seconds as i64))
+}
+
+fn is_past(time: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+  time.is_some_and(|time| time - EXPIRY_MARGIN <= now)
+}
+
+fn token_path() -> Result<PathBuf> {
+  let home = home::home_dir().context("Failed to find home directory")?;
+  Ok(home.join(".rqst-oauth.toml"))
+}
+
+impl OAuthToken {
+  fn new(host: &str, oauth: OAuth, now: DateTime<Utc>) -> Self {
+    OAuthToken {
+      host: host.to_string(),
+      access_token: oauth.access_token.expose_secret().to_string(),
+      expires_at: expires_at(now, oauth.expires_in),
+      refresh_token: oauth
+        .refresh_token
+        .map(|token| token.expose_secret().to_string()),
+      refresh_token_expires_at: expires_at(now, oauth.refresh_token_expires_in),
+    }
+  }
+
+  pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+    is_past(self.expires_at, now)
+  }
+
+  pub fn can_refresh(&self, now: DateTime<Utc>) -> bool {
+    self.refresh_token.is_some() && !is_past(self.refresh_token_expires_at, now)
+  }
+
+  /// Exchanges the refresh token for a new access token.
+  pub async fn refresh(&self, client: &OAuthClient) -> Result<Self> {
+    let refresh_token = self
+      .refresh_token
+      .as_ref()
+      .ok_or_else(|| anyhow!("Github token cannot be refreshed"))?;
+    let oauth: OAuth = client
+      .crab
+      .post(
+        "/login/oauth/access_token",
+        Some(&json!({
+          "client_id": client.client_id.expose_secret(),
+          "grant_type": "refresh_token",
+          "refresh_token": refresh_token,
+        })),
+      )
+      .await
+      .context("Failed to refresh Github token")?;
+    Ok(OAuthToken::new(&self.host, oauth, Utc::now()))
+  }
+
+  pub fn load() -> Result<Option<Self>> {
+    let path = token_path()?;
+    if !path.exists() {
+      return Ok(None);
+    }
+    let contents =
+      fs::read_to_string(&path).with_context(|| format!("Failed to read: {}", path.display()))?;
+    let token = toml::from_str(&contents)
+      .with_context(|| format!("Failed to parse OAuth token: {}", path.display()))?;
+    Ok(Some(token))
+  }
+
+  pub fn save(&self) -> Result<()> {
+    self.save_to(&token_path()?)
+  }
+
+  fn save_to(&self, path: &Path) -> Result<()> {
+    // Only the learner should be able to read their token.
+    write_private(path, &toml::to_string_pretty(self)?)
+  }
+}
+
+/// An unauthenticated client for a server's OAuth endpoints.
+pub struct OAuthClient {
+  crab: Octocrab,
+  client_id: SecretString,
+}
+
+impl OAuthClient {
+  /// `web_url` is where the server's website lives, e.g. `https://github.com`.
+  pub fn new(web_url: &str, client_id: &str) -> Result<Self> {
+    // The OAuth endpoints respond with form data unless asked for JSON.
+    let crab = Octocrab::builder()
+      .base_uri(web_url)
+      .with_context(|| format!("Invalid Github URL: {web_url}"))?
+      .add_header(ACCEPT, "application/json".to_string())
+      .build()
+      .context("Failed to build Github OAuth connector")?;
+    Ok(OAuthClient {
+      crab,
+      client_id: SecretString::from(client_id),
+    })
+  }
+
+  /// Returns a client for `server`, using the client ID from `client_id` if provided.
+  pub fn for_server(server: &GithubServer, client_id: Option<&str>) -> Result<Self> {
+    let client_id = client_id.or(DEFAULT_CLIENT_ID).ok_or_else(|| {
+      anyhow!("This build of RepoQuest has no Github OAuth app. Set `client_id` in ~/.rqst-github.toml, or use a personal access token.")
+    })?;
+    OAuthClient::new(&format!("https://{}", server.host), client_id)
+  }
+}
+
+/// The code that the user enters at the verification URL.
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct DeviceCode {
+  pub user_code: String,
+  pub verification_uri: String,
+  /// Seconds until the code expires.
+  pub expires_in: u64,
+}
+
+/// A device login waiting for the user to authorize RepoQuest.
+pub struct DeviceLogin {
+  client: OAuthClient,
+  server: GithubServer,
+  codes: DeviceCodes,
+}
+
+impl DeviceLogin {
+  pub async fn start(client: OAuthClient, server: GithubServer) -> Result<Self> {
+    let codes = client
+      .crab
+      .authenticate_as_device(&client.client_id, SCOPES)
+      .await
+      .context("Failed to start Github login")?;
+    Ok(DeviceLogin {
+      client,
+      server,
+      codes,
+    })
+  }
+
+  pub fn server(&self) -> &GithubServer {
+    &self.server
+  }
+
+  pub fn code(&self) -> DeviceCode {
+    DeviceCode {
+      user_code: self.codes.user_code.clone(),
+      verification_uri: self.codes.verification_uri.clone(),
+      expires_in: self.codes.expires_in,
+    }
+  }
+
+  /// Polls until the user authorizes RepoQuest or the code expires.
+  pub async fn finish(&self) -> Result<OAuthToken> {
+    let poll = self
+      .codes
+      .poll_until_available(&self.client.crab, &self.client.client_id);
+    let oauth = timeout(Duration::from_secs(self.codes.expires_in), poll)
+      .await
+      .context("Github login code expired")?
+      .context("Github login failed")?;
+    Ok(OAuthToken::new(&self.server.host, oauth, Utc::now()))
+  }
+}
+
+/// Keeps a device login's token fresh for the length of a session, since the token can
+/// expire while RepoQuest is still open.
+#[derive(Clone)]
+pub struct TokenRefresher(Arc<RefresherState>);
+
+struct RefresherState {
+  token: Mutex<OAuthToken>,
+  client: OAuthClient,
+  path: PathBuf,
+}
+
+impl TokenRefresher {
+  pub fn new(token: OAuthToken, client: OAuthClient) -> Result<Self> {
+    Ok(Self::saving_to(token, client, token_path()?))
+  }
+
+  fn saving_to(token: OAuthToken, client: OAuthClient, path: PathBuf) -> Self {
+    TokenRefresher(Arc::new(RefresherState {
+      token: Mutex::new(token),
+      client,
+      path,
+    }))
+  }
+
+  /// Returns the access token, first refreshing it if it has expired or if it is the token
+  /// that Github just `rejected`. If the refresh fails, the old token is returned so that the
+  /// request surfaces Github's error.
+  async fn access_token(&self, rejected: Option<&str>) -> String {
+    // Holding the lock across the refresh makes concurrent requests wait for one refresh,
+    // since Github only accepts each refresh token once.
+    let mut token = self.0.token.lock().await;
+    let now = Utc::now();
+    let stale = match rejected {
+      Some(rejected) => token.access_token == rejected,
+      None => token.is_expired(now),
+    };
+    if stale && token.can_refresh(now) {
+      let refreshed = match token.refresh(&self.0.client).await {
+        Ok(refreshed) => refreshed.save_to(&self.0.path).map(|()| refreshed),
+        Err(e) => Err(e),
+      };
+      match refreshed {
+        Ok(refreshed) => *token = refreshed,
+        Err(e) => tracing::warn!("{e:?}"),
+      }
+    }
+    token.access_token.clone()
+  }
+}
+
+/// Wraps a service to send the refresher's current token, refreshing it once if Github
+/// rejects a request as unauthorized.
+pub struct TokenRefreshLayer(Option<TokenRefresher>);
+
+impl TokenRefreshLayer {
+  /// Without a refresher, requests are sent unchanged.
+  pub fn new(refresher: Option<TokenRefresher>) -> Self {
+    TokenRefreshLayer(refresher)
+  }
+}
+
+impl<S> Layer<S> for TokenRefreshLayer {
+  type Service = TokenRefreshService<S>;
+
+  fn layer(&self, inner: S) -> Self::Service {
+    TokenRefreshService {
+      inner,
+      refresher: self.0.clone(),
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct TokenRefreshService<S> {
+  inner: S,
+  refresher: Option<TokenRefresher>,
+}
+
+// Only replaces the token of requests that were authorized to begin with, e.g. not of those
+// sent to another host.
+fn authorize<B: Clone>(request: &Request<B>, token: &str) -> Result<Request<B>, BoxError> {
+  let mut request = clone_request(request);
+  if request.headers().contains_key(AUTHORIZATION) {
+    let value = HeaderValue::try_from(format!("Bearer {token}"))?;
+    request.headers_mut().insert(AUTHORIZATION, value);
+  }
+  Ok(request)
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = Result<T, BoxError>> + Send>>;
+
+impl<S, B, RB> Service<Request<B>> for TokenRefreshService<S>
+where
+  S: Service<Request<B>, Response = Response<RB>> + Clone + Send + 'static,
+  S::Future: Send,
+  S::Error: Into<BoxError>,
+  B: Clone + Send + 'static,
+  RB: Send + 'static,
+{
+  type Response = Response<RB>;
+  type Error = BoxError;
+  type Future = BoxFuture<Self::Response>;
+
+  fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx).map_err(Into::into)
+  }
+
+  fn call(&mut self, request: Request<B>) -> Self::Future {
+    // Use the service that was polled ready, leaving a fresh clone in its place.
+    let clone = self.inner.clone();
+    let mut inner = std::mem::replace(&mut self.inner, clone);
+    let refresher = self.refresher.clone();
+    Box::pin(async move {
+      let Some(refresher) = refresher else {
+        return inner.call(request).await.map_err(Into::into);
+      };
+      let token = refresher.access_token(None).await;
+      let response = inner
+        .ready()
+        .await
+        .map_err(Into::into)?
+        .call(authorize(&request, &token)?)
+        .await
+        .map_err(Into::into)?;
+      if response.status() != StatusCode::UNAUTHORIZED {
+        return Ok(response);
+      }
+
+      // Github never applies unauthorized requests, so they are safe to send again.
+      let refreshed = refresher.access_token(Some(&token)).await;
+      if refreshed == token {
+        return Ok(response);
+      }
+      inner
+        .ready()
+        .await
+        .map_err(Into::into)?
+        .call(authorize(&request, &refreshed)?)
+        .await
+        .map_err(Into::into)
+    })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use wiremock::{
+    matchers::{body_partial_json, header, method, path},
+    Mock, MockServer, ResponseTemplate,
+  };
+
+  const DEVICE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+  fn token_response(token: &str) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(json!({
+      "access_token": token,
+      "token_type": "bearer",
+      "scope": "repo",
+      "expires_in": 28800,
+      "refresh_token": format!("{token}-refresh"),
+      "refresh_token_expires_in": 15897600,
+    }))
+  }
+
+  #[tokio::test]
+  async fn device_login() -> Result<()> {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+      .and(path("/login/device/code"))
+      .and(body_partial_json(
+        json!({ "client_id": "rqst", "scope": "repo" }),
+      ))
+      .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+        "device_code": "device",
+        "user_code": "ABCD-1234",
+        "verification_uri": "https://github.com/login/device",
+        "expires_in": 900,
+        "interval": 1,
+      })))
+      .mount(&server)
+      .await;
+    let poll = || body_partial_json(json!({ "device_code": "device", "grant_type": DEVICE_GRANT }));
+    Mock::given(method("POST"))
+      .and(path("/login/oauth/access_token"))
+      .and(poll())
+      .respond_with(
+        ResponseTemplate::new(200).set_body_json(json!({ "error": "authorization_pending" })),
+      )
+      .up_to_n_times(1)
+      .mount(&server)
+      .await;
+    Mock::given(method("POST"))
+      .and(path("/login/oauth/access_token"))
+      .and(poll())
+      .respond_with(token_response("token"))
+      .mount(&server)
+      .await;
+
+    let client = OAuthClient::new(&server.uri(), "rqst")?;
+    let login = DeviceLogin::start(client, GithubServer::default()).await?;
+    assert_eq!(login.code().user_code, "ABCD-1234");
+
+    let token = login.finish().await?;
+    assert_eq!(token.access_token, "token");
+    let now = Utc::now();
+    assert!(!token.is_expired(now));
+    assert!(token.is_expired(now + TimeDelta::hours(8)));
+    assert!(token.can_refresh(now + TimeDelta::hours(8)));
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn refresh() -> Result<()> {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+      .and(path("/login/oauth/access_token"))
+      .and(body_partial_json(json!({
+        "grant_type": "refresh_token",
+        "refresh_token": "old-refresh",
+      })))
+      .respond_with(token_response("new"))
+      .mount(&server)
+      .await;
+
+    let expired = OAuthToken {
+      host: "github.com".into(),
+      access_token: "old".into(),
+      expires_at: Some(Utc::now() - TimeDelta::hours(1)),
+      refresh_token: Some("old-refresh".into()),
+      refresh_token_expires_at: None,
+    };
+    assert!(expired.is_expired(Utc::now()));
+
+    let client = OAuthClient::new(&server.uri(), "rqst")?;
+    let token = expired.refresh(&client).await?;
+    assert_eq!(token.access_token, "new");
+    assert_eq!(token.refresh_token.as_deref(), Some("new-refresh"));
+    assert!(!token.is_expired(Utc::now()));
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn refresh_rejected_token() -> Result<()> {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+      .and(path("/login/oauth/access_token"))
+      .and(body_partial_json(json!({
+        "grant_type": "refresh_token",
+        "refresh_token": "old-refresh",
+      })))
+      .respond_with(token_response("new"))
+      .expect(1)
+      .mount(&server)
+      .await;
+    Mock::given(method("GET"))
+      .and(path("/user"))
+      .and(header("authorization", "Bearer old"))
+      .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+        "message": "Bad credentials",
+        "documentation_url": "https://docs.github.com/rest"
+      })))
+      .expect(1)
+      .mount(&server)
+      .await;
+    Mock::given(method("GET"))
+      .and(path("/user"))
+      .and(header("authorization", "Bearer new"))
+      .respond_with(
+        ResponseTemplate::new(200).set_body_json(crate::forge::model::author("learner")),
+      )
+      .expect(2)
+      .mount(&server)
+      .await;
+
+    // Github revoked the token before it was due to expire.
+    let revoked = OAuthToken {
+      host: "github.com".into(),
+      access_token: "old".into(),
+      expires_at: Some(Utc::now() + TimeDelta::hours(1)),
+      refresh_token: Some("old-refresh".into()),
+      refresh_token_expires_at: None,
+    };
+    let dir = tempfile::tempdir()?;
+    let token_path = dir.path().join("oauth.toml");
+    let client = OAuthClient::new(&server.uri(), "rqst")?;
+    let refresher = TokenRefresher::saving_to(revoked, client, token_path.clone());
+    let gh =
+      crate::github::build_client("old", &server.uri(), Default::default(), Some(refresher))?;
+    assert_eq!(gh.current().user().await?.login, "learner");
+    // Later requests use the refreshed token straight away.
+    assert_eq!(gh.current().user().await?.login, "learner");
+
+    let saved: OAuthToken = toml::from_str(&fs::read_to_string(&token_path)?)?;
+    assert_eq!(saved.access_token, "new");
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      assert_eq!(
+        fs::metadata(&token_path)?.permissions().mode() & 0o777,
+        0o600
+      );
+    }
+    Ok(())
+  }
+}
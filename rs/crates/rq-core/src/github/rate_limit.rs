@@ -0,0 +1,372 @@
+//! Backs off when Github rate limits RepoQuest, which happens regularly when a classroom of
+//! learners shares one IP address.
+//!
+//! See <https://docs.github.com/en/rest/using-the-rest-api/rate-limits-for-the-rest-api>.
+
+use chrono::{DateTime, TimeDelta, Utc};
+use http::{HeaderMap, Method, Request, Response, StatusCode};
+use parking_lot::Mutex;
+use std::{
+  collections::HashMap,
+  fmt,
+  future::Future,
+  pin::Pin,
+  sync::{Arc, LazyLock},
+  task::{Context, Poll},
+  time::Duration,
+};
+use tokio_retry::strategy::{jitter, ExponentialBackoff};
+use tower::{BoxError, Layer, Service, ServiceExt};
+
+use crate::forge::RateLimit;
+
+/// Longest that a request waits out rate limits in total, across its retries. Longer pauses
+/// fail the request instead.
+const MAX_WAIT: TimeDelta = TimeDelta::minutes(1);
+
+/// Github asks clients to wait at least a minute after a secondary rate limit.
+const SECONDARY_WAIT: TimeDelta = TimeDelta::minutes(1);
+
+const MAX_RETRIES: usize = 3;
+
+/// Delays before retrying a server error: up to 500ms, 1s, then 2s. Each delay is random so
+/// that a classroom of clients doesn't retry in lockstep.
+fn server_error_backoff() -> impl Iterator<Item = Duration> {
+  ExponentialBackoff::from_millis(2).factor(250).map(jitter)
+}
+
+/// Tracks the rate limit reported by a Github server across every request made through the
+/// clients that share it.
+#[derive(Clone, Default)]
+pub struct RateLimiter(Arc<Mutex<RateLimit>>);
+
+// Each server has its own rate limits.
+static LIMITERS: LazyLock<Mutex<HashMap<String, RateLimiter>>> = LazyLock::new(Mutex::default);
+
+fn header<T: std::str::FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+  headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+impl RateLimiter {
+  /// Returns the limiter shared by the clients of the server at `host`.
+  pub fn for_host(host: &str) -> Self {
+    LIMITERS.lock().entry(host.to_string()).or_default().clone()
+  }
+
+  /// Returns the rate limit, or `None` if Github has not reported one yet.
+  pub fn status(&self) -> Option<RateLimit> {
+    let limit = self.0.lock().clone();
+    (limit != RateLimit::default()).then_some(limit)
+  }
+
+  /// Records the quota reported by a response. Returns when to retry if the response is
+  /// a rate limit error.
+  fn observe(
+    &self,
+    status: StatusCode,
+    headers: &HeaderMap,
+    now: DateTime<Utc>,
+  ) -> Option<DateTime<Utc>> {
+    let remaining = header::<u32>(headers, "x-ratelimit-remaining");
+    let reset = header::<i64>(headers, "x-ratelimit-reset")
+      .and_then(|reset| DateTime::from_timestamp(reset, 0));
+    let retry_after = header::<i64>(headers, "retry-after").map(TimeDelta::seconds);
+
+    let mut limit = self.0.lock();
+    if remaining.is_some() {
+      limit.remaining = remaining;
+      limit.limit = header(headers, "x-ratelimit-limit");
+      limit.reset = reset;
+    }
+
+    // A 403 is only a rate limit if Github says so, since it also means missing permissions.
+    let limited = status == StatusCode::TOO_MANY_REQUESTS
+      || (status == StatusCode::FORBIDDEN && (retry_after.is_some() || remaining == Some(0)));
+    let resume = limited.then(|| match (retry_after, remaining, reset) {
+      (Some(retry_after), _, _) => now + retry_after,
+      (None, Some(0), Some(reset)) => reset,
+      _ => now + SECONDARY_WAIT,
+    });
+    limit.paused_until = resume;
+    resume
+  }
+}
+
+/// Returned instead of sending a request while Github would refuse it.
+#[derive(Debug)]
+pub struct RateLimited {
+  pub until: DateTime<Utc>,
+}
+
+impl fmt::Display for RateLimited {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "Github rate limit exceeded, requests resume at {}",
+      self.until.format("%H:%M UTC")
+    )
+  }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// Waits until the limiter allows requests again, if that is before `deadline`.
+async fn wait(limiter: &RateLimiter, deadline: DateTime<Utc>) -> Result<(), RateLimited> {
+  let Some(until) = limiter.0.lock().paused_until else {
+    return Ok(());
+  };
+  if until > deadline {
+    return Err(RateLimited { until });
+  }
+  if let Ok(delay) = (until - Utc::now()).to_std() {
+    tokio::time::sleep(delay).await;
+  }
+  Ok(())
+}
+
+// `Request` isn't `Clone`, so copy the parts that Github looks at.
+pub(super) fn clone_request<B: Clone>(request: &Request<B>) -> Request<B> {
+  let mut clone = Request::new(request.body().clone());
+  *clone.method_mut() = request.method().clone();
+  *clone.uri_mut() = request.uri().clone();
+  *clone.version_mut() = request.version();
+  *clone.headers_mut() = request.headers().clone();
+  clone
+}
+
+/// Wraps a service to wait out rate limits, retrying the requests that hit them. Requests
+/// that fail on the server are only retried if they are safe to send twice.
+///
+/// This replaces Octocrab's default retry layer, which retries rate limited requests at once.
+pub struct RateLimitLayer(RateLimiter);
+
+impl RateLimitLayer {
+  pub fn new(limiter: RateLimiter) -> Self {
+    RateLimitLayer(limiter)
+  }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+  type Service = RateLimitService<S>;
+
+  fn layer(&self, inner: S) -> Self::Service {
+    RateLimitService {
+      inner,
+      limiter: self.0.clone(),
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+  inner: S,
+  limiter: RateLimiter,
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = Result<T, BoxError>> + Send>>;
+
+impl<S, B, RB> Service<Request<B>> for RateLimitService<S>
+where
+  S: Service<Request<B>, Response = Response<RB>> + Clone + Send + 'static,
+  S::Future: Send,
+  S::Error: Into<BoxError>,
+  B: Clone + Send + 'static,
+  RB: Send + 'static,
+{
+  type Response = Response<RB>;
+  type Error = BoxError;
+  type Future = BoxFuture<Self::Response>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx).map_err(Into::into)
+  }
+
+  fn call(&mut self, request: Request<B>) -> Self::Future {
+    // Use the service that was polled ready, leaving a fresh clone in its place.
+    let clone = self.inner.clone();
+    let mut inner = std::mem::replace(&mut self.inner, clone);
+    let limiter = self.limiter.clone();
+    // A rate limited request never reached Github, but a failed write might have been applied.
+    let idempotent = matches!(*request.method(), Method::GET | Method::HEAD);
+    Box::pin(async move {
+      let deadline = Utc::now() + MAX_WAIT;
+      let mut backoff = server_error_backoff();
+      let mut retries = 0;
+      loop {
+        wait(&limiter, deadline).await?;
+        let response = inner
+          .ready()
+          .await
+          .map_err(Into::into)?
+          .call(clone_request(&request))
+          .await
+          .map_err(Into::into)?;
+        let delay = match limiter.observe(response.status(), response.headers(), Utc::now()) {
+          // `wait` sleeps until the limit resets.
+          Some(resume) => {
+            tracing::warn!("Rate limited by Github until {resume}");
+            (resume <= deadline).then_some(Duration::ZERO)
+          }
+          None if idempotent && response.status().is_server_error() => backoff
+            .next()
+            .filter(|delay| Utc::now() + *delay <= deadline),
+          None => None,
+        };
+        let Some(delay) = delay.filter(|_| retries < MAX_RETRIES) else {
+          return Ok(response);
+        };
+        tokio::time::sleep(delay).await;
+        retries += 1;
+      }
+    })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::forge::model;
+  use wiremock::{
+    matchers::{method, path},
+    Mock, MockServer, ResponseTemplate,
+  };
+
+  fn user() -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(model::author("learner"))
+  }
+
+  fn forbidden() -> ResponseTemplate {
+    ResponseTemplate::new(403).set_body_json(serde_json::json!({
+      "message": "You have exceeded a secondary rate limit.",
+      "documentation_url": "https://docs.github.com/rest"
+    }))
+  }
+
+  fn client(server: &MockServer, limiter: &RateLimiter) -> octocrab::Octocrab {
+    crate::github::build_client("token", &server.uri(), limiter.clone(), None).unwrap()
+  }
+
+  #[tokio::test]
+  async fn secondary_limit() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+      .and(path("/user"))
+      .respond_with(forbidden().insert_header("retry-after", "1"))
+      .up_to_n_times(1)
+      .mount(&server)
+      .await;
+    Mock::given(method("GET"))
+      .and(path("/user"))
+      .respond_with(
+        user()
+          .insert_header("x-ratelimit-remaining", "4999")
+          .insert_header("x-ratelimit-limit", "5000")
+          .insert_header("x-ratelimit-reset", "1900000000"),
+      )
+      .mount(&server)
+      .await;
+
+    let limiter = RateLimiter::default();
+    let gh = client(&server, &limiter);
+    assert_eq!(gh.current().user().await.unwrap().login, "learner");
+    assert_eq!(
+      limiter.status(),
+      Some(RateLimit {
+        remaining: Some(4999),
+        limit: Some(5000),
+        reset: DateTime::from_timestamp(1_900_000_000, 0),
+        paused_until: None,
+      })
+    );
+  }
+
+  #[tokio::test]
+  async fn exhausted_quota() {
+    let server = MockServer::start().await;
+    let reset = Utc::now() + TimeDelta::hours(1);
+    Mock::given(method("GET"))
+      .and(path("/user"))
+      .respond_with(
+        forbidden()
+          .insert_header("x-ratelimit-remaining", "0")
+          .insert_header("x-ratelimit-limit", "5000")
+          .insert_header("x-ratelimit-reset", reset.timestamp().to_string().as_str()),
+      )
+      .expect(1)
+      .mount(&server)
+      .await;
+
+    let limiter = RateLimiter::default();
+    let gh = client(&server, &limiter);
+    assert!(gh.current().user().await.is_err());
+    let status = limiter.status().unwrap();
+    assert!(status.is_paused(Utc::now()));
+    assert_eq!(
+      status.paused_until,
+      DateTime::from_timestamp(reset.timestamp(), 0)
+    );
+
+    // Requests fail without reaching Github until the quota resets.
+    assert!(gh.current().user().await.is_err());
+  }
+
+  #[tokio::test]
+  async fn missing_permissions() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+      .and(path("/user"))
+      .respond_with(forbidden())
+      .expect(1)
+      .mount(&server)
+      .await;
+
+    let limiter = RateLimiter::default();
+    let gh = client(&server, &limiter);
+    assert!(gh.current().user().await.is_err());
+    assert_eq!(limiter.status(), None);
+  }
+
+  #[tokio::test]
+  async fn server_errors() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+      .and(path("/user"))
+      .respond_with(ResponseTemplate::new(502))
+      .up_to_n_times(1)
+      .mount(&server)
+      .await;
+    Mock::given(method("GET"))
+      .and(path("/user"))
+      .respond_with(user())
+      .mount(&server)
+      .await;
+    Mock::given(method("POST"))
+      .and(path("/repos/learner/quest/issues"))
+      .respond_with(ResponseTemplate::new(502))
+      .expect(1)
+      .mount(&server)
+      .await;
+
+    let limiter = RateLimiter::default();
+    let gh = client(&server, &limiter);
+    assert_eq!(gh.current().user().await.unwrap().login, "learner");
+
+    // The issue might have been created, so retrying could create it twice.
+    let response = gh
+      ._post(
+        "/repos/learner/quest/issues",
+        Some(&serde_json::json!({"title": "Stage 1"})),
+      )
+      .await
+      .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+  }
+
+  #[test]
+  fn backoff() {
+    let delays = server_error_backoff().take(3).collect::<Vec<_>>();
+    for (delay, max) in delays.into_iter().zip([500, 1000, 2000]) {
+      assert!(delay <= Duration::from_millis(max), "{delay:?}");
+    }
+  }
+}
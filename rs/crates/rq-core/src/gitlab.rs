@@ -0,0 +1,877 @@
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures_util::future::try_join_all;
+use parking_lot::{MappedMutexGuard, Mutex, MutexGuard};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use specta::Type;
+use std::{fs, path::Path, time::Duration};
+use tokio::{
+  time::{sleep, timeout},
+  try_join,
+};
+
+use crate::{
+  command::command,
+  forge::{
+    rest::RestClient, Forge, ForgeHost, FullPullRequest, GitProtocol, Issue, IssueState, Label,
+    PullRequest, QuestReviewComment, ReviewComment,
+  },
+  github::is_not_found,
+};
+
+/// Connection settings for a GitLab instance, read from `~/.rqst-gitlab.toml`.
+#[derive(Serialize, Deserialize, Type, Debug, Clone)]
+pub struct GitlabConfig {
+  /// Base URL of the instance, e.g. `https://gitlab.com`.
+  pub url: String,
+  pub token: String,
+}
+
+pub fn get_gitlab_config() -> Result<Option<GitlabConfig>> {
+  let Some(home) = home::home_dir() else {
+    return Ok(None);
+  };
+  let path = home.join(".rqst-gitlab.toml");
+  if !path.exists() {
+    return Ok(None);
+  }
+  let contents =
+    fs::read_to_string(&path).with_context(|| format!("Failed to read: {}", path.display()))?;
+  let config = toml::from_str(&contents)
+    .with_context(|| format!("Failed to parse GitLab config: {}", path.display()))?;
+  Ok(Some(config))
+}
+
+const PAGE_LIMIT: usize = 100;
+
+#[derive(Deserialize)]
+struct GitlabUser {
+  username: String,
+}
+
+#[derive(Deserialize)]
+struct GitlabLabel {
+  name: String,
+  color: String,
+  description: Option<String>,
+}
+
+impl GitlabLabel {
+  fn to_label(&self) -> Label {
+    Label {
+      name: self.name.clone(),
+      color: self.color.trim_start_matches('#').to_string(),
+      description: self.description.clone(),
+      default: false,
+    }
+  }
+}
+
+/// Listings requested `with_labels_details` return full labels, but everything else
+/// only returns label names.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GitlabLabelRef {
+  Full(GitlabLabel),
+  Name(String),
+}
+
+fn label_names(labels: &[GitlabLabelRef]) -> Vec<String> {
+  labels
+    .iter()
+    .map(|label| match label {
+      GitlabLabelRef::Full(label) => label.name.clone(),
+      GitlabLabelRef::Name(name) => name.clone(),
+    })
+    .collect()
+}
+
+fn issue_state(state: &str) -> IssueState {
+  match state {
+    "opened" => IssueState::Open,
+    _ => IssueState::Closed,
+  }
+}
+
+#[derive(Deserialize)]
+struct GitlabMergeRequest {
+  iid: u64,
+  title: String,
+  description: Option<String>,
+  labels: Vec<GitlabLabelRef>,
+  source_branch: String,
+  target_branch: String,
+  state: String,
+  web_url: String,
+  created_at: DateTime<Utc>,
+  updated_at: DateTime<Utc>,
+  merged_at: Option<DateTime<Utc>>,
+}
+
+impl GitlabMergeRequest {
+  fn to_pull_request(&self) -> PullRequest {
+    PullRequest {
+      number: self.iid,
+      title: self.title.clone(),
+      body: self.description.clone(),
+      labels: label_names(&self.labels),
+      head: self.source_branch.clone(),
+      base: self.target_branch.clone(),
+      state: issue_state(&self.state),
+      html_url: self.web_url.clone(),
+      created_at: self.created_at,
+      updated_at: self.updated_at,
+      merged_at: self.merged_at,
+    }
+  }
+}
+
+#[derive(Deserialize)]
+struct GitlabIssue {
+  iid: u64,
+  title: String,
+  description: Option<String>,
+  labels: Vec<GitlabLabelRef>,
+  state: String,
+  web_url: String,
+  created_at: DateTime<Utc>,
+  updated_at: DateTime<Utc>,
+}
+
+impl GitlabIssue {
+  fn to_issue(&self) -> Issue {
+    Issue {
+      number: self.iid,
+      title: self.title.clone(),
+      body: self.description.clone(),
+      labels: label_names(&self.labels),
+      state: issue_state(&self.state),
+      html_url: self.web_url.clone(),
+      created_at: self.created_at,
+      updated_at: self.updated_at,
+    }
+  }
+}
+
+#[derive(Deserialize)]
+struct GitlabDiscussion {
+  notes: Vec<GitlabNote>,
+}
+
+#[derive(Deserialize)]
+struct GitlabNote {
+  id: u64,
+  body: String,
+  position: Option<GitlabPosition>,
+}
+
+#[derive(Deserialize)]
+struct GitlabPosition {
+  new_path: Option<String>,
+  new_line: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct GitlabDiffRefs {
+  base_sha: String,
+  head_sha: String,
+  start_sha: String,
+}
+
+#[derive(Deserialize)]
+struct GitlabMergeRequestDetails {
+  diff_refs: Option<GitlabDiffRefs>,
+}
+
+#[derive(Deserialize)]
+struct GitlabProject {
+  import_status: Option<String>,
+  default_branch: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GitlabBranch {
+  name: String,
+}
+
+/// GitLab addresses projects by their URL-encoded path.
+fn project_id(user: &str, name: &str) -> String {
+  format!("{user}%2F{name}")
+}
+
+/// An account on a GitLab instance.
+pub struct GitlabHost {
+  client: RestClient,
+}
+
+impl GitlabHost {
+  pub fn new(config: &GitlabConfig) -> Result<Self> {
+    let client = RestClient::new(
+      &config.url,
+      "/api/v4",
+      &config.token,
+      "per_page",
+      PAGE_LIMIT,
+    )
+    .context("Failed to build GitLab connector")?;
+    Ok(GitlabHost { client })
+  }
+
+  fn handle(&self, user: &str, name: &str) -> GitlabRepo {
+    GitlabRepo {
+      user: user.to_string(),
+      name: name.to_string(),
+      client: self.client.clone(),
+      prs: Mutex::new(None),
+      issues: Mutex::new(None),
+    }
+  }
+}
+
+#[async_trait]
+impl ForgeHost for GitlabHost {
+  async fn current_user(&self) -> Result<String> {
+    let user: GitlabUser = self
+      .client
+      .get("/user")
+      .await
+      .context("Failed to query GitLab for current user")?;
+    Ok(user.username)
+  }
+
+  fn repo(&self, user: &str, name: &str) -> Box<dyn Forge> {
+    Box::new(self.handle(user, name))
+  }
+
+  async fn create_repo(&self, name: &str) -> Result<Box<dyn Forge>> {
+    let user = self.current_user().await?;
+    let _project: serde_json::Value = self
+      .client
+      .post(
+        "/projects",
+        json!({
+          "name": name,
+          "path": name,
+          "visibility": "private",
+          "default_branch": "main",
+        }),
+      )
+      .await
+      .context("Failed to create project")?;
+    Ok(Box::new(self.handle(&user, name)))
+  }
+
+  async fn generate_repo(&self, template: &dyn Forge) -> Result<Box<dyn Forge>> {
+    // GitLab has no template repositories, so we fork the template and then cut the fork loose.
+    let user = self.current_user().await?;
+    let route = format!(
+      "/projects/{}/fork",
+      project_id(template.user(), template.name())
+    );
+    let _project: serde_json::Value = self
+      .client
+      .post(
+        &route,
+        json!({
+          "name": template.name(),
+          "path": template.name(),
+          "namespace_path": user,
+          "visibility": "private",
+        }),
+      )
+      .await
+      .with_context(|| {
+        format!(
+          "Failed to fork template {}/{}",
+          template.user(),
+          template.name()
+        )
+      })?;
+
+    let repo = self.handle(&user, template.name());
+    repo.wait_for_import().await?;
+    repo.detach_fork().await?;
+    Ok(Box::new(repo))
+  }
+
+  fn check_ssh(&self) -> Result<()> {
+    let host = self.client.git_host()?;
+    let output = command(&format!("ssh -T git@{host}"), Path::new("/")).output()?;
+    if !output.status.success() {
+      let stderr = String::from_utf8(output.stderr)?;
+      bail!("Failed to establish a secure connection to {host} with error:\n{stderr}")
+    }
+    Ok(())
+  }
+}
+
+pub struct GitlabRepo {
+  user: String,
+  name: String,
+  client: RestClient,
+  prs: Mutex<Option<Vec<FullPullRequest>>>,
+  issues: Mutex<Option<Vec<Issue>>>,
+}
+
+impl GitlabRepo {
+  fn route(&self, path: &str) -> String {
+    format!("/projects/{}{path}", project_id(&self.user, &self.name))
+  }
+
+  async fn list_prs(&self) -> octocrab::Result<Vec<GitlabMergeRequest>> {
+    self
+      .client
+      .get_all(&self.route("/merge_requests?state=all&with_labels_details=true"))
+      .await
+  }
+
+  async fn list_issues(&self) -> octocrab::Result<Vec<GitlabIssue>> {
+    self
+      .client
+      .get_all(&self.route("/issues?state=all&with_labels_details=true"))
+      .await
+  }
+
+  async fn list_pr_comments(&self, pr: &GitlabMergeRequest) -> Result<Vec<ReviewComment>> {
+    let discussions: Vec<GitlabDiscussion> = self
+      .client
+      .get_all(&self.route(&format!("/merge_requests/{}/discussions", pr.iid)))
+      .await?;
+    let comments = discussions
+      .iter()
+      .flat_map(|discussion| &discussion.notes)
+      .filter_map(|note| {
+        // Only diff notes have a position, and those are the ones quests care about.
+        let position = note.position.as_ref()?;
+        let path = position.new_path.as_ref()?;
+        Some(ReviewComment {
+          id: note.id,
+          path: path.clone(),
+          line: position.new_line,
+          body: note.body.clone(),
+        })
+      })
+      .collect();
+    Ok(comments)
+  }
+
+  // Forking happens in the background, so we have to wait for it before touching the project.
+  async fn wait_for_import(&self) -> Result<()> {
+    const RETRY_INTERVAL: u64 = 1000;
+    const RETRY_TIMEOUT: u64 = 60000;
+
+    let poll = async {
+      loop {
+        let project: GitlabProject = self
+          .client
+          .get(&self.route(""))
+          .await
+          .context("Failed to check fork status")?;
+        match project.import_status.as_deref() {
+          Some("finished" | "none") | None => return Ok(()),
+          Some("failed") => bail!("Failed to fork template"),
+          status => tracing::debug!("import status: {status:?}"),
+        }
+        sleep(Duration::from_millis(RETRY_INTERVAL)).await;
+      }
+    };
+    timeout(Duration::from_millis(RETRY_TIMEOUT), poll)
+      .await
+      .context("Fork is still importing after timeout")?
+  }
+
+  /// Removes the fork relationship and every branch except the default one, so the project
+  /// looks like a fresh copy of the template's default branch.
+  async fn detach_fork(&self) -> Result<()> {
+    self
+      .client
+      .delete(&self.route("/fork"))
+      .await
+      .context("Failed to remove fork relationship")?;
+
+    let project: GitlabProject = self.client.get(&self.route("")).await?;
+    let default_branch = project.default_branch.unwrap_or_else(|| "main".into());
+    let branches: Vec<GitlabBranch> = self
+      .client
+      .get_all(&self.route("/repository/branches"))
+      .await
+      .context("Failed to list branches")?;
+    try_join_all(
+      branches
+        .iter()
+        .filter(|branch| branch.name != default_branch)
+        .map(|branch| {
+          let route = self.route(&format!(
+            "/repository/branches/{}",
+            branch.name.replace('/', "%2F")
+          ));
+          async move { self.client.delete(&route).await }
+        }),
+    )
+    .await
+    .context("Failed to delete template branches")?;
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl Forge for GitlabRepo {
+  fn user(&self) -> &str {
+    &self.user
+  }
+
+  fn name(&self) -> &str {
+    &self.name
+  }
+
+  fn remote(&self, protocol: GitProtocol) -> String {
+    match protocol {
+      GitProtocol::Https => format!("{}/{}/{}.git", self.client.url, self.user, self.name),
+      GitProtocol::Ssh => {
+        let host = self.client.git_host().unwrap_or_default();
+        format!("git@{host}:{}/{}.git", self.user, self.name)
+      }
+    }
+  }
+
+  fn pr_reference(&self, number: u64) -> String {
+    format!("!{number}")
+  }
+
+  async fn fetch(&self) -> Result<bool> {
+    let (prs, issues) = match try_join!(self.list_prs(), self.list_issues()) {
+      Ok(lists) => lists,
+      Err(e) if is_not_found(&e) => return Ok(false),
+      Err(e) => return Err(e.into()),
+    };
+
+    let full_prs = try_join_all(prs.iter().map(|pr| async move {
+      let comments = self
+        .list_pr_comments(pr)
+        .await
+        .with_context(|| format!("Failed to fetch comments for MR {}", pr.iid))?;
+      Ok::<_, anyhow::Error>(FullPullRequest {
+        data: pr.to_pull_request(),
+        comments,
+      })
+    }))
+    .await?;
+
+    *self.prs.lock() = Some(full_prs);
+    *self.issues.lock() = Some(issues.iter().map(GitlabIssue::to_issue).collect());
+
+    Ok(true)
+  }
+
+  fn prs(&self) -> MappedMutexGuard<'_, Vec<FullPullRequest>> {
+    MutexGuard::map(self.prs.lock(), |opt| {
+      opt.as_mut().expect("PRs not populated")
+    })
+  }
+
+  fn issues(&self) -> MappedMutexGuard<'_, Vec<Issue>> {
+    MutexGuard::map(self.issues.lock(), |opt| {
+      opt.as_mut().expect("Issues not populated")
+    })
+  }
+
+  fn restore(&self, prs: Vec<FullPullRequest>, issues: Vec<Issue>) {
+    *self.prs.lock() = Some(prs);
+    *self.issues.lock() = Some(issues);
+  }
+
+  async fn list_all(&self) -> Result<Option<(Vec<PullRequest>, Vec<Issue>)>> {
+    let query = "state=all&order_by=created_at&sort=desc";
+    let prs_route = self.route(&format!("/merge_requests?{query}"));
+    let issues_route = self.route(&format!("/issues?{query}"));
+    let (prs, issues) = match try_join!(
+      self.client.get_all::<GitlabMergeRequest>(&prs_route),
+      self.client.get_all::<GitlabIssue>(&issues_route)
+    ) {
+      Ok(lists) => lists,
+      Err(e) if is_not_found(&e) => return Ok(None),
+      Err(e) => return Err(e.into()),
+    };
+    let prs = prs
+      .iter()
+      .map(GitlabMergeRequest::to_pull_request)
+      .collect();
+    let issues = issues.iter().map(GitlabIssue::to_issue).collect();
+    Ok(Some((prs, issues)))
+  }
+
+  async fn labels(&self) -> Result<Vec<Label>> {
+    let labels: Vec<GitlabLabel> = self
+      .client
+      .get_all(&self.route("/labels"))
+      .await
+      .context("Failed to fetch labels")?;
+    Ok(labels.iter().map(GitlabLabel::to_label).collect())
+  }
+
+  async fn create_labels(&self, labels: &[Label]) -> Result<()> {
+    // Depending on the instance, forks may already carry over the template's labels.
+    let existing = self.labels().await?;
+    let route = self.route("/labels");
+    try_join_all(
+      labels
+        .iter()
+        .filter(|label| !label.default)
+        .filter(|label| existing.iter().all(|other| other.name != label.name))
+        .map(|label| {
+          self.client.post::<serde_json::Value>(
+            &route,
+            json!({
+              "name": label.name,
+              "color": format!("#{}", label.color),
+              "description": label.description.as_deref().unwrap_or(""),
+            }),
+          )
+        }),
+    )
+    .await
+    .context("Failed to create labels")?;
+    Ok(())
+  }
+
+  async fn create_pr(
+    &self,
+    title: &str,
+    head: &str,
+    base: &str,
+    body: &str,
+    labels: &[String],
+  ) -> Result<PullRequest> {
+    let mr: GitlabMergeRequest = self
+      .client
+      .post(
+        &self.route("/merge_requests"),
+        json!({
+          "title": title,
+          "source_branch": head,
+          "target_branch": base,
+          "description": body,
+          "labels": labels.join(","),
+        }),
+      )
+      .await
+      .context("Failed to create MR")?;
+    Ok(mr.to_pull_request())
+  }
+
+  async fn copy_pr_comment(
+    &self,
+    pr: u64,
+    comment: &QuestReviewComment,
+    _commit: &str,
+  ) -> Result<()> {
+    // Diff notes are positioned relative to the MR's diff, which GitLab computes in the
+    // background after the MR is created.
+    let route = self.route(&format!("/merge_requests/{pr}"));
+    let strategy = tokio_retry::strategy::FixedInterval::from_millis(500).take(10);
+    let diff_refs = tokio_retry::Retry::spawn(strategy, || async {
+      let details: GitlabMergeRequestDetails = self.client.get(&route).await?;
+      details
+        .diff_refs
+        .ok_or_else(|| anyhow!("MR {pr} has no diff yet"))
+    })
+    .await?;
+
+    let comment_json = json!({
+      "body": comment.body,
+      "position": {
+        "position_type": "text",
+        "base_sha": diff_refs.base_sha,
+        "start_sha": diff_refs.start_sha,
+        "head_sha": diff_refs.head_sha,
+        "new_path": comment.path,
+        "old_path": comment.path,
+        "new_line": comment.line,
+      },
+    });
+    let _discussion: serde_json::Value = self
+      .client
+      .post(
+        &self.route(&format!("/merge_requests/{pr}/discussions")),
+        comment_json.clone(),
+      )
+      .await
+      .with_context(|| format!("Failed to copy MR comment: {comment_json:#?}"))?;
+    Ok(())
+  }
+
+  async fn create_issue(&self, title: &str, body: &str, labels: &[String]) -> Result<Issue> {
+    let issue: GitlabIssue = self
+      .client
+      .post(
+        &self.route("/issues"),
+        json!({
+          "title": title,
+          "description": body,
+          "labels": labels.join(","),
+        }),
+      )
+      .await?;
+    Ok(issue.to_issue())
+  }
+
+  async fn close_issue(&self, issue: &Issue) -> Result<()> {
+    let _issue: serde_json::Value = self
+      .client
+      .put(
+        &self.route(&format!("/issues/{}", issue.number)),
+        json!({ "state_event": "close" }),
+      )
+      .await
+      .with_context(|| format!("Failed to close issue: {}", issue.number))?;
+    Ok(())
+  }
+
+  async fn merge_pr(&self, pr: &PullRequest) -> Result<()> {
+    // GitLab checks mergeability in the background, and refuses to merge until it's done.
+    let route = self.route(&format!("/merge_requests/{}/merge", pr.number));
+    let strategy = tokio_retry::strategy::FixedInterval::from_millis(500).take(10);
+    let mr: GitlabMergeRequest =
+      tokio_retry::Retry::spawn(strategy, || self.client.put(&route, json!({})))
+        .await
+        .with_context(|| format!("Failed to merge MR: {}", pr.number))?;
+    ensure!(
+      mr.state == "merged",
+      "MR {} was not merged, state: {}",
+      pr.number,
+      mr.state
+    );
+    Ok(())
+  }
+
+  async fn delete(&self) -> Result<()> {
+    self
+      .client
+      .delete(&self.route(""))
+      .await
+      .context("Failed to delete project")?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use wiremock::{
+    matchers::{body_partial_json, method, path, query_param},
+    Mock, MockServer, ResponseTemplate,
+  };
+
+  const PROJECT: &str = "/api/v4/projects/learner%2Fquest";
+
+  fn user() -> serde_json::Value {
+    json!({ "username": "learner" })
+  }
+
+  fn label(id: u64, name: &str) -> serde_json::Value {
+    json!({ "id": id, "name": name, "color": "#e11d21", "description": null })
+  }
+
+  fn issue(iid: u64, label_name: &str) -> serde_json::Value {
+    json!({
+      "iid": iid,
+      "title": format!("Issue {iid}"),
+      "description": "Do the thing",
+      "labels": [label(1, label_name)],
+      "state": "opened",
+      "web_url": format!("https://gitlab.test/learner/quest/-/issues/{iid}"),
+      "author": user(),
+      "created_at": "2024-05-01T12:00:00Z",
+      "updated_at": "2024-05-02T12:00:00Z",
+    })
+  }
+
+  fn merge_request(iid: u64, label_name: &str, state: &str) -> serde_json::Value {
+    json!({
+      "iid": iid,
+      "title": "Starter code",
+      "description": "Here is some code",
+      "labels": [label(1, label_name)],
+      "source_branch": format!("{label_name}-a"),
+      "target_branch": "main",
+      "sha": "abc123",
+      "state": state,
+      "web_url": format!("https://gitlab.test/learner/quest/-/merge_requests/{iid}"),
+      "author": user(),
+      "created_at": "2024-05-01T12:00:00Z",
+      "updated_at": "2024-05-02T12:00:00Z",
+      "merged_at": (state == "merged").then_some("2024-05-02T12:00:00Z"),
+    })
+  }
+
+  async fn mock_get(server: &MockServer, route: &str, body: serde_json::Value) {
+    Mock::given(method("GET"))
+      .and(path(format!("{PROJECT}{route}")))
+      .respond_with(ResponseTemplate::new(200).set_body_json(body))
+      .mount(server)
+      .await;
+  }
+
+  fn host(server: &MockServer) -> GitlabHost {
+    GitlabHost::new(&GitlabConfig {
+      url: server.uri(),
+      token: "token".into(),
+    })
+    .unwrap()
+  }
+
+  #[tokio::test]
+  async fn fetch() -> Result<()> {
+    let server = MockServer::start().await;
+    mock_get(
+      &server,
+      "/merge_requests",
+      json!([
+        merge_request(1, "s1", "merged"),
+        merge_request(2, "s2", "opened")
+      ]),
+    )
+    .await;
+    mock_get(
+      &server,
+      "/merge_requests/1/discussions",
+      json!([
+        { "notes": [{ "id": 8, "body": "Thanks!", "author": user(), "position": null }] },
+        { "notes": [{
+          "id": 9,
+          "body": "Look here",
+          "author": user(),
+          "position": { "new_path": "src/lib.rs", "new_line": 4, "head_sha": "abc123" },
+        }] },
+      ]),
+    )
+    .await;
+    mock_get(&server, "/merge_requests/2/discussions", json!([])).await;
+    mock_get(&server, "/issues", json!([issue(1, "s1"), issue(2, "s2")])).await;
+
+    let repo = host(&server).load("learner", "quest").await?;
+
+    let prs = repo.prs();
+    assert_eq!(prs.len(), 2);
+    assert_eq!(prs[0].data.head, "s1-a");
+    assert_eq!(prs[0].data.merged_at, Some("2024-05-02T12:00:00Z".parse()?));
+    assert_eq!(prs[0].data.state, IssueState::Closed);
+    assert_eq!(prs[0].comments.len(), 1);
+    assert_eq!(prs[0].comments[0].line, Some(4));
+    assert_eq!(prs[1].data.state, IssueState::Open);
+    assert!(prs[1].data.merged_at.is_none());
+    drop(prs);
+
+    // Issues and MRs are numbered separately, so MR references need a different sigil.
+    assert_eq!(
+      repo.process_issue_body("See {{ s2 pr }} and {{ s2 issue }}"),
+      "See !2 and #2"
+    );
+
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn fetch_missing_repo() -> Result<()> {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+      .respond_with(
+        ResponseTemplate::new(404).set_body_json(json!({ "message": "404 Project Not Found" })),
+      )
+      .mount(&server)
+      .await;
+
+    let repo = host(&server).repo("learner", "quest");
+    assert!(!repo.fetch().await?);
+    assert!(repo.list_all().await?.is_none());
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn create_pr() -> Result<()> {
+    let server = MockServer::start().await;
+    let mut created = merge_request(3, "s2", "opened");
+    created["labels"] = json!(["s2", "reset"]);
+    Mock::given(method("POST"))
+      .and(path(format!("{PROJECT}/merge_requests")))
+      .and(body_partial_json(json!({
+        "source_branch": "s2-a",
+        "target_branch": "main",
+        "labels": "s2,reset",
+      })))
+      .respond_with(ResponseTemplate::new(201).set_body_json(created))
+      .expect(1)
+      .mount(&server)
+      .await;
+
+    let repo = host(&server).repo("learner", "quest");
+    let pr = repo
+      .create_pr(
+        "Starter code",
+        "s2-a",
+        "main",
+        "Here is some code",
+        &["s2".into(), "reset".into()],
+      )
+      .await?;
+    assert_eq!(pr.number, 3);
+    assert_eq!(pr.labels, ["s2", "reset"]);
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn generate_repo() -> Result<()> {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+      .and(path("/api/v4/user"))
+      .respond_with(ResponseTemplate::new(200).set_body_json(user()))
+      .mount(&server)
+      .await;
+    Mock::given(method("POST"))
+      .and(path("/api/v4/projects/author%2Fquest/fork"))
+      .and(body_partial_json(json!({ "namespace_path": "learner" })))
+      .respond_with(ResponseTemplate::new(201).set_body_json(json!({})))
+      .expect(1)
+      .mount(&server)
+      .await;
+    mock_get(
+      &server,
+      "",
+      json!({ "import_status": "finished", "default_branch": "main" }),
+    )
+    .await;
+    Mock::given(method("DELETE"))
+      .and(path(format!("{PROJECT}/fork")))
+      .respond_with(ResponseTemplate::new(204))
+      .expect(1)
+      .mount(&server)
+      .await;
+    Mock::given(method("GET"))
+      .and(path(format!("{PROJECT}/repository/branches")))
+      .and(query_param("page", "1"))
+      .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+        { "name": "main" },
+        { "name": "s1-a" },
+        { "name": "s1-b" },
+      ])))
+      .mount(&server)
+      .await;
+    for branch in ["s1-a", "s1-b"] {
+      Mock::given(method("DELETE"))
+        .and(path(format!("{PROJECT}/repository/branches/{branch}")))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&server)
+        .await;
+    }
+
+    let host = host(&server);
+    let template = host.repo("author", "quest");
+    let repo = host.generate_repo(&*template).await?;
+    assert_eq!(repo.user(), "learner");
+    assert_eq!(repo.name(), "quest");
+    Ok(())
+  }
+}
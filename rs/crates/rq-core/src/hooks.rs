@@ -0,0 +1,111 @@
+//! Quests can ship git hooks in `.githooks`, which run arbitrary code on the learner's machine.
+//! Hooks are only installed once the learner has seen them and allowed them.
+//! When RepoQuest runs `post-checkout` itself, the hook gets a cleared environment apart from
+//! `PATH` and `HOME`, and is killed if it runs too long. This keeps tokens out of reach, but it
+//! isn't a sandbox: an allowed hook runs with the learner's permissions.
+//!
+//! The learner's decision is kept in the quest repo's config along with the hash of the hooks
+//! it was made for, so a later version of the quest with different hooks asks them again.
+
+use anyhow::{Context, Result};
+use ring::digest::{Context as Digest, SHA256};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use std::{fs, path::Path};
+
+use crate::{
+  git::GitRepo,
+  utils::{hex, sha256},
+};
+
+/// Where quests keep their hooks, relative to the repo.
+pub const HOOKS_DIR: &str = ".githooks";
+
+/// The config key holding the learner's decision, as `allow <hash>` or `deny <hash>`.
+const DECISION_KEY: &str = "rqst.hooks";
+
+#[derive(Serialize, Deserialize, Type, Clone, Debug, PartialEq, Eq)]
+pub struct Hook {
+  pub name: String,
+  /// For display only, since bytes that aren't UTF-8 are replaced.
+  pub contents: String,
+  /// The SHA-256 of the hook file, in hex.
+  pub sha256: String,
+}
+
+/// The hooks in a quest's `.githooks`, sorted by name.
+#[derive(Serialize, Deserialize, Type, Clone, Debug, PartialEq, Eq)]
+pub struct HookSet {
+  pub hooks: Vec<Hook>,
+  /// Identifies this version of the hooks, by hashing each hook's name and hash.
+  pub sha256: String,
+}
+
+/// Whether the learner lets a quest's hooks run. Denying them plays the quest without hooks.
+#[derive(Serialize, Deserialize, Type, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HookDecision {
+  Allow,
+  Deny,
+}
+
+impl HookSet {
+  /// Reads the hooks of the quest in `repo_dir`, or returns `None` if it has none.
+  pub fn read(repo_dir: &Path) -> Result<Option<Self>> {
+    let dir = repo_dir.join(HOOKS_DIR);
+    if !dir.is_dir() {
+      return Ok(None);
+    }
+
+    let mut hooks = Vec::new();
+    let entries =
+      fs::read_dir(&dir).with_context(|| format!("Failed to read: {}", dir.display()))?;
+    for entry in entries {
+      let entry = entry?;
+      // Only regular files are installed, so symlinks and directories never run.
+      if !entry.file_type()?.is_file() {
+        continue;
+      }
+      let path = entry.path();
+      let bytes = fs::read(&path).with_context(|| format!("Failed to read: {}", path.display()))?;
+      hooks.push(Hook {
+        name: entry.file_name().to_string_lossy().into_owned(),
+        contents: String::from_utf8_lossy(&bytes).into_owned(),
+        sha256: sha256(&bytes),
+      });
+    }
+    if hooks.is_empty() {
+      return Ok(None);
+    }
+    hooks.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut combined = Digest::new(&SHA256);
+    for hook in &hooks {
+      combined.update(format!("{}\0{}\n", hook.name, hook.sha256).as_bytes());
+    }
+    let sha256 = hex(combined.finish());
+    Ok(Some(HookSet { hooks, sha256 }))
+  }
+
+  /// The learner's decision about this version of the hooks, if they made one.
+  pub fn decision(&self, repo: &GitRepo) -> Result<Option<HookDecision>> {
+    let Some(value) = repo.config_get(DECISION_KEY)? else {
+      return Ok(None);
+    };
+    Ok(match value.split_once(' ') {
+      Some((decision, hash)) if hash == self.sha256 => match decision {
+        "allow" => Some(HookDecision::Allow),
+        "deny" => Some(HookDecision::Deny),
+        _ => None,
+      },
+      _ => None,
+    })
+  }
+
+  pub fn record(&self, repo: &GitRepo, decision: HookDecision) -> Result<()> {
+    let decision = match decision {
+      HookDecision::Allow => "allow",
+      HookDecision::Deny => "deny",
+    };
+    repo.config_set(DECISION_KEY, &format!("{decision} {}", self.sha256))
+  }
+}
@@ -1,6 +1,11 @@
 mod command;
+pub mod credential;
+pub mod forge;
 pub mod git;
+pub mod gitea;
 pub mod github;
+pub mod gitlab;
+pub mod hooks;
 pub mod package;
 pub mod quest;
 pub mod stage;
@@ -1,21 +1,28 @@
 use std::{
-  collections::HashMap,
-  fs::File,
-  io::{BufReader, BufWriter, Read},
+  collections::{BTreeMap, HashMap},
+  fs::{self, File},
+  io::{BufWriter, Read, Write},
   path::{Path, PathBuf},
 };
 
 use crate::{
+  forge::{ForgeHost, Label, QuestIssue, QuestPullRequest},
   git::GitRepo,
-  github::{FullPullRequest, GithubRepo},
   quest::QuestConfig,
   stage::StagePart,
+  utils,
 };
-use anyhow::{Context, Result};
-use flate2::{read::GzDecoder, write::GzEncoder, Compression};
-use octocrab::models::{issues::Issue, Label};
-use semver::{Version, VersionReq};
+use anyhow::{ensure, Context, Result};
+use flate2::{read::GzDecoder, Compression, GzBuilder};
+use semver::Version;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use self::signature::{PackageTrust, TrustStore};
+
+pub(crate) mod paths;
+pub mod signature;
+pub mod source;
 
 #[derive(Serialize, Deserialize)]
 pub struct Patch {
@@ -24,17 +31,221 @@ pub struct Patch {
   pub patch: String,
 }
 
+/// The code of a quest.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum PackageContents {
+  /// The text files on `main`, and each stage's starter code as a diff from the previous
+  /// stage. Binary files and file modes are lost.
+  Files {
+    initial: BTreeMap<PathBuf, String>,
+    patches: Vec<Patch>,
+  },
+  /// A git bundle of `main` and every stage branch, which keeps binary files, file modes
+  /// and history.
+  Bundle {
+    #[serde(with = "base64_bytes")]
+    bundle: Vec<u8>,
+  },
+}
+
+/// Stores bytes as a base64 string rather than a JSON array of numbers.
+mod base64_bytes {
+  use base64::{engine::general_purpose::STANDARD, Engine};
+  use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+  pub fn serialize<S: Serializer>(bytes: &[u8], s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&STANDARD.encode(bytes))
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+    let encoded = String::deserialize(d)?;
+    STANDARD.decode(encoded).map_err(D::Error::custom)
+  }
+}
+
+/// A quest that can be played without its template repo.
 #[derive(Serialize, Deserialize)]
 pub struct QuestPackage {
+  /// The version of RepoQuest that built the package.
   pub version: Version,
+  /// The version of the package format, which is [`SCHEMA_VERSION`] once loaded.
+  pub schema: u32,
+  /// The SHA-256 of everything in the package except `version` and `schema`, in hex. It
+  /// identifies the quest's revision, and is the same whenever the same repo is packed.
+  pub content_hash: String,
   pub config: QuestConfig,
-  pub issues: Vec<Issue>,
-  pub prs: Vec<FullPullRequest>,
-  pub initial: HashMap<PathBuf, String>,
-  pub patches: Vec<Patch>,
+  pub issues: Vec<QuestIssue>,
+  pub prs: Vec<QuestPullRequest>,
+  pub contents: PackageContents,
   #[serde(skip)]
   patch_map: HashMap<(String, String), usize>,
   pub labels: Vec<Label>,
+  #[serde(skip)]
+  migrations: Vec<String>,
+  #[serde(skip)]
+  trust: PackageTrust,
+}
+
+struct Migration {
+  description: &'static str,
+  apply: fn(&mut Map<String, Value>) -> Result<()>,
+}
+
+/// Upgrades packages in older formats, where `MIGRATIONS[i]` turns a package with schema
+/// version `i + 1` into one with version `i + 2`. Packages that predate schema versions are
+/// version 1.
+///
+/// To change the format, bump the schema by adding a migration that rewrites older packages'
+/// JSON into the new format.
+const MIGRATIONS: &[Migration] = &[
+  Migration {
+    description: "move the starter code into `contents`",
+    apply: nest_contents,
+  },
+  Migration {
+    description: "record a content hash",
+    apply: add_content_hash,
+  },
+  Migration {
+    description: "keep only the fields of issues and PRs that quests use",
+    apply: slim_issues_and_prs,
+  },
+  Migration {
+    description: "keep only the fields of labels that quests use",
+    apply: slim_labels,
+  },
+];
+
+/// The version of the package format written by this version of RepoQuest.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32 + 1;
+
+fn nest_contents(package: &mut Map<String, Value>) -> Result<()> {
+  let contents = match package.remove("bundle") {
+    Some(bundle) => json!({ "type": "Bundle", "bundle": bundle }),
+    None => json!({
+      "type": "Files",
+      "initial": package.remove("initial").unwrap_or_else(|| json!({})),
+      "patches": package.remove("patches").unwrap_or_else(|| json!([])),
+    }),
+  };
+  package.remove("initial");
+  package.remove("patches");
+  package.insert("contents".into(), contents);
+  Ok(())
+}
+
+/// The hash is computed once the package is loaded, since it covers the migrated contents.
+fn add_content_hash(package: &mut Map<String, Value>) -> Result<()> {
+  package.insert("content_hash".into(), "".into());
+  Ok(())
+}
+
+fn slim_issues_and_prs(package: &mut Map<String, Value>) -> Result<()> {
+  let str_field = |value: &Value, key: &str| value[key].as_str().unwrap_or_default().to_string();
+  let label_names = |labels: &Value| {
+    let labels = labels.as_array().map(Vec::as_slice).unwrap_or_default();
+    labels
+      .iter()
+      .map(|label| str_field(label, "name"))
+      .collect::<Vec<_>>()
+  };
+
+  let issues = package.get("issues").and_then(Value::as_array);
+  let issues = issues
+    .into_iter()
+    .flatten()
+    .map(|issue| {
+      json!({
+        "title": str_field(issue, "title"),
+        "body": str_field(issue, "body"),
+        "labels": label_names(&issue["labels"]),
+      })
+    })
+    .collect::<Vec<_>>();
+
+  let prs = package.get("prs").and_then(Value::as_array);
+  let prs = prs
+    .into_iter()
+    .flatten()
+    .map(|pr| {
+      let data = &pr["data"];
+      let comments = pr["comments"].as_array().map(Vec::as_slice);
+      let comments = comments
+        .unwrap_or_default()
+        .iter()
+        .map(|comment| {
+          json!({
+            "path": str_field(comment, "path"),
+            "line": comment["line"],
+            "body": str_field(comment, "body"),
+          })
+        })
+        .collect::<Vec<_>>();
+      json!({
+        "title": str_field(data, "title"),
+        "body": str_field(data, "body"),
+        "labels": label_names(&data["labels"]),
+        "head": str_field(&data["head"], "ref"),
+        "base": str_field(&data["base"], "ref"),
+        "comments": comments,
+      })
+    })
+    .collect::<Vec<_>>();
+
+  package.insert("issues".into(), issues.into());
+  package.insert("prs".into(), prs.into());
+  Ok(())
+}
+
+fn slim_labels(package: &mut Map<String, Value>) -> Result<()> {
+  let labels = package.get("labels").and_then(Value::as_array);
+  let labels = labels
+    .into_iter()
+    .flatten()
+    .map(|label| {
+      json!({
+        "name": label["name"],
+        "color": label["color"],
+        "description": label["description"],
+        "default": label["default"].as_bool().unwrap_or_default(),
+      })
+    })
+    .collect::<Vec<_>>();
+  package.insert("labels".into(), labels.into());
+  Ok(())
+}
+
+/// Brings a package's JSON up to [`SCHEMA_VERSION`]. Returns a description of each
+/// migration that was applied.
+fn migrate(json: &mut Value) -> Result<Vec<String>> {
+  let package = json
+    .as_object_mut()
+    .context("Package is not a JSON object")?;
+  let schema = match package.get("schema") {
+    Some(schema) => schema
+      .as_u64()
+      .and_then(|schema| u32::try_from(schema).ok())
+      .filter(|schema| *schema > 0)
+      .context("Invalid package schema version")?,
+    None => 1,
+  };
+  ensure!(
+    schema <= SCHEMA_VERSION,
+    "The quest package was made by a newer version of RepoQuest, which uses package format \
+     v{schema}. This version only reads formats up to v{SCHEMA_VERSION}, please update RepoQuest."
+  );
+
+  let mut applied = Vec::new();
+  for from in schema..SCHEMA_VERSION {
+    let migration = &MIGRATIONS[from as usize - 1];
+    let to = from + 1;
+    (migration.apply)(package)
+      .with_context(|| format!("Failed to migrate package from v{from} to v{to}"))?;
+    applied.push(format!("v{from} → v{to}: {}", migration.description));
+  }
+  package.insert("schema".into(), SCHEMA_VERSION.into());
+  Ok(applied)
 }
 
 fn version() -> Version {
@@ -42,85 +253,282 @@ fn version() -> Version {
 }
 
 impl QuestPackage {
-  pub async fn build(path: &Path) -> Result<Self> {
+  pub async fn build(path: &Path, host: &dyn ForgeHost) -> Result<Self> {
     let git_repo = GitRepo::new(path);
     let config = QuestConfig::load(&git_repo, None)?;
-    let gh_repo = GithubRepo::load(&config.author, &config.repo).await?;
-
-    let initial = git_repo.read_initial_files()?;
-    let issues = gh_repo.issues().clone();
-    let prs = gh_repo.prs().clone();
-    let labels = gh_repo
-      .issue_handler()
-      .list_labels_for_repo()
-      .send()
-      .await?
-      .take_items();
-    let patches = config
-      .stages
+    let gh_repo = host.load(&config.author, &config.repo).await?;
+
+    // Sorted, and stripped of what changes without the quest changing, like update times and
+    // avatars, so that packing the same repo twice gives the same package.
+    let mut issues = gh_repo.issues().clone();
+    issues.sort_by_key(|issue| issue.number);
+    let issues = issues
       .iter()
-      .enumerate()
-      .filter(|(_, stage)| !matches!(stage.no_starter, Some(true)))
-      .map(|(i, stage)| {
-        let prev_stage = (i > 0).then(|| &config.stages[i - 1]);
-        let base = match prev_stage {
-          Some(stage) => stage.branch_name(StagePart::Solution),
-          None => "main".into(),
-        };
-        let head = stage.branch_name(StagePart::Starter);
-        let patch = git_repo.diff(&base, &head)?;
-        Ok(Patch { base, head, patch })
-      })
+      .map(QuestIssue::try_from)
+      .collect::<Result<Vec<_>>>()?;
+    let mut prs = gh_repo.prs().clone();
+    prs.sort_by_key(|pr| pr.data.number);
+    for pr in &mut prs {
+      pr.comments.sort_by_key(|comment| comment.id);
+    }
+    let prs = prs
+      .iter()
+      .map(QuestPullRequest::try_from)
       .collect::<Result<Vec<_>>>()?;
+    let mut labels = gh_repo.labels().await?;
+    labels.sort_by(|a, b| a.name.cmp(&b.name));
 
-    Ok(QuestPackage {
-      version: version(),
+    let mut branches = vec![String::from("main")];
+    for stage in &config.stages {
+      if !stage.no_starter() {
+        branches.push(stage.branch_name(StagePart::Starter));
+      }
+      branches.push(stage.branch_name(StagePart::Solution));
+    }
+    let bundle = git_repo
+      .bundle(&branches)
+      .context("Failed to bundle quest branches")?;
+
+    Self::new(
       config,
-      initial,
       issues,
       prs,
+      PackageContents::Bundle { bundle },
       labels,
-      patches,
+    )
+  }
+
+  fn new(
+    config: QuestConfig,
+    issues: Vec<QuestIssue>,
+    prs: Vec<QuestPullRequest>,
+    contents: PackageContents,
+    labels: Vec<Label>,
+  ) -> Result<Self> {
+    let mut package = QuestPackage {
+      version: version(),
+      schema: SCHEMA_VERSION,
+      content_hash: String::new(),
+      config,
+      issues,
+      prs,
+      contents,
       patch_map: HashMap::default(),
-    })
+      labels,
+      migrations: Vec::new(),
+      trust: PackageTrust::Unsigned,
+    };
+    package.validate_paths()?;
+    package.index_patches();
+    package.content_hash = package.compute_content_hash()?;
+    Ok(package)
   }
 
-  pub fn patch(&self, key: &(String, String)) -> Option<usize> {
-    self.patch_map.get(key).copied()
+  fn compute_content_hash(&self) -> Result<String> {
+    let mut json = serde_json::to_value(self)?;
+    let package = json.as_object_mut().unwrap();
+    for field in ["version", "schema", "content_hash"] {
+      package.remove(field);
+    }
+    // Without serde_json's `preserve_order` feature, object keys are sorted.
+    Ok(utils::sha256(&serde_json::to_vec(&json)?))
   }
 
-  fn deserialize<T: Read>(t: T) -> Result<Self> {
-    let mut decoder = GzDecoder::new(t);
-    let mut package: QuestPackage =
-      serde_json::from_reader(&mut decoder).context("Failed to parse JSON")?;
-    package.patch_map = package
-      .patches
+  /// Checks the package's contents against its content hash, or records the hash if the
+  /// package was just migrated, since migrations change the contents.
+  fn check_content_hash(&mut self) -> Result<()> {
+    let content_hash = self.compute_content_hash()?;
+    if self.migrations.is_empty() {
+      ensure!(
+        self.content_hash == content_hash,
+        "The package's contents don't match its content hash"
+      );
+    } else {
+      self.content_hash = content_hash;
+    }
+    Ok(())
+  }
+
+  /// Normalizes the paths of the package's files, and fails if any of them, or any path in
+  /// its patches, could be written outside of the quest's repo.
+  fn validate_paths(&mut self) -> Result<()> {
+    // Reading a bundle's files takes a repo, so they are checked as the bundle is imported
+    // into one, before anything is checked out from it. See `GitRepo::import_bundle`.
+    let PackageContents::Files { initial, patches } = &mut self.contents else {
+      return Ok(());
+    };
+    let mut normalized = BTreeMap::new();
+    for (path, contents) in std::mem::take(initial) {
+      let path = paths::normalize(&path)?;
+      ensure!(
+        !normalized.contains_key(&path),
+        "Package contains the same file twice: {}",
+        path.display()
+      );
+      normalized.insert(path, contents);
+    }
+    *initial = normalized;
+    for patch in patches.iter() {
+      paths::check_patch(&patch.patch)
+        .with_context(|| format!("Invalid patch from {} to {}", patch.base, patch.head))?;
+    }
+    Ok(())
+  }
+
+  fn index_patches(&mut self) {
+    let PackageContents::Files { patches, .. } = &self.contents else {
+      return;
+    };
+    self.patch_map = patches
       .iter()
       .enumerate()
       .map(|(i, patch)| ((patch.base.clone(), patch.head.clone()), i))
       .collect();
-    let version = version();
-    let req = VersionReq::parse(&format!("^{version}")).unwrap();
-    if !req.matches(&package.version) {
-      tracing::warn!("Loaded package has potentially incompatible version: {version}");
-    }
+  }
+
+  pub fn patch(&self, key: &(String, String)) -> Option<usize> {
+    self.patch_map.get(key).copied()
+  }
+
+  /// The migrations applied to bring the package up to date when it was loaded.
+  pub fn migrations(&self) -> &[String] {
+    &self.migrations
+  }
+
+  /// Who signed the package, if it was loaded from a file. Packages loaded any other way are
+  /// considered unsigned.
+  pub fn trust(&self) -> &PackageTrust {
+    &self.trust
+  }
+
+  fn deserialize<T: Read>(t: T) -> Result<Self> {
+    let decoder = GzDecoder::new(t);
+    let mut json: Value = serde_json::from_reader(decoder).context("Failed to parse JSON")?;
+    let migrations = migrate(&mut json)?;
+    let mut package: QuestPackage =
+      serde_json::from_value(json).context("Failed to parse package")?;
+    package.validate_paths()?;
+    package.index_patches();
+    package.migrations = migrations;
+    package.check_content_hash()?;
+    Ok(package)
+  }
+
+  /// Loads the package at `path`, refusing it unless it's signed by an author in the learner's
+  /// trust store or `allow_untrusted` is set. Fails if the signature doesn't match either way.
+  pub fn load_trusted(path: &Path, allow_untrusted: bool) -> Result<Self> {
+    Self::load_trusted_with(path, &TrustStore::load()?, allow_untrusted)
+  }
+
+  pub(crate) fn load_trusted_with(
+    path: &Path,
+    store: &TrustStore,
+    allow_untrusted: bool,
+  ) -> Result<Self> {
+    let package = Self::load_with(path, store)?;
+    ensure!(
+      allow_untrusted || matches!(package.trust, PackageTrust::Trusted { .. }),
+      "Quest package is not signed by a trusted author: {}",
+      path.display()
+    );
     Ok(package)
   }
 
-  pub fn load_from_file(path: &Path) -> Result<Self> {
-    let mut f = BufReader::new(File::open(path)?);
-    Self::deserialize(&mut f)
-      .with_context(|| format!("Failed to load quest package: {}", path.display()))
+  fn load_with(path: &Path, store: &TrustStore) -> Result<Self> {
+    let contents = fs::read(path).with_context(|| format!("Failed to read: {}", path.display()))?;
+    let trust = store.verify(path, &contents)?;
+    let mut package = Self::deserialize(contents.as_slice())
+      .with_context(|| format!("Failed to load quest package: {}", path.display()))?;
+    package.trust = trust;
+    Ok(package)
   }
 
   pub fn load_from_blob(blob: &[u8]) -> Result<Self> {
     Self::deserialize(blob).context("Failed to load quest package from blob")
   }
 
+  /// Writes the package, giving the same bytes for the same package.
   pub fn save(&self, path: &Path) -> Result<()> {
-    let mut f = BufWriter::new(File::create(path)?);
-    let mut encoder = GzEncoder::new(&mut f, Compression::best());
+    let f = BufWriter::new(File::create(path)?);
+    // A zero mtime leaves the gzip header without a timestamp.
+    let mut encoder = GzBuilder::new().mtime(0).write(f, Compression::best());
     serde_json::to_writer_pretty(&mut encoder, self)?;
+    encoder.finish()?.flush()?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn migrate_unversioned() -> Result<()> {
+    let mut json = json!({
+      "initial": { "README.md": "# Quest\n" },
+      "patches": [{ "base": "main", "head": "s1-a", "patch": "" }],
+      "issues": [{
+        "number": 1,
+        "title": "Stage 1",
+        "body": "Do it",
+        "user": { "login": "author", "avatar_url": "https://example.com/a.png" },
+        "labels": [{ "id": 1, "name": "s1", "color": "fff" }],
+      }],
+      "labels": [{
+        "id": 1,
+        "node_id": "LA_1",
+        "url": "https://api.github.com/repos/author/quest/labels/s1",
+        "name": "s1",
+        "color": "fff",
+        "description": null,
+        "default": false,
+      }],
+      "prs": [{
+        "data": {
+          "number": 2,
+          "title": "s1 starter",
+          "body": "Starter",
+          "labels": null,
+          "head": { "ref": "s1-a", "sha": "abc" },
+          "base": { "ref": "main", "sha": "def" },
+        },
+        "comments": [{ "id": 3, "path": "src/lib.rs", "line": 4, "body": "Here" }],
+      }],
+    });
+    let applied = migrate(&mut json)?;
+    assert_eq!(applied.len(), MIGRATIONS.len());
+    assert_eq!(
+      json,
+      json!({
+        "schema": SCHEMA_VERSION,
+        "content_hash": "",
+        "issues": [{ "title": "Stage 1", "body": "Do it", "labels": ["s1"] }],
+        "prs": [{
+          "title": "s1 starter",
+          "body": "Starter",
+          "labels": [],
+          "head": "s1-a",
+          "base": "main",
+          "comments": [{ "path": "src/lib.rs", "line": 4, "body": "Here" }],
+        }],
+        "labels": [{ "name": "s1", "color": "fff", "description": null, "default": false }],
+        "contents": {
+          "type": "Files",
+          "initial": { "README.md": "# Quest\n" },
+          "patches": [{ "base": "main", "head": "s1-a", "patch": "" }],
+        },
+      })
+    );
+
+    // Up to date packages are left alone.
+    assert!(migrate(&mut json)?.is_empty());
     Ok(())
   }
+
+  #[test]
+  fn refuse_newer() {
+    let mut json = json!({ "schema": SCHEMA_VERSION + 1 });
+    let err = migrate(&mut json).unwrap_err();
+    assert!(err.to_string().contains("newer version of RepoQuest"));
+  }
 }
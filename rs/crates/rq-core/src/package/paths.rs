@@ -0,0 +1,206 @@
+//! Checks that the files a package writes stay inside the quest's repo, since packages come
+//! from arbitrary authors.
+
+use anyhow::{bail, ensure, Result};
+use std::{
+  fs,
+  path::{Component, Path, PathBuf},
+};
+
+/// Returns `path` without `.` components, or fails if it could point outside the repo or into
+/// its `.git` directory.
+pub fn normalize(path: &Path) -> Result<PathBuf> {
+  let display = path.display();
+  // On Windows, backslashes are separators, so they could hide any of the components below.
+  ensure!(
+    !path.to_string_lossy().contains('\\'),
+    "Package path contains a backslash: {display}"
+  );
+  let mut normalized = PathBuf::new();
+  for component in path.components() {
+    match component {
+      Component::Prefix(_) | Component::RootDir => bail!("Package path is absolute: {display}"),
+      Component::ParentDir => bail!("Package path leaves the quest directory: {display}"),
+      Component::CurDir => {}
+      Component::Normal(name) => {
+        // Case-insensitive filesystems treat `.GIT` as `.git`.
+        ensure!(
+          !name.eq_ignore_ascii_case(".git"),
+          "Package path is inside a `.git` directory: {display}"
+        );
+        normalized.push(name);
+      }
+    }
+  }
+  ensure!(
+    !normalized.as_os_str().is_empty(),
+    "Package path is empty: {display:?}"
+  );
+  Ok(normalized)
+}
+
+/// Fails if writing `rel_path` inside `root` would follow a symlink, which could lead
+/// anywhere on the learner's machine.
+pub fn ensure_no_symlinks(root: &Path, rel_path: &Path) -> Result<()> {
+  let mut path = root.to_path_buf();
+  for component in rel_path.components() {
+    path.push(component);
+    let is_symlink = fs::symlink_metadata(&path).is_ok_and(|meta| meta.is_symlink());
+    ensure!(
+      !is_symlink,
+      "Package path goes through a symlink: {}",
+      rel_path.display()
+    );
+  }
+  Ok(())
+}
+
+/// Undoes git's quoting of paths with unusual characters, e.g. `"caf\303\251"`.
+fn unquote(path: &str) -> String {
+  let Some(quoted) = path.strip_prefix('"').and_then(|p| p.strip_suffix('"')) else {
+    return path.to_string();
+  };
+  let mut bytes = Vec::new();
+  let mut rest = quoted.as_bytes();
+  while let Some((&b, tail)) = rest.split_first() {
+    rest = tail;
+    if b != b'\\' {
+      bytes.push(b);
+      continue;
+    }
+    let Some((&escaped, tail)) = rest.split_first() else {
+      break;
+    };
+    rest = tail;
+    match escaped {
+      b't' => bytes.push(b'\t'),
+      b'n' => bytes.push(b'\n'),
+      b'0'..=b'7' if rest.len() >= 2 => {
+        let octal = [escaped, rest[0], rest[1]];
+        rest = &rest[2..];
+        let value = std::str::from_utf8(&octal)
+          .ok()
+          .and_then(|octal| u8::from_str_radix(octal, 8).ok());
+        bytes.extend(value);
+      }
+      other => bytes.push(other),
+    }
+  }
+  String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Returns the path in a `diff --git a/<path> b/<path>` header. Renames have differing paths,
+/// which are instead read from the `rename from` and `rename to` lines.
+fn header_path(paths: &str) -> Option<String> {
+  let mid = paths.len() / 2;
+  if !paths.is_char_boundary(mid) || !paths.is_char_boundary(mid + 1) {
+    return None;
+  }
+  let (a, b) = (unquote(&paths[..mid]), unquote(&paths[mid + 1..]));
+  let (a, b) = (a.strip_prefix("a/")?, b.strip_prefix("b/")?);
+  (a == b).then(|| a.to_string())
+}
+
+/// Checks every path touched by a diff, and that the diff doesn't create symlinks, which
+/// would let later writes escape the repo.
+pub fn check_patch(patch: &str) -> Result<()> {
+  let check = |path: &str| normalize(Path::new(path)).map(|_| ());
+  // Lines inside hunks are file contents, which could look like anything.
+  let mut in_hunk = false;
+  for line in patch.lines() {
+    if let Some(paths) = line.strip_prefix("diff --git ") {
+      in_hunk = false;
+      if let Some(path) = header_path(paths) {
+        check(&path)?;
+      }
+    } else if in_hunk {
+      continue;
+    } else if line.starts_with("@@ ") {
+      in_hunk = true;
+    } else if let Some(path) = line
+      .strip_prefix("--- ")
+      .or_else(|| line.strip_prefix("+++ "))
+    {
+      let path = unquote(path.split('\t').next().unwrap_or_default());
+      if path == "/dev/null" {
+        continue;
+      }
+      let path = path
+        .strip_prefix("a/")
+        .or_else(|| path.strip_prefix("b/"))
+        .unwrap_or(&path);
+      check(path)?;
+    } else if let Some(path) = ["rename from ", "rename to ", "copy from ", "copy to "]
+      .iter()
+      .find_map(|prefix| line.strip_prefix(prefix))
+    {
+      check(&unquote(path))?;
+    } else if line == "new file mode 120000" || line == "new mode 120000" {
+      bail!("Starter code creates a symlink, which packages may not do");
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn paths() {
+    assert_eq!(
+      normalize(Path::new("./src/./main.rs")).unwrap(),
+      Path::new("src/main.rs")
+    );
+    for (path, error) in [
+      ("../../.bashrc", "leaves the quest directory"),
+      ("src/../../x", "leaves the quest directory"),
+      ("/etc/passwd", "is absolute"),
+      (".git/hooks/post-checkout", "inside a `.git` directory"),
+      ("sub/.GIT/config", "inside a `.git` directory"),
+      ("..\\x", "backslash"),
+      (".", "is empty"),
+    ] {
+      let err = normalize(Path::new(path)).unwrap_err().to_string();
+      assert!(err.contains(error), "{path}: {err}");
+    }
+  }
+
+  #[test]
+  fn patches() {
+    let patch = r#"diff --git a/src/lib.rs b/src/lib.rs
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -1 +1 @@
+--- a/looks/like/a/header
++fn main() {}
+diff --git "a/caf\303\251.txt" "b/caf\303\251.txt"
+new file mode 100644
+--- /dev/null
++++ "b/caf\303\251.txt"
+"#;
+    check_patch(patch).unwrap();
+
+    let escaping = "diff --git a/x b/x\n--- a/x\n+++ b/../../x\n";
+    let err = check_patch(escaping).unwrap_err().to_string();
+    assert!(err.contains("leaves the quest directory"), "{err}");
+
+    let renamed = "diff --git a/x b/.git/config\nrename from x\nrename to .git/config\n";
+    assert!(check_patch(renamed).is_err());
+
+    let symlink = "diff --git a/link b/link\nnew file mode 120000\n";
+    assert!(check_patch(symlink).is_err());
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn symlinks() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let root = dir.path().join("repo");
+    fs::create_dir_all(root.join("src"))?;
+    std::os::unix::fs::symlink(dir.path(), root.join("escape"))?;
+    ensure_no_symlinks(&root, Path::new("src/main.rs"))?;
+    assert!(ensure_no_symlinks(&root, Path::new("escape/.bashrc")).is_err());
+    Ok(())
+  }
+}
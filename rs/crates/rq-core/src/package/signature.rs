@@ -0,0 +1,243 @@
+//! Detached ed25519 signatures for quest packages, so that learners can tell whether a
+//! package really comes from an author they trust before its hooks run on their machine.
+//!
+//! A package `quest.json.gz` is signed by `quest.json.gz.sig`, which holds the signer's
+//! public key and a signature over the package file's bytes.
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::{
+  rand::SystemRandom,
+  signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519},
+};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use std::{
+  fs,
+  path::{Path, PathBuf},
+};
+
+use crate::utils::write_private;
+
+/// Whether a package was signed by an author in the learner's trust store.
+#[derive(Serialize, Deserialize, Type, Clone, Debug, PartialEq, Eq, Default)]
+#[serde(tag = "type")]
+pub enum PackageTrust {
+  Trusted {
+    author: String,
+  },
+  /// Signed, but by a key that isn't in the trust store.
+  Untrusted {
+    key: String,
+  },
+  #[default]
+  Unsigned,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DetachedSignature {
+  /// The signer's public key, in base64.
+  key: String,
+  /// In base64.
+  signature: String,
+}
+
+/// Returns where the signature of the package at `path` is kept.
+pub fn signature_path(path: &Path) -> PathBuf {
+  let mut sig_path = path.as_os_str().to_owned();
+  sig_path.push(".sig");
+  PathBuf::from(sig_path)
+}
+
+fn home_file(name: &str) -> Result<PathBuf> {
+  let home = home::home_dir().context("Failed to find home directory")?;
+  Ok(home.join(name))
+}
+
+/// An author's key for signing packages, read from `~/.rqst-signing-key.toml`.
+pub struct SigningKey(Ed25519KeyPair);
+
+#[derive(Serialize, Deserialize)]
+struct SigningKeyFile {
+  /// The PKCS#8 encoding of the key pair, in base64.
+  pkcs8: String,
+}
+
+impl SigningKey {
+  /// Loads the author's signing key, generating one on first use.
+  pub fn load_or_generate() -> Result<Self> {
+    Self::load_or_generate_at(&home_file(".rqst-signing-key.toml")?)
+  }
+
+  pub(crate) fn load_or_generate_at(path: &Path) -> Result<Self> {
+    let pkcs8 = if path.exists() {
+      let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read: {}", path.display()))?;
+      let file: SigningKeyFile = toml::from_str(&contents)
+        .with_context(|| format!("Failed to parse signing key: {}", path.display()))?;
+      STANDARD
+        .decode(file.pkcs8)
+        .with_context(|| format!("Invalid signing key: {}", path.display()))?
+    } else {
+      let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .map_err(|_| anyhow!("Failed to generate signing key"))?;
+      let file = SigningKeyFile {
+        pkcs8: STANDARD.encode(pkcs8.as_ref()),
+      };
+      // Only the author should be able to read their private key.
+      write_private(path, &toml::to_string_pretty(&file)?)?;
+      pkcs8.as_ref().to_vec()
+    };
+    let pair = Ed25519KeyPair::from_pkcs8(&pkcs8)
+      .map_err(|e| anyhow!("Invalid signing key: {}: {e}", path.display()))?;
+    Ok(SigningKey(pair))
+  }
+
+  /// The key that learners add to their trust store, in base64.
+  pub fn public_key(&self) -> String {
+    STANDARD.encode(self.0.public_key().as_ref())
+  }
+
+  /// Writes a signature for the package at `path` next to it.
+  pub fn sign(&self, path: &Path) -> Result<PathBuf> {
+    let contents = fs::read(path).with_context(|| format!("Failed to read: {}", path.display()))?;
+    let signature = DetachedSignature {
+      key: self.public_key(),
+      signature: STANDARD.encode(self.0.sign(&contents).as_ref()),
+    };
+    let sig_path = signature_path(path);
+    fs::write(&sig_path, serde_json::to_string_pretty(&signature)?)
+      .with_context(|| format!("Failed to write: {}", sig_path.display()))?;
+    Ok(sig_path)
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+struct TrustedAuthor {
+  name: String,
+  /// The author's public key, in base64.
+  key: String,
+}
+
+/// The authors whose packages a learner trusts, read from `~/.rqst-trusted-keys.toml`.
+#[derive(Serialize, Deserialize, Default)]
+pub struct TrustStore {
+  #[serde(default)]
+  authors: Vec<TrustedAuthor>,
+  #[serde(skip)]
+  path: PathBuf,
+}
+
+impl TrustStore {
+  pub fn load() -> Result<Self> {
+    Self::load_from(&home_file(".rqst-trusted-keys.toml")?)
+  }
+
+  pub(crate) fn load_from(path: &Path) -> Result<Self> {
+    let mut store = if path.exists() {
+      let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read: {}", path.display()))?;
+      toml::from_str(&contents)
+        .with_context(|| format!("Failed to parse trust store: {}", path.display()))?
+    } else {
+      TrustStore::default()
+    };
+    store.path = path.to_path_buf();
+    Ok(store)
+  }
+
+  /// Trusts packages signed by `key`, replacing any other key for an author named `name`.
+  pub fn trust(&mut self, name: &str, key: &str) -> Result<()> {
+    let decoded = STANDARD
+      .decode(key)
+      .context("Public key is not valid base64")?;
+    if decoded.len() != 32 {
+      bail!("Public key is not an ed25519 key");
+    }
+    self.authors.retain(|author| author.name != name);
+    self.authors.push(TrustedAuthor {
+      name: name.to_string(),
+      key: key.to_string(),
+    });
+    fs::write(&self.path, toml::to_string_pretty(self)?)
+      .with_context(|| format!("Failed to write: {}", self.path.display()))
+  }
+
+  /// Checks the signature of the package at `path`, whose contents are `contents`. Fails if
+  /// the package has a signature that doesn't match it.
+  pub fn verify(&self, path: &Path, contents: &[u8]) -> Result<PackageTrust> {
+    let sig_path = signature_path(path);
+    if !sig_path.exists() {
+      return Ok(PackageTrust::Unsigned);
+    }
+    let sig_str = fs::read_to_string(&sig_path)
+      .with_context(|| format!("Failed to read: {}", sig_path.display()))?;
+    let signature: DetachedSignature = serde_json::from_str(&sig_str)
+      .with_context(|| format!("Failed to parse signature: {}", sig_path.display()))?;
+
+    let key = STANDARD
+      .decode(&signature.key)
+      .context("Invalid public key in signature")?;
+    let sig_bytes = STANDARD
+      .decode(&signature.signature)
+      .context("Invalid signature")?;
+    UnparsedPublicKey::new(&ED25519, key)
+      .verify(contents, &sig_bytes)
+      .map_err(|_| {
+        anyhow!(
+          "The package does not match its signature, so it may have been tampered with: {}",
+          path.display()
+        )
+      })?;
+
+    let author = self
+      .authors
+      .iter()
+      .find(|author| author.key == signature.key);
+    Ok(match author {
+      Some(author) => PackageTrust::Trusted {
+        author: author.name.clone(),
+      },
+      None => PackageTrust::Untrusted { key: signature.key },
+    })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn verify() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let package = dir.path().join("quest.json.gz");
+    fs::write(&package, b"package")?;
+    let mut store = TrustStore::load_from(&dir.path().join("trusted.toml"))?;
+    assert_eq!(store.verify(&package, b"package")?, PackageTrust::Unsigned);
+
+    let key = SigningKey::load_or_generate_at(&dir.path().join("key.toml"))?;
+    key.sign(&package)?;
+    assert_eq!(
+      store.verify(&package, b"package")?,
+      PackageTrust::Untrusted {
+        key: key.public_key()
+      }
+    );
+
+    store.trust("Author", &key.public_key())?;
+    let store = TrustStore::load_from(&dir.path().join("trusted.toml"))?;
+    assert_eq!(
+      store.verify(&package, b"package")?,
+      PackageTrust::Trusted {
+        author: "Author".into()
+      }
+    );
+
+    // The same key is loaded again rather than replaced.
+    let key2 = SigningKey::load_or_generate_at(&dir.path().join("key.toml"))?;
+    assert_eq!(key.public_key(), key2.public_key());
+
+    assert!(store.verify(&package, b"tampered").is_err());
+    Ok(())
+  }
+}
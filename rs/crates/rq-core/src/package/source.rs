@@ -0,0 +1,208 @@
+//! Builds packages from a quest's source files, so that quests can be written without a
+//! forge. A source directory looks like:
+//!
+//! ```text
+//! rqst.toml                  the quest configuration, with the list of stages
+//! final.toml                 the final quiz, if any
+//! initial/                   the code on `main` when the quest starts
+//! stages/<label>/issue.md    the stage's issue
+//! stages/<label>/starter.md  the starter code PR, unless the stage has no starter code
+//! stages/<label>/starter.patch
+//! stages/<label>/solution.md the reference solution PR
+//! stages/<label>/solution.patch
+//! ```
+//!
+//! Each patch is a diff from the code before that part of the stage, e.g. from the previous
+//! stage's solution to this stage's starter code. Markdown files begin with TOML front matter
+//! between `+++` lines, holding the `title` and, for PRs, review comments:
+//!
+//! ```text
+//! +++
+//! title = "Add a parser"
+//!
+//! [[comments]]
+//! path = "src/parser.rs"
+//! line = 12
+//! body = "Start here."
+//! +++
+//! The PR's description.
+//! ```
+//!
+//! Since the code is stored as text, binary files and file modes are lost.
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::Deserialize;
+use std::{
+  collections::BTreeMap,
+  fs,
+  path::{Path, PathBuf},
+};
+
+use super::{PackageContents, Patch, QuestPackage};
+use crate::{
+  forge::{Label, QuestIssue, QuestPullRequest, QuestReviewComment},
+  quest::QuestConfig,
+  stage::StagePart,
+};
+
+const LABEL_COLOR: &str = "ededed";
+
+/// Whether `dir` holds a quest's source files rather than a quest repo.
+pub fn is_source(dir: &Path) -> bool {
+  dir.join("rqst.toml").is_file() && dir.join("stages").is_dir()
+}
+
+fn read(path: &Path) -> Result<String> {
+  fs::read_to_string(path).with_context(|| format!("Failed to read: {}", path.display()))
+}
+
+/// Splits a markdown file into its front matter and its body.
+fn split_front_matter(text: &str) -> Result<(&str, &str)> {
+  let rest = text
+    .strip_prefix("+++\n")
+    .or_else(|| text.strip_prefix("+++\r\n"))
+    .context("Missing front matter, which starts with a `+++` line")?;
+  let mut offset = 0;
+  for line in rest.split_inclusive('\n') {
+    if line.trim_end() == "+++" {
+      let front_matter = &rest[..offset];
+      let body = &rest[offset + line.len()..];
+      return Ok((front_matter, body.trim()));
+    }
+    offset += line.len();
+  }
+  bail!("Front matter is missing its closing `+++` line")
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FrontMatter {
+  title: String,
+  #[serde(default)]
+  comments: Vec<QuestReviewComment>,
+}
+
+fn read_markdown(path: &Path) -> Result<(FrontMatter, String)> {
+  let text = read(path)?;
+  let (front_matter, body) =
+    split_front_matter(&text).with_context(|| format!("Invalid markdown: {}", path.display()))?;
+  let front_matter = toml::from_str(front_matter)
+    .with_context(|| format!("Failed to parse front matter: {}", path.display()))?;
+  Ok((front_matter, body.to_string()))
+}
+
+/// Reads every file under `dir`, keyed by its path relative to `root`.
+fn read_tree(root: &Path, dir: &Path, files: &mut BTreeMap<PathBuf, String>) -> Result<()> {
+  let entries = fs::read_dir(dir).with_context(|| format!("Failed to read: {}", dir.display()))?;
+  for entry in entries {
+    let path = entry?.path();
+    if path.is_dir() {
+      read_tree(root, &path, files)?;
+    } else {
+      let contents = read(&path).context("Source quests can only contain text files")?;
+      files.insert(path.strip_prefix(root)?.to_path_buf(), contents);
+    }
+  }
+  Ok(())
+}
+
+impl QuestPackage {
+  /// Compiles the quest source in `dir` into a package, without contacting a forge.
+  pub fn build_from_source(dir: &Path) -> Result<Self> {
+    let quiz_path = dir.join("final.toml");
+    let quiz_str = quiz_path.exists().then(|| read(&quiz_path)).transpose()?;
+    let config = QuestConfig::parse(&read(&dir.join("rqst.toml"))?, quiz_str.as_deref())?;
+
+    let initial_dir = dir.join("initial");
+    let mut initial = BTreeMap::new();
+    if initial_dir.is_dir() {
+      read_tree(&initial_dir, &initial_dir, &mut initial)?;
+    }
+    ensure!(
+      !initial.is_empty(),
+      "Quest source has no initial code in: {}",
+      initial_dir.display()
+    );
+
+    let mut issues = Vec::new();
+    let mut prs = Vec::new();
+    let mut patches = Vec::new();
+    let mut labels = Vec::new();
+    let mut base = String::from("main");
+    for stage in &config.stages {
+      let stage_dir = dir.join("stages").join(&stage.label);
+      ensure!(
+        stage_dir.is_dir(),
+        "Missing source for stage {}: {}",
+        stage.label,
+        stage_dir.display()
+      );
+      labels.push(Label {
+        name: stage.label.clone(),
+        color: LABEL_COLOR.into(),
+        description: None,
+        default: false,
+      });
+
+      let (front_matter, body) = read_markdown(&stage_dir.join("issue.md"))?;
+      ensure!(
+        front_matter.comments.is_empty(),
+        "Issues can't have review comments: {}",
+        stage_dir.join("issue.md").display()
+      );
+      issues.push(QuestIssue {
+        title: front_matter.title,
+        body,
+        labels: vec![stage.label.clone()],
+      });
+
+      let mut parts = vec![(StagePart::Solution, "solution")];
+      if !stage.no_starter() {
+        parts.insert(0, (StagePart::Starter, "starter"));
+      }
+      for (part, name) in parts {
+        let head = stage.branch_name(part);
+        let (front_matter, body) = read_markdown(&stage_dir.join(format!("{name}.md")))?;
+        prs.push(QuestPullRequest {
+          title: front_matter.title,
+          body,
+          labels: vec![stage.label.clone()],
+          head: head.clone(),
+          base: base.clone(),
+          comments: front_matter.comments,
+        });
+        patches.push(Patch {
+          base: base.clone(),
+          head: head.clone(),
+          patch: read(&stage_dir.join(format!("{name}.patch")))?,
+        });
+        base = head;
+      }
+    }
+
+    Self::new(
+      config,
+      issues,
+      prs,
+      PackageContents::Files { initial, patches },
+      labels,
+    )
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn front_matter() -> Result<()> {
+    let text = "+++\ntitle = \"Stage 1\"\n+++\n\nDo the thing.\n";
+    assert_eq!(
+      split_front_matter(text)?,
+      ("title = \"Stage 1\"\n", "Do the thing.")
+    );
+    assert!(split_front_matter("Do the thing.\n").is_err());
+    assert!(split_front_matter("+++\ntitle = \"Stage 1\"\n").is_err());
+    Ok(())
+  }
+}
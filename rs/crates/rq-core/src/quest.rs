@@ -1,23 +1,25 @@
 use std::{borrow::Cow, collections::HashMap, path::PathBuf, time::Duration};
 
 use crate::{
+  forge::{Forge, ForgeHost, GitProtocol, Issue, IssueState, PullRequest, PullSelector, RateLimit},
   git::{GitRepo, UPSTREAM},
-  github::{self, load_user, GithubRepo, PullSelector},
+  hooks::{HookDecision, HookSet},
   package::QuestPackage,
   stage::{Stage, StagePart, StagePartStatus},
   template::{InstanceOutputs, PackageTemplate, QuestTemplate, RepoTemplate},
 };
-use anyhow::{Context, Result};
-use http::StatusCode;
-use octocrab::{
-  models::{issues::Issue, pulls::PullRequest, IssueState},
-  params::{issues, pulls, Direction},
-  GitHubError,
-};
+use anyhow::{ensure, Context, Result};
+use chrono::Utc;
+use parking_lot::Mutex;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use specta::Type;
-use tokio::{time::sleep, try_join};
+use tokio::time::sleep;
+use tracing::warn;
+
+use self::cache::{QuestCache, RepoSnapshot};
+
+mod cache;
 
 pub trait StateEmitter: Send + Sync + 'static {
   fn emit(&self, state: StateDescriptor) -> Result<()>;
@@ -58,13 +60,22 @@ impl QuestConfig {
       None => Cow::Borrowed("meta"),
     };
     let config_str = repo.read_file(&branch, "rqst.toml")?;
-    let mut config = toml::de::from_str::<QuestConfig>(&config_str)
+    let quiz_str = if repo.contains_file(&branch, "final.toml")? {
+      Some(repo.read_file(&branch, "final.toml")?)
+    } else {
+      None
+    };
+    Self::parse(&config_str, quiz_str.as_deref())
+  }
+
+  /// Parses the contents of `rqst.toml`, and of `final.toml` if the quest has a final quiz.
+  pub fn parse(config_str: &str, quiz_str: Option<&str>) -> Result<Self> {
+    let mut config = toml::de::from_str::<QuestConfig>(config_str)
       .context("Failed to parse quest configuration rqst.toml")?;
 
-    if repo.contains_file(&branch, "final.toml")? {
-      let quiz_str = repo.read_file(&branch, "final.toml")?;
+    if let Some(quiz_str) = quiz_str {
       let quiz =
-        toml::de::from_str::<serde_json::Value>(&quiz_str).context("Failed to parse final.toml")?;
+        toml::de::from_str::<serde_json::Value>(quiz_str).context("Failed to parse final.toml")?;
       config.r#final = Some(quiz);
     }
 
@@ -85,11 +96,14 @@ pub enum QuestState {
 
 pub struct Quest {
   template: Box<dyn QuestTemplate>,
-  origin: GithubRepo,
+  origin: Box<dyn Forge>,
   origin_git: GitRepo,
   stage_index: HashMap<String, usize>,
   dir: PathBuf,
   state_event: Box<dyn StateEmitter>,
+  last_state: Mutex<Option<StateDescriptor>>,
+  connectivity: Mutex<Connectivity>,
+  pending_hooks: Mutex<Option<HookSet>>,
 
   pub config: QuestConfig,
 }
@@ -101,11 +115,38 @@ pub struct StateDescriptor {
   state: QuestState,
   can_skip: bool,
   behind_origin: bool,
+  local: bool,
+  rate_limit: Option<RateLimit>,
+  connectivity: Connectivity,
+  /// Hooks that won't run until the learner allows or denies them.
+  pending_hooks: Option<HookSet>,
+}
+
+/// Whether the quest's state is up to date with the forge.
+#[derive(Serialize, Deserialize, Clone, Debug, Type, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub enum Connectivity {
+  Online,
+  /// The last `attempts` updates failed, so the state is from before the first failure.
+  Retrying {
+    error: String,
+    attempts: u32,
+  },
+  /// The forge has been unreachable since the quest was opened, so the state is from the
+  /// quest's cache.
+  Offline,
 }
 
+/// How often the state is updated while the forge is reachable.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Bounds on the delay before retrying a failed update, which doubles with each failure.
+const MIN_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
 pub enum CreateSource {
   Remote { user: String, repo: String },
-  Package(QuestPackage),
+  Package(Box<QuestPackage>),
 }
 
 impl Quest {
@@ -114,8 +155,9 @@ impl Quest {
     config: QuestConfig,
     state_event: Box<dyn StateEmitter>,
     template: Box<dyn QuestTemplate>,
-    origin: GithubRepo,
+    origin: Box<dyn Forge>,
     origin_git: GitRepo,
+    cached_state: Option<StateDescriptor>,
   ) -> Result<Self> {
     let stage_index = config
       .stages
@@ -132,35 +174,57 @@ impl Quest {
       origin_git,
       stage_index,
       state_event,
+      connectivity: Mutex::new(if cached_state.is_some() {
+        Connectivity::Offline
+      } else {
+        Connectivity::Online
+      }),
+      last_state: Mutex::new(cached_state),
+      pending_hooks: Mutex::new(None),
     };
+    q.sync_hooks()?;
 
-    q.infer_state_update().await?;
+    if let Err(e) = q.infer_state_update().await {
+      if !q.is_offline() {
+        return Err(e);
+      }
+      warn!("Forge is unreachable, opening quest from its cache: {e:?}");
+    }
 
     Ok(q)
   }
 
+  /// Instantiates a quest from `source` in `dir`. If `protocol` is not given, SSH is used
+  /// when it works and HTTPS otherwise.
   pub async fn create(
     dir: PathBuf,
     source: CreateSource,
+    host: &dyn ForgeHost,
+    protocol: Option<GitProtocol>,
     state_event: Box<dyn StateEmitter>,
   ) -> Result<Self> {
-    github::check_ssh()?;
+    let protocol = match protocol {
+      Some(GitProtocol::Ssh) => {
+        host.check_ssh()?;
+        GitProtocol::Ssh
+      }
+      Some(GitProtocol::Https) => GitProtocol::Https,
+      None => host.detect_protocol(),
+    };
 
     let template: Box<dyn QuestTemplate> = match source {
       CreateSource::Remote { user, repo } => {
-        let upstream = GithubRepo::load(&user, &repo).await?;
+        let upstream = host.load(&user, &repo).await?;
         Box::new(RepoTemplate(upstream))
       }
-      CreateSource::Package(package) => Box::new(PackageTemplate(package)),
+      CreateSource::Package(package) => Box::new(PackageTemplate(*package)),
     };
 
     let InstanceOutputs {
       origin,
       origin_git,
       config,
-    } = template.instantiate(&dir).await?;
-
-    origin_git.install_hooks()?;
+    } = template.instantiate(host, &dir, protocol).await?;
 
     Self::load_core(
       dir.join(&config.repo),
@@ -169,24 +233,49 @@ impl Quest {
       template,
       origin,
       origin_git,
+      None,
     )
     .await
   }
 
-  pub async fn load(dir: PathBuf, state_event: Box<dyn StateEmitter>) -> Result<Self> {
-    let user = load_user().await?;
+  /// Opens the quest in `dir`. If the forge can't be reached, the quest is opened from what
+  /// the last session fetched, and catches up once the forge is back.
+  pub async fn load(
+    dir: PathBuf,
+    host: &dyn ForgeHost,
+    state_event: Box<dyn StateEmitter>,
+  ) -> Result<Self> {
     let origin_git = GitRepo::new(&dir);
     let upstream = origin_git
       .upstream()
       .context("Failed to test for upstream")?;
     let config = QuestConfig::load(&origin_git, upstream).context("Failed to load quest config")?;
-    let origin = GithubRepo::load(&user, &config.repo)
-      .await
-      .context("Failed to load GitHub repo")?;
-    let template: Box<dyn QuestTemplate> = if upstream.is_some() {
-      let upstream = GithubRepo::load(&config.author, &config.repo)
-        .await
-        .context("Failed to load upstream GitHub repo")?;
+
+    let (origin, upstream, cached_state) =
+      match Self::load_forges(host, &config, upstream.is_some()).await {
+        Ok((origin, upstream)) => (origin, upstream, None),
+        Err(e) => {
+          let cache = match QuestCache::load(&dir) {
+            Ok(Some(cache)) => cache,
+            Ok(None) => return Err(e),
+            Err(cache_err) => {
+              warn!("Failed to load quest cache: {cache_err:?}");
+              return Err(e);
+            }
+          };
+          warn!("Opening quest from its cache: {e:?}");
+          let origin = host.repo(&cache.user, &config.repo);
+          cache.origin.restore(&*origin);
+          let upstream = cache.upstream.map(|snapshot| {
+            let upstream = host.repo(&config.author, &config.repo);
+            snapshot.restore(&*upstream);
+            upstream
+          });
+          (origin, upstream, Some(cache.state))
+        }
+      };
+
+    let template: Box<dyn QuestTemplate> = if let Some(upstream) = upstream {
       Box::new(RepoTemplate(upstream))
     } else {
       let contents = origin_git.show_bin("meta", "package.json.gz")?;
@@ -195,7 +284,39 @@ impl Quest {
       Box::new(PackageTemplate(package))
     };
 
-    Self::load_core(dir, config, state_event, template, origin, origin_git).await
+    Self::load_core(
+      dir,
+      config,
+      state_event,
+      template,
+      origin,
+      origin_git,
+      cached_state,
+    )
+    .await
+  }
+
+  async fn load_forges(
+    host: &dyn ForgeHost,
+    config: &QuestConfig,
+    has_upstream: bool,
+  ) -> Result<(Box<dyn Forge>, Option<Box<dyn Forge>>)> {
+    let user = host.current_user().await?;
+    let origin = host
+      .load(&user, &config.repo)
+      .await
+      .context("Failed to load origin repo")?;
+    origin.check_access().await?;
+    let upstream = if has_upstream {
+      let upstream = host
+        .load(&config.author, &config.repo)
+        .await
+        .context("Failed to load upstream repo")?;
+      Some(upstream)
+    } else {
+      None
+    };
+    Ok((origin, upstream))
   }
 
   pub fn stages(&self) -> &[Stage] {
@@ -207,7 +328,7 @@ impl Quest {
   }
 
   fn parse_stage(&self, pr: &PullRequest) -> Option<(Stage, StagePart)> {
-    let branch = &pr.head.ref_field;
+    let branch = &pr.head;
     let re = Regex::new("^(.*)-([abc])$").unwrap();
     let (_, [name, part_str]) = re.captures(branch)?.extract();
     let stage = self.stage_index.get(name)?;
@@ -215,55 +336,16 @@ impl Quest {
     Some((self.stage(*stage).clone(), part))
   }
 
-  async fn infer_state(&self) -> Result<QuestState> {
-    let pr_handler = self.origin.pr_handler();
-    let pr_page_future = pr_handler
-      .list()
-      .state(octocrab::params::State::All)
-      .sort(pulls::Sort::Created)
-      .direction(Direction::Descending)
-      .per_page(10)
-      .send();
-
-    let issue_handler = self.origin.issue_handler();
-    let issue_page_future = issue_handler
-      .list()
-      .state(octocrab::params::State::All)
-      .sort(issues::Sort::Created)
-      .direction(Direction::Descending)
-      .per_page(10)
-      .send();
-
-    let (mut pr_page, mut issue_page) = match try_join!(pr_page_future, issue_page_future) {
-      Ok(result) => result,
-      Err(octocrab::Error::GitHub {
-        source: GitHubError {
-          status_code: StatusCode::NOT_FOUND,
-          ..
-        },
-        ..
-      }) => {
-        return Ok(QuestState::Ongoing {
-          stage: 0,
-          part: StagePart::Starter,
-          status: StagePartStatus::Start,
-        })
-      }
-      Err(e) => return Err(e.into()),
-    };
-
-    let prs = pr_page.take_items();
-    let issues = issue_page.take_items();
+  /// Infers the state from the PRs and issues that the origin last fetched.
+  fn infer_state(&self) -> QuestState {
+    let prs = self.origin.prs();
+    let issues = self.origin.issues();
 
     let issue_map = issues
-      .into_iter()
+      .iter()
       .filter_map(|issue| {
         let label = issue.labels.first()?;
-        if issue.pull_request.is_none() {
-          Some((label.name.clone(), issue))
-        } else {
-          None
-        }
+        Some((label.clone(), issue))
       })
       .collect::<HashMap<_, _>>();
 
@@ -274,8 +356,8 @@ impl Quest {
       .collect::<HashMap<_, _>>();
 
     let pr_stages = prs.iter().filter_map(|pr| {
-      let (stage, part) = self.parse_stage(pr)?;
-      let finished = pr.merged_at.is_some()
+      let (stage, part) = self.parse_stage(&pr.data)?;
+      let finished = pr.data.merged_at.is_some()
         && match part {
           StagePart::Solution => {
             let issue = issue_map.get(&stage.label)?;
@@ -304,16 +386,16 @@ impl Quest {
       .chain(issue_stages)
       .max_by_key(|(stage, part, finished)| (stage_idx(stage), *part, *finished))
     else {
-      return Ok(QuestState::Ongoing {
+      return QuestState::Ongoing {
         stage: 0,
         part: StagePart::Starter,
         status: StagePartStatus::Start,
-      });
+      };
     };
 
     let stage = stage_idx(&stage);
 
-    Ok(if finished {
+    if finished {
       match part.next_part() {
         Some(next_part) => QuestState::Ongoing {
           stage: stage as u32,
@@ -338,11 +420,20 @@ impl Quest {
         part,
         status: StagePartStatus::Ongoing,
       }
-    })
+    }
   }
 
   pub async fn state_descriptor(&self) -> Result<StateDescriptor> {
-    let state = self.infer_state().await?;
+    if self.is_offline() {
+      if let Some(state) = self.last_known_state() {
+        return Ok(state);
+      }
+    }
+    self.fetch_state_descriptor().await
+  }
+
+  async fn fetch_state_descriptor(&self) -> Result<StateDescriptor> {
+    let state = self.infer_state();
     let behind_origin = self.origin_git.is_behind_origin()?;
     Ok(StateDescriptor {
       dir: self.dir.clone(),
@@ -350,22 +441,173 @@ impl Quest {
       state,
       can_skip: self.template.can_skip(),
       behind_origin,
+      local: self.origin.is_local(),
+      rate_limit: self.origin.rate_limit(),
+      connectivity: Connectivity::Online,
+      pending_hooks: self.pending_hooks.lock().clone(),
     })
   }
 
+  fn is_offline(&self) -> bool {
+    *self.connectivity.lock() == Connectivity::Offline
+  }
+
+  /// The last state fetched from the forge, with the current connectivity.
+  fn last_known_state(&self) -> Option<StateDescriptor> {
+    let mut state = self.last_state.lock().clone()?;
+    state.connectivity = self.connectivity.lock().clone();
+    state.pending_hooks = self.pending_hooks.lock().clone();
+    Some(state)
+  }
+
+  /// Quests opened from their cache are read-only, since their state may be out of date.
+  fn ensure_online(&self) -> Result<()> {
+    ensure!(
+      !self.is_offline(),
+      "Can't change the quest while the forge is unreachable"
+    );
+    Ok(())
+  }
+
+  fn save_cache(&self, state: &StateDescriptor) -> Result<()> {
+    let cache = QuestCache {
+      user: self.origin.user().to_string(),
+      origin: RepoSnapshot::of(&*self.origin),
+      upstream: self.template.upstream().map(RepoSnapshot::of),
+      state: state.clone(),
+    };
+    cache.save(&self.dir)
+  }
+
+  /// While the forge is rate limiting us, re-emits the last state with the time that requests
+  /// resume, rather than failing. Returns false if not rate limited.
+  fn emit_paused(&self) -> Result<bool> {
+    let Some(rate_limit) = self.origin.rate_limit() else {
+      return Ok(false);
+    };
+    if !rate_limit.is_paused(Utc::now()) {
+      return Ok(false);
+    }
+    let Some(mut state) = self.last_known_state() else {
+      return Ok(false);
+    };
+    state.rate_limit = Some(rate_limit);
+    self.state_event.emit(state)?;
+    Ok(true)
+  }
+
+  /// Installs the quest's hooks if the learner allowed them, and otherwise makes sure that
+  /// none run. Hooks the learner hasn't decided on yet are kept in `pending_hooks`.
+  ///
+  /// The hooks can change whenever the checked-out branch does, so this runs after every
+  /// checkout or reset that RepoQuest makes.
+  fn sync_hooks(&self) -> Result<()> {
+    let hooks = HookSet::read(&self.dir).context("Failed to read quest hooks")?;
+    let mut pending = None;
+    match hooks {
+      Some(hooks) => match hooks.decision(&self.origin_git)? {
+        Some(HookDecision::Allow) => self.origin_git.install_hooks(&hooks)?,
+        Some(HookDecision::Deny) => self.origin_git.uninstall_hooks()?,
+        None => {
+          self.origin_git.uninstall_hooks()?;
+          pending = Some(hooks);
+        }
+      },
+      None => self.origin_git.uninstall_hooks()?,
+    }
+    *self.pending_hooks.lock() = pending;
+    Ok(())
+  }
+
+  /// Records the learner's decision about the pending hooks, whose hash is `sha256`, and
+  /// installs them if they were allowed.
+  pub async fn decide_hooks(&self, sha256: &str, decision: HookDecision) -> Result<()> {
+    let hooks = self
+      .pending_hooks
+      .lock()
+      .clone()
+      .context("No hooks are waiting for a decision")?;
+    ensure!(
+      hooks.sha256 == sha256,
+      "The quest's hooks changed, please review them again"
+    );
+    hooks.record(&self.origin_git, decision)?;
+    self.sync_hooks()?;
+
+    if self.is_offline() {
+      if let Some(state) = self.last_known_state() {
+        return self.state_event.emit(state);
+      }
+    }
+    self.infer_state_update().await
+  }
+
   pub async fn infer_state_update(&self) -> Result<()> {
-    self.origin_git.fetch("origin")?;
-    self.origin.fetch().await?;
-    let state = self.state_descriptor().await?;
+    if self.emit_paused()? {
+      return Ok(());
+    }
+
+    let result = async {
+      self.origin_git.fetch("origin")?;
+      ensure!(
+        self.origin.fetch().await?,
+        "The quest's repo {}/{} no longer exists",
+        self.origin.user(),
+        self.origin.name()
+      );
+      self.fetch_state_descriptor().await
+    };
+    let state = match result.await {
+      Ok(state) => state,
+      Err(_) if self.emit_paused()? => return Ok(()),
+      Err(e) => {
+        {
+          let mut connectivity = self.connectivity.lock();
+          let attempts = match &*connectivity {
+            Connectivity::Online => Some(1),
+            Connectivity::Retrying { attempts, .. } => Some(attempts + 1),
+            Connectivity::Offline => None,
+          };
+          if let Some(attempts) = attempts {
+            *connectivity = Connectivity::Retrying {
+              error: format!("{e:#}"),
+              attempts,
+            };
+          }
+        }
+        if let Some(state) = self.last_known_state() {
+          self.state_event.emit(state)?;
+        }
+        return Err(e);
+      }
+    };
+    *self.connectivity.lock() = Connectivity::Online;
+    *self.last_state.lock() = Some(state.clone());
+    if let Err(e) = self.save_cache(&state) {
+      warn!("Failed to save quest cache: {e:?}");
+    }
     self.state_event.emit(state)?;
 
     Ok(())
   }
 
+  /// Keeps the state up to date, backing off while updates fail.
   pub async fn infer_state_loop(&self) {
+    let mut backoff = None;
     loop {
-      self.infer_state_update().await.unwrap();
-      sleep(Duration::from_secs(10)).await;
+      let delay = match self.infer_state_update().await {
+        Ok(()) => {
+          backoff = None;
+          POLL_INTERVAL
+        }
+        Err(e) => {
+          warn!("Failed to update quest state: {e:?}");
+          let delay = backoff.map_or(MIN_BACKOFF, |delay: Duration| (delay * 2).min(MAX_BACKOFF));
+          backoff = Some(delay);
+          delay
+        }
+      };
+      sleep(delay).await;
     }
   }
 
@@ -380,6 +622,7 @@ impl Quest {
       .origin_git
       .create_branch_from(&*self.template, base_branch, target_branch)
       .with_context(|| format!("Failed to create new branch: {base_branch} -> {target_branch}"))?;
+    self.sync_hooks()?;
 
     let pr = self
       .template
@@ -415,6 +658,7 @@ impl Quest {
     &self,
     stage_index: usize,
   ) -> Result<(Option<PullRequest>, Issue)> {
+    self.ensure_online()?;
     let stage = self.stage(stage_index);
     let base_branch = if stage_index > 0 {
       let prev_stage = self.stage(stage_index - 1);
@@ -444,6 +688,7 @@ impl Quest {
   }
 
   pub async fn file_solution(&self, stage_index: usize) -> Result<PullRequest> {
+    self.ensure_online()?;
     let stage = self.stage(stage_index);
     let base = if stage.no_starter() {
       // TODO: repeats w/ file_feature
@@ -474,19 +719,19 @@ impl Quest {
         let issue_url = self
           .origin
           .issue(&stage.label)
-          .map(|issue| issue.html_url.to_string());
+          .map(|issue| issue.html_url.clone());
 
         let feature_pr_url = self
           .origin
           .pr(&PullSelector::Branch(stage.branch_name(StagePart::Starter)))
-          .map(|pr| pr.data.html_url.as_ref().unwrap().to_string());
+          .map(|pr| pr.data.html_url.clone());
 
         let solution_pr_url = self
           .origin
           .pr(&PullSelector::Branch(
             stage.branch_name(StagePart::Solution),
           ))
-          .map(|pr| pr.data.html_url.as_ref().unwrap().to_string());
+          .map(|pr| pr.data.html_url.clone());
 
         let reference_solution_pr_url = self.template.reference_solution_pr_url(stage);
 
@@ -501,25 +746,47 @@ impl Quest {
       .collect()
   }
 
+  /// Closes the issue for a stage on the learner's behalf, for forges without a website.
+  pub async fn close_stage_issue(&self, stage_index: usize) -> Result<()> {
+    self.ensure_online()?;
+    let stage = self.stage(stage_index);
+    let issue = self
+      .origin
+      .issue(&stage.label)
+      .with_context(|| format!("No issue for stage: {}", stage.label))?
+      .clone();
+    self.origin.close_issue(&issue).await?;
+    self.infer_state_update().await
+  }
+
+  /// Merges a stage's PR on the learner's behalf, for forges without a website.
+  pub async fn merge_stage_pr(&self, stage_index: usize, part: StagePart) -> Result<()> {
+    self.ensure_online()?;
+    let branch = self.stage(stage_index).branch_name(part);
+    let pr = self
+      .origin
+      .pr(&PullSelector::Branch(branch.clone()))
+      .with_context(|| format!("No PR for branch: {branch}"))?
+      .data
+      .clone();
+    self.origin.merge_pr(&pr).await?;
+    self.infer_state_update().await
+  }
+
   pub async fn skip_to_stage(&self, stage_index: usize) -> Result<()> {
+    self.ensure_online()?;
     let prev_stage = self.stage(stage_index - 1);
     let branch = format!("{UPSTREAM}/{}", prev_stage.branch_name(StagePart::Solution));
     self
       .origin_git
       .reset(&branch)
       .with_context(|| format!("Failed to reset to branch: {branch}"))?;
+    self.sync_hooks()?;
     let issue = self
       .file_issue(stage_index - 1)
       .await
       .context("Failed to file issue for preceding stage")?;
-    self
-      .origin
-      .issue_handler()
-      .update(issue.number)
-      .state(IssueState::Closed)
-      .send()
-      .await
-      .with_context(|| format!("Failed to close issue: {}", issue.number))?;
+    self.origin.close_issue(&issue).await?;
 
     self.infer_state_update().await?;
     Ok(())
@@ -529,14 +796,27 @@ impl Quest {
 #[cfg(test)]
 mod test {
   use super::*;
-  use crate::github::{self, GithubToken};
+  use crate::{
+    command::command,
+    forge::{fake::FakeHost, local::LocalHost, GitProtocol},
+    github::{self, GithubHost, GithubToken},
+    package::{
+      signature::{PackageTrust, SigningKey, TrustStore},
+      Patch, SCHEMA_VERSION,
+    },
+  };
   use anyhow::ensure;
   use env::current_dir;
+  use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+  use serde_json::json;
   use std::{
     env, fs,
+    path::Path,
     process::Command,
     sync::{Arc, Once},
   };
+  use tempfile::TempDir;
+  use tokio::sync::OnceCell;
   use tracing_subscriber::{fmt, layer::SubscriberExt, prelude::*, EnvFilter};
 
   const TEST_ORG: &str = "cognitive-engineering-lab";
@@ -560,31 +840,40 @@ mod test {
     }
   }
 
-  fn setup() {
+  fn setup_local() {
     static SETUP: Once = Once::new();
     SETUP.call_once(|| {
       tracing_subscriber::registry()
         .with(fmt::layer())
         .with(EnvFilter::from_default_env())
         .init();
-
-      let token = github::get_github_token();
-      match token {
-        GithubToken::Found(token) => github::init_octocrab(&token).unwrap(),
-        other => panic!("Failed to get github token: {other:?}"),
-      }
     });
   }
 
+  async fn setup() {
+    setup_local();
+
+    static SETUP: OnceCell<()> = OnceCell::const_new();
+    SETUP
+      .get_or_init(|| async {
+        let token = github::get_github_token().await;
+        match token {
+          GithubToken::Found(credentials) => github::init_octocrab(&credentials).unwrap(),
+          other => panic!("Failed to get github token: {other:?}"),
+        }
+      })
+      .await;
+  }
+
   async fn create_test_quest(source: CreateSource) -> Result<Arc<Quest>> {
     let dir = current_dir()?;
-    let quest = Quest::create(dir, source, Box::new(NoopEmitter)).await?;
+    let quest = Quest::create(dir, source, &GithubHost::new(), None, Box::new(NoopEmitter)).await?;
     Ok(Arc::new(quest))
   }
 
   macro_rules! test_quest {
     ($id:ident, $source:expr) => {
-      setup();
+      setup().await;
 
       let $id = create_test_quest($source).await?;
       let _remote = DeleteRemoteRepo(Arc::clone(&$id));
@@ -603,7 +892,8 @@ mod test {
 
   macro_rules! state_is {
     ($quest:expr, $a:expr, $b:expr, $c:expr) => {{
-      let state = $quest.infer_state().await?;
+      $quest.origin.fetch().await?;
+      let state = $quest.infer_state();
       match state {
         QuestState::Ongoing {
           stage,
@@ -677,8 +967,8 @@ mod test {
     fs::remove_dir_all(repo_path)?;
 
     let package_path = PathBuf::from(format!("{TEST_REPO}.json.gz"));
-    let package = QuestPackage::load_from_file(&package_path)?;
-    test_quest!(quest, CreateSource::Package(package));
+    let package = QuestPackage::load_trusted(&package_path, true)?;
+    test_quest!(quest, CreateSource::Package(Box::new(package)));
 
     state_is!(quest, 0, StagePart::Starter, StagePartStatus::Start);
 
@@ -713,7 +1003,8 @@ mod test {
 
     macro_rules! state_is {
       ($a:expr, $b:expr, $c:expr) => {
-        let state = quest.infer_state().await?;
+        quest.origin.fetch().await?;
+        let state = quest.infer_state();
         match state {
           QuestState::Ongoing {
             stage,
@@ -735,4 +1026,602 @@ mod test {
 
     Ok(())
   }
+
+  const FAKE_AUTHOR: &str = "rqst-author";
+  const FAKE_USER: &str = "rqst-learner";
+  const FAKE_REPO: &str = "rqst-fake";
+
+  const FAKE_CONFIG: &str = r#"
+title = "Fake Quest"
+author = "rqst-author"
+repo = "rqst-fake"
+
+[[stages]]
+label = "s1"
+name = "Stage 1"
+no-starter = true
+
+[[stages]]
+label = "s2"
+name = "Stage 2"
+
+[[stages]]
+label = "s3"
+name = "Stage 3"
+"#;
+
+  /// Not valid UTF-8, so it can only be packaged as bytes.
+  const FAKE_BINARY: &[u8] = &[0x89, b'P', b'N', b'G', 0xff, 0x00];
+
+  fn git(dir: &Path, args: &[&str]) -> Result<()> {
+    let output = command("git", dir).args(args).output()?;
+    ensure!(
+      output.status.success(),
+      "git {} failed, stderr:\n{}",
+      args.join(" "),
+      String::from_utf8_lossy(&output.stderr)
+    );
+    Ok(())
+  }
+
+  fn commit_file(dir: &Path, file: &str, contents: &str) -> Result<()> {
+    fs::write(dir.join(file), contents)?;
+    git(dir, &["add", "."])?;
+    git(dir, &["commit", "-m", &format!("Update {file}")])
+  }
+
+  /// Sets up a fake forge containing a three-stage quest template, where stage `s1` has
+  /// no starter code. Returns the forge and a local checkout of the template.
+  async fn fake_template(root: &Path) -> Result<(FakeHost, PathBuf)> {
+    // The space checks that repo paths are never split into separate arguments.
+    let host = FakeHost::new(&root.join("fake forge"), FAKE_USER);
+    let template = host.add_repo(FAKE_AUTHOR, FAKE_REPO)?;
+
+    let src = root.join("author").join(FAKE_REPO);
+    fs::create_dir_all(&src)?;
+    git(&src, &["init", "--initial-branch=main"])?;
+    commit_file(&src, "README.md", "# Fake quest\n")?;
+    fs::write(src.join("logo.bin"), FAKE_BINARY)?;
+    fs::write(src.join("run.sh"), "#!/bin/sh\n")?;
+    git(&src, &["add", "."])?;
+    git(&src, &["update-index", "--chmod=+x", "run.sh"])?;
+    git(&src, &["commit", "-m", "Add assets"])?;
+
+    git(&src, &["checkout", "--orphan", "meta"])?;
+    git(&src, &["rm", "-rf", "."])?;
+    commit_file(&src, "rqst.toml", FAKE_CONFIG)?;
+    git(&src, &["checkout", "main"])?;
+
+    let stages = [("s1", true), ("s2", false), ("s3", false)];
+    let mut prs = Vec::new();
+    let mut base = String::from("main");
+    for (label, no_starter) in stages {
+      let file = format!("{label}.txt");
+      let mut parts = vec![(StagePart::Solution, "solution")];
+      if !no_starter {
+        parts.insert(0, (StagePart::Starter, "starter"));
+      }
+      for (part, contents) in parts {
+        let branch = format!("{label}-{part}");
+        git(&src, &["checkout", "-b", &branch, &base])?;
+        commit_file(&src, &file, contents)?;
+        prs.push((label, contents, branch.clone(), base));
+        base = branch;
+      }
+    }
+    git(&src, &["checkout", "main"])?;
+    git(&src, &["push", "--all", &template.remote(GitProtocol::Ssh)])?;
+
+    for (label, contents, head, base) in prs {
+      template
+        .create_pr(
+          &format!("{label} {contents}"),
+          &head,
+          &base,
+          &format!("The {contents} for {label}"),
+          &[label.to_string()],
+        )
+        .await?;
+    }
+    for (label, _) in stages {
+      template
+        .create_issue(
+          &format!("Stage {label}"),
+          &format!("Complete stage {label}. The starter code is in {{{{ {label} pr }}}}."),
+          &[label.to_string()],
+        )
+        .await?;
+    }
+
+    Ok((host, src))
+  }
+
+  async fn create_fake_quest(
+    root: &TempDir,
+    host: &dyn ForgeHost,
+    source: CreateSource,
+  ) -> Result<Quest> {
+    let dir = root.path().join("learner");
+    fs::create_dir_all(&dir)?;
+    Quest::create(dir, source, host, None, Box::new(NoopEmitter)).await
+  }
+
+  fn fake_remote() -> CreateSource {
+    CreateSource::Remote {
+      user: FAKE_AUTHOR.into(),
+      repo: FAKE_REPO.into(),
+    }
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn fake_remote_playthrough() -> Result<()> {
+    setup_local();
+    let root = TempDir::new()?;
+    let (host, _) = fake_template(root.path()).await?;
+    let quest = create_fake_quest(&root, &host, fake_remote()).await?;
+
+    state_is!(quest, 0, StagePart::Starter, StagePartStatus::Start);
+
+    let issue = quest.file_issue(0).await?;
+    state_is!(quest, 0, StagePart::Solution, StagePartStatus::Start);
+
+    quest.origin.close_issue(&issue).await?;
+    state_is!(quest, 1, StagePart::Starter, StagePartStatus::Start);
+
+    let (pr, issue) = quest.file_feature_and_issue(1).await?;
+    let pr = pr.unwrap();
+    state_is!(quest, 1, StagePart::Starter, StagePartStatus::Ongoing);
+    assert_eq!(
+      issue.body.as_deref(),
+      Some(format!("Complete stage s2. The starter code is in #{}.", pr.number).as_str())
+    );
+
+    quest.origin.merge_pr(&pr).await?;
+    state_is!(quest, 1, StagePart::Solution, StagePartStatus::Start);
+
+    let pr = quest.file_solution(1).await?;
+    state_is!(quest, 1, StagePart::Solution, StagePartStatus::Ongoing);
+
+    quest.origin.merge_pr(&pr).await?;
+    state_is!(quest, 1, StagePart::Solution, StagePartStatus::Ongoing);
+
+    quest.origin.close_issue(&issue).await?;
+    state_is!(quest, 2, StagePart::Starter, StagePartStatus::Start);
+
+    quest.origin_git.pull()?;
+    let solution = fs::read_to_string(quest.dir.join("s2.txt"))?;
+    assert_eq!(solution, "solution");
+
+    Ok(())
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn fake_local_playthrough() -> Result<()> {
+    setup_local();
+    let root = TempDir::new()?;
+    let (template_host, src) = fake_template(root.path()).await?;
+    let package = QuestPackage::build(&src, &template_host).await?;
+    // The space checks that repo paths are never split into separate arguments.
+    let host = LocalHost::new(&root.path().join("local forge"), FAKE_USER);
+    let quest = create_fake_quest(&root, &host, CreateSource::Package(Box::new(package))).await?;
+    assert!(quest.state_descriptor().await?.local);
+
+    assert_eq!(fs::read(quest.dir.join("logo.bin"))?, FAKE_BINARY);
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      let mode = fs::metadata(quest.dir.join("run.sh"))?.permissions().mode();
+      assert_eq!(mode & 0o111, 0o111);
+    }
+
+    state_is!(quest, 0, StagePart::Starter, StagePartStatus::Start);
+
+    quest.file_issue(0).await?;
+    state_is!(quest, 0, StagePart::Solution, StagePartStatus::Start);
+
+    quest.close_stage_issue(0).await?;
+    state_is!(quest, 1, StagePart::Starter, StagePartStatus::Start);
+
+    quest.file_feature_and_issue(1).await?;
+    state_is!(quest, 1, StagePart::Starter, StagePartStatus::Ongoing);
+
+    quest.merge_stage_pr(1, StagePart::Starter).await?;
+    state_is!(quest, 1, StagePart::Solution, StagePartStatus::Start);
+
+    quest.close_stage_issue(1).await?;
+    state_is!(quest, 2, StagePart::Starter, StagePartStatus::Start);
+
+    quest.origin_git.pull()?;
+    let starter = fs::read_to_string(quest.dir.join("s2.txt"))?;
+    assert_eq!(starter, "starter");
+
+    // The quest can be reopened from its directory alone, with all state read from disk.
+    let host = LocalHost::detect(&quest.dir).unwrap();
+    let quest = Quest::load(quest.dir.clone(), &host, Box::new(NoopEmitter)).await?;
+    state_is!(quest, 2, StagePart::Starter, StagePartStatus::Start);
+
+    Ok(())
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn fake_v1_package() -> Result<()> {
+    setup_local();
+    let root = TempDir::new()?;
+    let (host, src) = fake_template(root.path()).await?;
+
+    // Downgrade a package to v1, which stores text files and starter code diffs.
+    let package = QuestPackage::build(&src, &host).await?;
+    let src_git = GitRepo::new(&src);
+    let patches = [("s1-b", "s2-a"), ("s2-b", "s3-a")]
+      .into_iter()
+      .map(|(base, head)| {
+        Ok(Patch {
+          base: base.into(),
+          head: head.into(),
+          patch: src_git.diff(base, head)?,
+        })
+      })
+      .collect::<Result<Vec<_>>>()?;
+    let mut json = serde_json::to_value(&package)?;
+    let fields = json.as_object_mut().unwrap();
+    fields.remove("schema");
+    fields.remove("content_hash");
+    fields.remove("contents");
+    // v1 packages store issues and PRs as Github returns them.
+    let template = host.load(FAKE_AUTHOR, FAKE_REPO).await?;
+    let github_labels = |labels: &[String]| {
+      labels
+        .iter()
+        .map(|name| json!({ "name": name, "color": "ededed" }))
+        .collect::<Vec<_>>()
+    };
+    let issues = template
+      .issues()
+      .iter()
+      .map(|issue| {
+        json!({
+          "number": issue.number,
+          "title": issue.title,
+          "body": issue.body,
+          "labels": github_labels(&issue.labels),
+        })
+      })
+      .collect::<Vec<_>>();
+    let prs = template
+      .prs()
+      .iter()
+      .map(|pr| {
+        json!({
+          "data": {
+            "number": pr.data.number,
+            "title": pr.data.title,
+            "body": pr.data.body,
+            "labels": github_labels(&pr.data.labels),
+            "head": { "ref": pr.data.head },
+            "base": { "ref": pr.data.base },
+          },
+          "comments": pr.comments,
+        })
+      })
+      .collect::<Vec<_>>();
+    fields.insert("issues".into(), issues.into());
+    fields.insert("prs".into(), prs.into());
+    fields.insert("initial".into(), json!({ "README.md": "# Fake quest\n" }));
+    fields.insert("patches".into(), serde_json::to_value(patches)?);
+    let path = root.path().join("package.json.gz");
+    let mut encoder = GzEncoder::new(fs::File::create(&path)?, Compression::default());
+    serde_json::to_writer(&mut encoder, &json)?;
+    encoder.finish()?;
+
+    let package = QuestPackage::load_trusted(&path, true)?;
+    assert_eq!(package.migrations().len(), SCHEMA_VERSION as usize - 1);
+
+    let quest = create_fake_quest(&root, &host, CreateSource::Package(Box::new(package))).await?;
+    assert!(quest.dir.join("README.md").exists());
+    quest.file_issue(0).await?;
+    quest.close_stage_issue(0).await?;
+    quest.file_feature_and_issue(1).await?;
+    quest.merge_stage_pr(1, StagePart::Starter).await?;
+    quest.origin_git.pull()?;
+    let starter = fs::read_to_string(quest.dir.join("s2.txt"))?;
+    assert_eq!(starter, "starter");
+
+    Ok(())
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn fake_reproducible_package() -> Result<()> {
+    setup_local();
+    let root = TempDir::new()?;
+    let (host, src) = fake_template(root.path()).await?;
+
+    let mut paths = Vec::new();
+    for i in 0..2 {
+      let package = QuestPackage::build(&src, &host).await?;
+      let path = root.path().join(format!("package{i}.json.gz"));
+      package.save(&path)?;
+      paths.push(path);
+    }
+    assert_eq!(fs::read(&paths[0])?, fs::read(&paths[1])?);
+    let package = QuestPackage::load_trusted(&paths[0], true)?;
+    assert_eq!(package.content_hash.len(), 64);
+
+    // Changing the contents without the hash is caught.
+    let mut json: serde_json::Value =
+      serde_json::from_reader(GzDecoder::new(fs::File::open(&paths[0])?))?;
+    json["config"]["title"] = json!("Tampered");
+    let mut encoder = GzEncoder::new(fs::File::create(&paths[1])?, Compression::default());
+    serde_json::to_writer(&mut encoder, &json)?;
+    encoder.finish()?;
+    let Err(err) = QuestPackage::load_trusted(&paths[1], true) else {
+      panic!("Tampered package loaded");
+    };
+    assert!(format!("{err:#}").contains("content hash"), "{err:#}");
+
+    Ok(())
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn fake_untrusted_package() -> Result<()> {
+    setup_local();
+    let root = TempDir::new()?;
+    let (host, src) = fake_template(root.path()).await?;
+    let path = root.path().join("package.json.gz");
+    QuestPackage::build(&src, &host).await?.save(&path)?;
+    let mut store = TrustStore::load_from(&root.path().join("trusted.toml"))?;
+    let refused = |store: &TrustStore| {
+      let Err(err) = QuestPackage::load_trusted_with(&path, store, false) else {
+        panic!("Untrusted package loaded");
+      };
+      assert!(err.to_string().contains("trusted author"), "{err:#}");
+    };
+
+    // Unsigned packages are refused.
+    refused(&store);
+    assert!(QuestPackage::load_trusted_with(&path, &store, true).is_ok());
+
+    // So are packages signed by an author the learner doesn't trust.
+    let key = SigningKey::load_or_generate_at(&root.path().join("key.toml"))?;
+    key.sign(&path)?;
+    refused(&store);
+    assert!(QuestPackage::load_trusted_with(&path, &store, true).is_ok());
+
+    store.trust("Author", &key.public_key())?;
+    let package = QuestPackage::load_trusted_with(&path, &store, false)?;
+    assert_eq!(
+      package.trust(),
+      &PackageTrust::Trusted {
+        author: "Author".into()
+      }
+    );
+
+    Ok(())
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn fake_source_package() -> Result<()> {
+    setup_local();
+    let root = TempDir::new()?;
+    let (host, src) = fake_template(root.path()).await?;
+
+    // Write the fake quest as source files, with the template's diffs as patches.
+    let source = root.path().join("source");
+    fs::create_dir_all(source.join("initial"))?;
+    fs::write(source.join("rqst.toml"), FAKE_CONFIG)?;
+    fs::write(source.join("initial/README.md"), "# Fake quest\n")?;
+    let src_git = GitRepo::new(&src);
+    let mut base = String::from("main");
+    for (label, no_starter) in [("s1", true), ("s2", false), ("s3", false)] {
+      let dir = source.join("stages").join(label);
+      fs::create_dir_all(&dir)?;
+      fs::write(
+        dir.join("issue.md"),
+        format!("+++\ntitle = \"Stage {label}\"\n+++\nSee {{{{ {label} pr }}}}.\n"),
+      )?;
+      let mut parts = vec![(StagePart::Solution, "solution")];
+      if !no_starter {
+        parts.insert(0, (StagePart::Starter, "starter"));
+      }
+      for (part, name) in parts {
+        let head = format!("{label}-{part}");
+        fs::write(
+          dir.join(format!("{name}.md")),
+          format!(
+            "+++\ntitle = \"{label} {name}\"\n\n[[comments]]\npath = \"{label}.txt\"\nline = 1\nbody = \"Look here\"\n+++\nThe {name} for {label}\n"
+          ),
+        )?;
+        fs::write(
+          dir.join(format!("{name}.patch")),
+          src_git.diff(&base, &head)?,
+        )?;
+        base = head;
+      }
+    }
+
+    let package = QuestPackage::build_from_source(&source)?;
+    assert_eq!(package.prs.len(), 5);
+    let path = root.path().join("package.json.gz");
+    package.save(&path)?;
+    let package = QuestPackage::load_trusted(&path, true)?;
+
+    let quest = create_fake_quest(&root, &host, CreateSource::Package(Box::new(package))).await?;
+    assert!(quest.dir.join("README.md").exists());
+    quest.file_issue(0).await?;
+    quest.close_stage_issue(0).await?;
+    let (pr, issue) = quest.file_feature_and_issue(1).await?;
+    let pr = pr.unwrap();
+    assert_eq!(pr.title, "s2 starter");
+    assert_eq!(
+      issue.body.as_deref(),
+      Some(format!("See #{}.", pr.number).as_str())
+    );
+    let comments = quest
+      .origin
+      .pr(&PullSelector::Branch("s2-a".into()))
+      .unwrap()
+      .comments
+      .clone();
+    assert_eq!(comments[0].body, "Look here");
+
+    quest.merge_stage_pr(1, StagePart::Starter).await?;
+    quest.origin_git.pull()?;
+    let starter = fs::read_to_string(quest.dir.join("s2.txt"))?;
+    assert_eq!(starter, "starter");
+
+    Ok(())
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn fake_skip() -> Result<()> {
+    setup_local();
+    let root = TempDir::new()?;
+    let (host, _) = fake_template(root.path()).await?;
+    let quest = create_fake_quest(&root, &host, fake_remote()).await?;
+
+    state_is!(quest, 0, StagePart::Starter, StagePartStatus::Start);
+
+    quest.skip_to_stage(1).await?;
+    state_is!(quest, 1, StagePart::Starter, StagePartStatus::Start);
+
+    quest.skip_to_stage(2).await?;
+    state_is!(quest, 2, StagePart::Starter, StagePartStatus::Start);
+
+    let solution = fs::read_to_string(quest.dir.join("s2.txt"))?;
+    assert_eq!(solution, "solution");
+
+    Ok(())
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn fake_offline() -> Result<()> {
+    setup_local();
+    let root = TempDir::new()?;
+    let (host, _) = fake_template(root.path()).await?;
+    let quest = create_fake_quest(&root, &host, fake_remote()).await?;
+    let issue = quest.file_issue(0).await?;
+    quest.infer_state_update().await?;
+    let dir = quest.dir.clone();
+    drop(quest);
+
+    // Take the forge offline, and reopen the quest.
+    host.set_offline(true);
+    let quest = Quest::load(dir.clone(), &host, Box::new(NoopEmitter)).await?;
+    let state = quest.state_descriptor().await?;
+    assert_eq!(state.connectivity, Connectivity::Offline);
+    assert!(matches!(
+      state.state,
+      QuestState::Ongoing {
+        stage: 0,
+        part: StagePart::Solution,
+        status: StagePartStatus::Start
+      }
+    ));
+    assert_eq!(
+      state.stages[0].issue_url.as_deref(),
+      issue.html_url.as_str().into()
+    );
+    assert!(quest.close_stage_issue(0).await.is_err());
+
+    // Polling keeps the cached state until the forge is back.
+    assert!(quest.infer_state_update().await.is_err());
+    assert!(quest.is_offline());
+    host.set_offline(false);
+    quest.infer_state_update().await?;
+    let state = quest.state_descriptor().await?;
+    assert_eq!(state.connectivity, Connectivity::Online);
+    quest.close_stage_issue(0).await?;
+    state_is!(quest, 1, StagePart::Starter, StagePartStatus::Start);
+
+    Ok(())
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn fake_retrying() -> Result<()> {
+    setup_local();
+    let root = TempDir::new()?;
+    let (host, _) = fake_template(root.path()).await?;
+    let quest = create_fake_quest(&root, &host, fake_remote()).await?;
+
+    host.set_offline(true);
+    for attempt in 1..=2 {
+      assert!(quest.infer_state_update().await.is_err());
+      let state = quest.last_known_state().unwrap();
+      assert!(matches!(
+        state.connectivity,
+        Connectivity::Retrying { attempts, .. } if attempts == attempt
+      ));
+    }
+
+    host.set_offline(false);
+    quest.infer_state_update().await?;
+    let state = quest.last_known_state().unwrap();
+    assert_eq!(state.connectivity, Connectivity::Online);
+
+    Ok(())
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn fake_hooks() -> Result<()> {
+    setup_local();
+    let root = TempDir::new()?;
+    let (host, src) = fake_template(root.path()).await?;
+    fs::create_dir_all(src.join(".githooks"))?;
+    fs::write(
+      src.join(".githooks/post-checkout"),
+      "#!/bin/sh\ntouch hooked\n",
+    )?;
+    git(&src, &["add", "."])?;
+    git(
+      &src,
+      &["update-index", "--chmod=+x", ".githooks/post-checkout"],
+    )?;
+    git(&src, &["commit", "-m", "Add hooks"])?;
+    let template = host.load(FAKE_AUTHOR, FAKE_REPO).await?;
+    git(&src, &["push", &template.remote(GitProtocol::Ssh), "main"])?;
+
+    // Nothing runs until the learner decides.
+    let quest = create_fake_quest(&root, &host, fake_remote()).await?;
+    let hooks = quest.state_descriptor().await?.pending_hooks.unwrap();
+    assert_eq!(hooks.hooks.len(), 1);
+    assert_eq!(hooks.hooks[0].name, "post-checkout");
+    assert!(!quest.dir.join("hooked").exists());
+    assert!(quest.origin_git.config_get("core.hooksPath")?.is_none());
+
+    assert!(quest
+      .decide_hooks("stale", HookDecision::Allow)
+      .await
+      .is_err());
+    quest
+      .decide_hooks(&hooks.sha256, HookDecision::Allow)
+      .await?;
+    assert!(quest.dir.join("hooked").exists());
+    assert!(quest.origin_git.config_get("core.hooksPath")?.is_some());
+    assert!(quest.state_descriptor().await?.pending_hooks.is_none());
+
+    // Polling the forge leaves the hooks alone.
+    fs::write(
+      quest.dir.join(".githooks/post-checkout"),
+      "#!/bin/sh\ntouch changed\n",
+    )?;
+    quest.infer_state_update().await?;
+    assert!(quest.state_descriptor().await?.pending_hooks.is_none());
+
+    // Changed hooks are uninstalled until the learner sees them again.
+    let quest = Quest::load(quest.dir.clone(), &host, Box::new(NoopEmitter)).await?;
+    let changed = quest.state_descriptor().await?.pending_hooks.unwrap();
+    assert_ne!(changed.sha256, hooks.sha256);
+    assert!(quest.origin_git.config_get("core.hooksPath")?.is_none());
+
+    quest
+      .decide_hooks(&changed.sha256, HookDecision::Deny)
+      .await?;
+    assert!(!quest.dir.join("changed").exists());
+    assert!(quest.origin_git.config_get("core.hooksPath")?.is_none());
+
+    // The decision is remembered when the quest is reopened.
+    let quest = Quest::load(quest.dir.clone(), &host, Box::new(NoopEmitter)).await?;
+    assert!(quest.state_descriptor().await?.pending_hooks.is_none());
+
+    Ok(())
+  }
 }
@@ -0,0 +1,73 @@
+//! Saves what a quest last fetched from its forge, so that the quest can be opened while the
+//! forge is unreachable.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+  fs,
+  path::{Path, PathBuf},
+};
+
+use super::StateDescriptor;
+use crate::forge::{Forge, FullPullRequest, Issue};
+
+/// Kept inside the quest's `.git` directory so that it never shows up as a change.
+const CACHE_FILE: &str = ".git/rqst-cache.json";
+
+/// The PRs and issues of one repo, as of its last fetch.
+#[derive(Serialize, Deserialize)]
+pub struct RepoSnapshot {
+  prs: Vec<FullPullRequest>,
+  issues: Vec<Issue>,
+}
+
+impl RepoSnapshot {
+  pub fn of(forge: &dyn Forge) -> Self {
+    RepoSnapshot {
+      prs: forge.prs().clone(),
+      issues: forge.issues().clone(),
+    }
+  }
+
+  pub fn restore(self, forge: &dyn Forge) {
+    forge.restore(self.prs, self.issues);
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct QuestCache {
+  pub user: String,
+  pub origin: RepoSnapshot,
+  /// Missing for quests created from a package.
+  pub upstream: Option<RepoSnapshot>,
+  pub state: StateDescriptor,
+}
+
+fn path(dir: &Path) -> PathBuf {
+  dir.join(CACHE_FILE)
+}
+
+impl QuestCache {
+  /// Returns the cache saved in the quest at `dir`, or `None` if there is none.
+  pub fn load(dir: &Path) -> Result<Option<Self>> {
+    let path = path(dir);
+    if !path.exists() {
+      return Ok(None);
+    }
+    let contents =
+      fs::read_to_string(&path).with_context(|| format!("Failed to read: {}", path.display()))?;
+    let cache = serde_json::from_str(&contents)
+      .with_context(|| format!("Failed to parse quest cache: {}", path.display()))?;
+    Ok(Some(cache))
+  }
+
+  pub fn save(&self, dir: &Path) -> Result<()> {
+    let path = path(dir);
+    // Write then rename, so that a crash mid-write doesn't leave a truncated cache.
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, serde_json::to_string(self)?)
+      .with_context(|| format!("Failed to write: {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, &path).with_context(|| format!("Failed to write: {}", path.display()))?;
+    Ok(())
+  }
+}
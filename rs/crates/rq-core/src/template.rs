@@ -1,27 +1,31 @@
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
-use octocrab::models::issues::Issue;
 use std::path::Path;
 
 use crate::{
-  git::{GitRepo, MergeType},
-  github::{find_issue, find_pr, FullPullRequest, GithubRepo, PullSelector},
-  package::QuestPackage,
+  forge::{Forge, ForgeHost, GitProtocol, PullSelector, QuestIssue, QuestPullRequest},
+  git::{GitRepo, MergeType, PACKAGE, UPSTREAM},
+  package::{PackageContents, QuestPackage},
   quest::QuestConfig,
   stage::{Stage, StagePart},
 };
 
 pub struct InstanceOutputs {
-  pub origin: GithubRepo,
+  pub origin: Box<dyn Forge>,
   pub origin_git: GitRepo,
   pub config: QuestConfig,
 }
 
 #[async_trait]
 pub trait QuestTemplate: Send + Sync + 'static {
-  async fn instantiate(&self, path: &Path) -> Result<InstanceOutputs>;
-  fn pull_request(&self, selector: &PullSelector) -> Result<FullPullRequest>;
-  fn issue(&self, label: &str) -> Result<Issue>;
+  async fn instantiate(
+    &self,
+    host: &dyn ForgeHost,
+    path: &Path,
+    protocol: GitProtocol,
+  ) -> Result<InstanceOutputs>;
+  fn pull_request(&self, selector: &PullSelector) -> Result<QuestPullRequest>;
+  fn issue(&self, label: &str) -> Result<QuestIssue>;
   fn apply_patch(
     &self,
     repo: &GitRepo,
@@ -30,19 +34,44 @@ pub trait QuestTemplate: Send + Sync + 'static {
   ) -> Result<MergeType>;
   fn reference_solution_pr_url(&self, stage: &Stage) -> Option<String>;
   fn can_skip(&self) -> bool;
+
+  /// The repo that the quest is copied from, if it is hosted on a forge.
+  fn upstream(&self) -> Option<&dyn Forge> {
+    None
+  }
 }
 
-pub struct RepoTemplate(pub GithubRepo);
+pub struct RepoTemplate(pub Box<dyn Forge>);
 
 #[async_trait]
 impl QuestTemplate for RepoTemplate {
-  async fn instantiate(&self, path: &Path) -> Result<InstanceOutputs> {
-    let origin = GithubRepo::instantiate_from_repo(&self.0)
+  async fn instantiate(
+    &self,
+    host: &dyn ForgeHost,
+    path: &Path,
+    protocol: GitProtocol,
+  ) -> Result<InstanceOutputs> {
+    let origin = host
+      .generate_repo(&*self.0)
       .await
-      .context("Failed to instantiate Github repo from template")?;
-    let origin_git = origin.clone(path).context("Failed to clone Github repo")?;
+      .context("Failed to instantiate repo from template")?;
+
+    // Copy all issue labels.
+    let labels = self
+      .0
+      .labels()
+      .await
+      .context("Failed to fetch labels from upstream repo")?;
+    origin
+      .create_labels(&labels)
+      .await
+      .context("Failed to transfer upstream labels to repo")?;
+
+    let origin_git = origin
+      .clone(path, protocol)
+      .context("Failed to clone repo")?;
     origin_git
-      .setup_upstream(&self.0)
+      .setup_upstream(&*self.0)
       .context("Failed to setup upstream")?;
     let config = QuestConfig::load(&origin_git, Some("upstream"))
       .context("Failed to load quest config from upstream")?;
@@ -53,17 +82,17 @@ impl QuestTemplate for RepoTemplate {
     })
   }
 
-  fn pull_request(&self, selector: &PullSelector) -> Result<FullPullRequest> {
+  fn pull_request(&self, selector: &PullSelector) -> Result<QuestPullRequest> {
     let pr = self.0.pr(selector).ok_or(anyhow!("Missing PR"))?;
-    Ok((*pr).clone())
+    QuestPullRequest::try_from(&*pr)
   }
 
-  fn issue(&self, label: &str) -> Result<Issue> {
+  fn issue(&self, label: &str) -> Result<QuestIssue> {
     let issue = self
       .0
       .issue(label)
       .ok_or_else(|| anyhow!("Missing issue for label: {label}"))?;
-    Ok((*issue).clone())
+    QuestIssue::try_from(&*issue)
   }
 
   fn apply_patch(
@@ -72,7 +101,12 @@ impl QuestTemplate for RepoTemplate {
     base_branch: &str,
     target_branch: &str,
   ) -> Result<MergeType> {
-    repo.cherry_pick(base_branch, target_branch)
+    repo.cherry_pick(
+      UPSTREAM,
+      base_branch,
+      target_branch,
+      MergeType::SolutionReset,
+    )
   }
 
   fn reference_solution_pr_url(&self, stage: &Stage) -> Option<String> {
@@ -81,23 +115,39 @@ impl QuestTemplate for RepoTemplate {
       .pr(&PullSelector::Branch(
         stage.branch_name(StagePart::Solution),
       ))
-      .map(|pr| pr.data.html_url.as_ref().unwrap().to_string())
+      .map(|pr| pr.data.html_url.clone())
   }
 
   fn can_skip(&self) -> bool {
     true
   }
+
+  fn upstream(&self) -> Option<&dyn Forge> {
+    Some(&*self.0)
+  }
 }
 
 pub struct PackageTemplate(pub QuestPackage);
 
 #[async_trait]
 impl QuestTemplate for PackageTemplate {
-  async fn instantiate(&self, path: &Path) -> Result<InstanceOutputs> {
-    let origin = GithubRepo::instantiate_from_package(&self.0)
+  async fn instantiate(
+    &self,
+    host: &dyn ForgeHost,
+    path: &Path,
+    protocol: GitProtocol,
+  ) -> Result<InstanceOutputs> {
+    let origin = host
+      .create_repo(&self.0.config.repo)
       .await
       .context("Failed to instantiate repo from package")?;
-    let origin_git = origin.clone(path).context("Failed to clone repo")?;
+    origin
+      .create_labels(&self.0.labels)
+      .await
+      .context("Failed to transfer package labels to repo")?;
+    let origin_git = origin
+      .clone(path, protocol)
+      .context("Failed to clone repo")?;
     origin_git
       .write_initial_files(&self.0)
       .context("Failed to write starter code to new repo")?;
@@ -109,16 +159,21 @@ impl QuestTemplate for PackageTemplate {
     })
   }
 
-  fn pull_request(&self, selector: &PullSelector) -> Result<FullPullRequest> {
-    let index = find_pr(selector, &self.0.prs)
-      .ok_or_else(|| anyhow!("Missing PR for selector: {selector:?}"))?;
-    Ok(self.0.prs[index].clone())
+  fn pull_request(&self, selector: &PullSelector) -> Result<QuestPullRequest> {
+    let pr = self.0.prs.iter().find(|pr| pr.matches(selector));
+    pr.cloned()
+      .ok_or_else(|| anyhow!("Missing PR for selector: {selector:?}"))
   }
 
-  fn issue(&self, label: &str) -> Result<Issue> {
-    let index = find_issue(label, &self.0.issues)
-      .ok_or_else(|| anyhow!("Missing issue for label: {label}"))?;
-    Ok(self.0.issues[index].clone())
+  fn issue(&self, label: &str) -> Result<QuestIssue> {
+    let issue = self
+      .0
+      .issues
+      .iter()
+      .find(|issue| issue.labels.iter().any(|l| l == label));
+    issue
+      .cloned()
+      .ok_or_else(|| anyhow!("Missing issue for label: {label}"))
   }
 
   fn apply_patch(
@@ -127,12 +182,21 @@ impl QuestTemplate for PackageTemplate {
     base_branch: &str,
     target_branch: &str,
   ) -> Result<MergeType> {
+    let patches = match &self.0.contents {
+      PackageContents::Files { patches, .. } => patches,
+      PackageContents::Bundle { bundle } => {
+        // The bundle isn't imported yet if the quest was cloned rather than instantiated here.
+        repo.import_bundle(bundle)?;
+        return repo.cherry_pick(PACKAGE, base_branch, target_branch, MergeType::StarterReset);
+      }
+    };
+
     let patch_index = self
       .0
       .patch(&(base_branch.to_string(), target_branch.to_string()))
       .ok_or_else(|| anyhow!("Missing patch in package: {base_branch}..{target_branch}"))?;
 
-    let patches = self.0.patches[..=patch_index]
+    let patches = patches[..=patch_index]
       .iter()
       .map(|patch| patch.patch.as_str())
       .collect::<Vec<_>>();
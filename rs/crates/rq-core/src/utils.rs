@@ -1,4 +1,26 @@
-use std::ops::Range;
+use std::{
+  fmt::Write,
+  fs::{self, OpenOptions},
+  io::Write as _,
+  ops::Range,
+  path::Path,
+};
+
+use anyhow::{Context, Result};
+
+use ring::digest::{digest, Digest, SHA256};
+
+pub fn hex(digest: Digest) -> String {
+  digest.as_ref().iter().fold(String::new(), |mut s, b| {
+    let _ = write!(s, "{b:02x}");
+    s
+  })
+}
+
+/// Returns the SHA-256 of `bytes`, in hex.
+pub fn sha256(bytes: &[u8]) -> String {
+  hex(digest(&SHA256, bytes))
+}
 
 pub fn replace_many_ranges(
   s: &mut String,
@@ -12,3 +34,26 @@ pub fn replace_many_ranges(
     }
   }
 }
+
+/// Writes `contents` to `path` such that only the current user can read it, for secrets like
+/// tokens and keys.
+pub fn write_private(path: &Path, contents: &str) -> Result<()> {
+  let mut options = OpenOptions::new();
+  options.write(true).create(true).truncate(true);
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    options.mode(0o600);
+    // The mode only applies to new files, so also restrict files written by older versions.
+    if path.exists() {
+      fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to set permissions: {}", path.display()))?;
+    }
+  }
+  let mut file = options
+    .open(path)
+    .with_context(|| format!("Failed to write: {}", path.display()))?;
+  file
+    .write_all(contents.as_bytes())
+    .with_context(|| format!("Failed to write: {}", path.display()))
+}